@@ -9,13 +9,230 @@ pub struct StackInfo {
     pub kernel_stack_id: Option<i32>,
     pub cmd: [u8; 16],
     pub cpu: u32,
+    pub state: u32,             // THREAD_*, what the thread was doing for `period` ns
+    pub ts: u64,                // bpf_ktime_get_ns, zero in the counts map keys
+    pub period: u64, // sample period of the perf event or ns blocked, zero in the counts map keys
+    pub user_stack_hash: u64, // key in the dwarf_stacks map, 0 when unwound with frame pointers
+    pub python_stack_hash: u64, // key in the python_stacks map, 0 outside of Python threads
 }
 
+/// On a cpu, sampled by the cpu-clock perf event.
+pub const THREAD_RUNNING: u32 = 0;
+/// Preempted or woken up, waiting for a cpu.
+pub const THREAD_RUNNABLE: u32 = 1;
+/// Blocked in an interruptible sleep: locks, sockets, timers.
+pub const THREAD_SLEEPING: u32 = 2;
+/// Blocked in an uninterruptible sleep, mostly disk I/O.
+pub const THREAD_IO: u32 = 3;
+
+/// One row of a compact unwind table: how to find the caller's frame for
+/// every pc from `pc` up to the pc of the next row.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[repr(C)]
+pub struct UnwindRow {
+    pub pc: u64, // ELF virtual address
+    pub cfa_offset: i32,
+    pub rbp_offset: i32, // the caller's rbp is saved at cfa + rbp_offset, RBP_SAVED only
+    pub cfa_rule: u8,
+    pub rbp_rule: u8,
+    pub _pad: [u8; 6],
+}
+
+/// No unwind info, or the outermost frame: stop unwinding.
+pub const CFA_END: u8 = 0;
+pub const CFA_RSP: u8 = 1;
+pub const CFA_RBP: u8 = 2;
+/// PLT stubs: rsp + 8, plus 8 once the stub pushed the relocation index.
+pub const CFA_PLT: u8 = 3;
+
+pub const RBP_SAME: u8 = 0;
+pub const RBP_SAVED: u8 = 1;
+
+pub const UNWIND_ROWS: u32 = 1 << 20;
+pub const MAX_UNWIND_MAPPINGS: usize = 64;
+pub const MAX_DWARF_FRAMES: usize = 64;
+
+/// An executable mapping and its rows in the `unwind_rows` map.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[repr(C)]
+pub struct UnwindMapping {
+    pub start: u64,
+    pub end: u64,
+    pub bias: u64, // runtime address - ELF virtual address
+    pub table_start: u32,
+    pub table_len: u32,
+}
+
+/// Executable mappings of a process with unwind tables, sorted by address.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ProcUnwind {
+    pub len: u32,
+    pub _pad: u32,
+    pub mappings: [UnwindMapping; MAX_UNWIND_MAPPINGS],
+}
+
+/// A user stack unwound in the kernel with the DWARF tables, leaf first.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct DwarfStack {
+    pub len: u64,
+    pub ips: [u64; MAX_DWARF_FRAMES],
+}
+
+impl DwarfStack {
+    pub fn ips(&self) -> &[u64] {
+        &self.ips[..(self.len as usize).min(MAX_DWARF_FRAMES)]
+    }
+}
+
+pub const MAX_SNAPSHOT_SIZE: usize = 16 * 1024;
+
+/// A sample with the user registers and a copy of the top of the user stack,
+/// unwound in userspace. Published truncated to `size` bytes of `data`.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct StackSnapshot {
+    pub info: StackInfo,
+    pub ip: u64,
+    pub sp: u64,
+    pub bp: u64,
+    pub size: u64, // bytes copied from sp upwards
+    pub data: [u8; MAX_SNAPSHOT_SIZE],
+}
+
+pub const SNAPSHOT_HEADER_SIZE: usize = core::mem::size_of::<StackSnapshot>() - MAX_SNAPSHOT_SIZE;
+
+pub const MAX_PY_FRAMES: usize = 64;
+pub const MAX_PY_THREADS: usize = 64;
+/// Field not present in this CPython version.
+pub const PY_NONE: u64 = u64::MAX;
+/// `_PyInterpreterFrame.owner` of the shim frames the evaluation loop puts on
+/// the C stack since 3.12.
+pub const PY_FRAME_OWNED_BY_CSTACK: u8 = 3;
+/// Set in `PyFrame::code` on the outermost frame of an evaluation loop call.
+pub const PY_ENTRY_FRAME: u64 = 1;
+
+/// Offsets of the CPython structures walked to reach the frames of a thread,
+/// they change with every minor release.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct PyOffsets {
+    pub runtime_interpreters_head: u64,
+    pub interp_threads_head: u64,
+    pub tstate_next: u64,
+    pub tstate_native_thread_id: u64,
+    pub tstate_frame: u64,         // cframe before 3.13, current_frame since
+    pub cframe_current_frame: u64, // PY_NONE when tstate_frame is the frame
+    pub frame_code: u64,
+    pub frame_previous: u64,
+    pub frame_instr: u64,
+    pub frame_owner: u64,
+    pub frame_is_entry: u64, // PY_NONE since 3.12, entries follow a C stack frame
+}
+
+/// A process running CPython, with the address of its `_PyRuntime`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct PyProc {
+    pub runtime: u64,
+    pub offsets: PyOffsets,
+    // device and inode of its pid namespace, CPython knows its threads by
+    // their tid there; 0 in the root namespace
+    pub pidns_dev: u64,
+    pub pidns_ino: u64,
+}
+
+/// A frame of the evaluation loop: the code object, with PY_ENTRY_FRAME, and
+/// the instruction it is at.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[repr(C)]
+pub struct PyFrame {
+    pub code: u64,
+    pub instr: u64,
+}
+
+/// The Python frames of a thread, leaf first.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PyStack {
+    pub len: u64,
+    pub frames: [PyFrame; MAX_PY_FRAMES],
+}
+
+impl PyStack {
+    pub fn frames(&self) -> &[PyFrame] {
+        &self.frames[..(self.len as usize).min(MAX_PY_FRAMES)]
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for UnwindRow {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ProcUnwind {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DwarfStack {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PyProc {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PyStack {}
+
 /* Global configuration */
-#[no_mangle]
-static SKIP_IDLE: u8 = 0;
 
-pub unsafe fn skip_idle() -> bool {
-    let skip = core::ptr::read_volatile(&SKIP_IDLE); // TODO: why we need this?
-    skip > 0
+/// Declares a global the loader sets before the programs are loaded, and its
+/// getter. The global is read volatile so that the compiler does not fold in
+/// the value it is declared with.
+macro_rules! config {
+    ($(#[$doc:meta])* $name:ident: u8 => fn $getter:ident() -> bool) => {
+        #[no_mangle]
+        static $name: u8 = 0;
+
+        $(#[$doc])*
+        pub fn $getter() -> bool {
+            // SAFETY: a static is always valid for reads
+            unsafe { core::ptr::read_volatile(&$name) > 0 }
+        }
+    };
+    ($(#[$doc:meta])* $name:ident: $ty:ty => fn $getter:ident() -> $ret:ty) => {
+        #[no_mangle]
+        static $name: $ty = 0;
+
+        $(#[$doc])*
+        pub fn $getter() -> $ret {
+            // SAFETY: a static is always valid for reads
+            unsafe { core::ptr::read_volatile(&$name) }
+        }
+    };
+}
+
+config!(SKIP_IDLE: u8 => fn skip_idle() -> bool);
+
+config! {
+    /// Only count stacks in the `counts` map, without publishing every sample.
+    AGGREGATE: u8 => fn aggregate() -> bool
+}
+
+config! {
+    /// Process the tracepoint programs are limited to, 0 for all.
+    TARGET_TGID: u32 => fn target_tgid() -> u32
+}
+
+config! {
+    /// Also record the time threads wait for a cpu, see THREAD_RUNNABLE.
+    WALL_CLOCK: u8 => fn wall_clock() -> bool
+}
+
+config! {
+    /// Unwind user stacks with the tables in `unwind_rows` instead of frame pointers.
+    DWARF_UNWIND: u8 => fn dwarf_unwind() -> bool
+}
+
+config! {
+    /// Bytes of user stack copied into every sample, 0 to not take snapshots.
+    SNAPSHOT_SIZE: u32 => fn snapshot_size() -> u32
+}
+
+config! {
+    /// Walk the frames of CPython threads, see `python_procs`.
+    PYTHON_UNWIND: u8 => fn python_unwind() -> bool
 }
@@ -1,101 +1,51 @@
 #![no_std]
 #![no_main]
 
+// Samples go through a BPF ring buffer (Linux 5.8+), see perf.rs for the
+// fallback used on older kernels.
+
+mod offcpu;
+mod profile;
+mod python;
+mod snapshot;
+mod unwind;
+
 use aya_ebpf::{
-    bindings::BPF_F_USER_STACK,
-    helpers::bpf_get_smp_processor_id,
-    macros::{map, perf_event},
-    maps::{HashMap, Queue, StackTrace},
-    programs::PerfEventContext,
+    macros::map,
+    maps::{PerCpuArray, RingBuf},
     EbpfContext,
 };
+use doctor_common::StackInfo;
 
-use doctor_common::{skip_idle, StackInfo};
-
-const STACK_SIZE: u32 = 100000;
+const EVENTS_SIZE: u32 = 16 * 1024 * 1024;
 
-#[map(name = "stack_traces")]
-pub static mut STACK_TRACE: StackTrace = StackTrace::with_max_entries(STACK_SIZE, 0);
+#[map(name = "events")]
+pub static EVENTS: RingBuf = RingBuf::with_byte_size(EVENTS_SIZE, 0);
 
-#[map]
-pub static STACKS: Queue<StackInfo> = Queue::with_max_entries(STACK_SIZE, 0);
-
-#[map(name = "counts")]
-pub static mut COUNTS: HashMap<StackInfo, u64> = HashMap::with_max_entries(STACK_SIZE, 0);
-
-#[perf_event]
-pub fn doctor(ctx: PerfEventContext) -> u32 {
-    let pid = match try_cpu_profier(ctx) {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    };
-    return pid;
-}
+/// Samples not published because the ring buffer was full.
+#[map(name = "dropped")]
+pub static DROPPED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
 
 #[inline(always)]
-unsafe fn try_get_stack_info(ctx: &PerfEventContext) -> StackInfo {
-    let (cpu, tgid, pid, cmd) = (
-        bpf_get_smp_processor_id(),
-        ctx.tgid(),
-        ctx.pid(),
-        ctx.command().unwrap_or_default(),
-    );
-
-    let (user_stack_id, kernel_stack_id) = (
-        STACK_TRACE
-            .get_stackid(ctx, BPF_F_USER_STACK.into())
-            .ok()
-            .and_then(|v| Some(v as i32)),
-        STACK_TRACE
-            .get_stackid(ctx, 0)
-            .ok()
-            .and_then(|v| Some(v as i32)),
-    );
-
-    StackInfo {
-        cpu,
-        tgid,
-        pid,
-        cmd,
-        user_stack_id,
-        kernel_stack_id,
+pub(crate) fn emit<C: EbpfContext>(_ctx: &C, stack: &StackInfo) {
+    if EVENTS.output(stack, 0).is_err() {
+        count_drop();
     }
 }
 
-fn kernel_idel_stack(stack: &StackInfo) -> bool {
-    if stack.pid == 0 && stack.cmd.starts_with("swapper".as_bytes()) {
-        true
-    } else {
-        false
+/// Publishes a sample longer than `StackInfo`, starting with one.
+#[inline(always)]
+pub(crate) fn emit_raw<C: EbpfContext>(_ctx: &C, data: &[u8]) {
+    if EVENTS.output(data, 0).is_err() {
+        count_drop();
     }
 }
 
-unsafe fn try_profile(ctx: &PerfEventContext) -> Result<u32, u32> {
-    if skip_idle() && ctx.pid() == 0 {
-        // not profiling idle
-        return Ok(0);
-    }
-
-    let stack_info = try_get_stack_info(&ctx);
-    if kernel_idel_stack(&stack_info) {
-        return Ok(0);
-    }
-
-    match COUNTS.get_ptr_mut(&stack_info) {
-        Some(cnt) => {
-            *cnt += 1;
-        }
-        None => {
-            COUNTS.insert(&stack_info, &1, 0);
-        }
+#[inline(always)]
+fn count_drop() {
+    if let Some(dropped) = DROPPED.get_ptr_mut(0) {
+        unsafe { *dropped += 1 };
     }
-
-    STACKS.push(&stack_info, 0);
-    Ok(0)
-}
-
-fn try_cpu_profier(ctx: PerfEventContext) -> Result<u32, u32> {
-    unsafe { try_profile(&ctx) }
 }
 
 #[panic_handler]
@@ -0,0 +1,128 @@
+use aya_ebpf::{
+    bindings::BPF_F_USER_STACK,
+    helpers::{bpf_get_smp_processor_id, bpf_ktime_get_ns},
+    macros::{map, tracepoint},
+    maps::LruHashMap,
+    programs::TracePointContext,
+    EbpfContext,
+};
+use doctor_common::{
+    python_unwind, target_tgid, wall_clock, StackInfo, THREAD_IO, THREAD_RUNNABLE,
+    THREAD_SLEEPING,
+};
+
+use crate::{emit, profile::STACK_TRACE, python::unwind_python};
+
+const BLOCKED_SIZE: u32 = 65536;
+
+// field offsets from /sys/kernel/tracing/events/sched/*/format, stable since 4.x
+const SWITCH_PREV_PID: usize = 24;
+const SWITCH_PREV_STATE: usize = 32;
+const SWITCH_NEXT_PID: usize = 56;
+const WAKEUP_PID: usize = 24;
+
+// blocked states of TASK_REPORT, preempted threads are reported as
+// TASK_REPORT_MAX which is outside of it
+const TASK_REPORT: i64 = 0x7f;
+const TASK_UNINTERRUPTIBLE: i64 = 0x2;
+
+/// Threads currently off-cpu: tid -> stack at the time it was switched out,
+/// with the state and the time it entered it.
+/// LRU so threads exiting while blocked do not fill the map.
+#[map(name = "blocked")]
+pub static mut BLOCKED: LruHashMap<u32, StackInfo> =
+    LruHashMap::with_max_entries(BLOCKED_SIZE, 0);
+
+#[tracepoint]
+pub fn doctor_sched_switch(ctx: TracePointContext) -> u32 {
+    match unsafe { try_sched_switch(&ctx) } {
+        Ok(ret) => ret,
+        Err(_) => 0,
+    }
+}
+
+#[tracepoint]
+pub fn doctor_sched_wakeup(ctx: TracePointContext) -> u32 {
+    match unsafe { try_sched_wakeup(&ctx) } {
+        Ok(ret) => ret,
+        Err(_) => 0,
+    }
+}
+
+/// Runs in the context of the thread being switched out.
+unsafe fn try_sched_switch(ctx: &TracePointContext) -> Result<u32, i64> {
+    let now = bpf_ktime_get_ns();
+    if wall_clock() {
+        // the thread switched in stops waiting for a cpu
+        let next_pid: i32 = ctx.read_at(SWITCH_NEXT_PID)?;
+        finish(ctx, next_pid as u32, now)?;
+    }
+
+    let prev_pid: i32 = ctx.read_at(SWITCH_PREV_PID)?;
+    let prev_state: i64 = ctx.read_at(SWITCH_PREV_STATE)?;
+    let state = if prev_state & TASK_REPORT == 0 {
+        // preempted threads are still runnable, they did not block
+        if !wall_clock() {
+            return Ok(0);
+        }
+        THREAD_RUNNABLE
+    } else if prev_state & TASK_UNINTERRUPTIBLE != 0 {
+        THREAD_IO
+    } else {
+        THREAD_SLEEPING
+    };
+    let tgid = ctx.tgid();
+    let target = target_tgid();
+    if prev_pid == 0 || (target != 0 && tgid != target) {
+        return Ok(0);
+    }
+
+    let mut info: StackInfo = core::mem::zeroed();
+    info.cpu = bpf_get_smp_processor_id();
+    info.tgid = tgid;
+    info.pid = ctx.pid();
+    info.cmd = ctx.command().unwrap_or_default();
+    info.state = state;
+    info.user_stack_id = STACK_TRACE
+        .get_stackid(ctx, BPF_F_USER_STACK.into())
+        .ok()
+        .map(|v| v as i32);
+    info.kernel_stack_id = STACK_TRACE.get_stackid(ctx, 0).ok().map(|v| v as i32);
+    if python_unwind() {
+        info.python_stack_hash = unwind_python(tgid, info.pid).unwrap_or_default();
+    }
+    info.ts = now;
+    BLOCKED.insert(&info.pid, &info, 0)?;
+    Ok(0)
+}
+
+unsafe fn try_sched_wakeup(ctx: &TracePointContext) -> Result<u32, i64> {
+    let pid: i32 = ctx.read_at(WAKEUP_PID)?;
+    let now = bpf_ktime_get_ns();
+    if let Some(mut info) = finish(ctx, pid as u32, now)? {
+        if wall_clock() {
+            // woken up, waits for a cpu with the same stack
+            info.state = THREAD_RUNNABLE;
+            info.ts = now;
+            info.period = 0;
+            BLOCKED.insert(&info.pid, &info, 0)?;
+        }
+    }
+    Ok(0)
+}
+
+/// Publishes the time `pid` spent in its current off-cpu state.
+unsafe fn finish<C: EbpfContext>(
+    ctx: &C,
+    pid: u32,
+    now: u64,
+) -> Result<Option<StackInfo>, i64> {
+    let mut info = match BLOCKED.get(&pid) {
+        Some(info) => *info,
+        None => return Ok(None),
+    };
+    BLOCKED.remove(&pid)?;
+    info.period = now - info.ts;
+    emit(ctx, &info);
+    Ok(Some(info))
+}
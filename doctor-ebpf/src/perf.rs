@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+// Same programs as main.rs, but samples go through a per-cpu perf buffer for
+// kernels without BPF ring buffers.
+
+mod offcpu;
+mod profile;
+mod python;
+mod snapshot;
+mod unwind;
+
+use aya_ebpf::{macros::map, maps::PerfEventByteArray, EbpfContext};
+use doctor_common::StackInfo;
+
+// bytes, samples with a stack snapshot are longer than `StackInfo`. The
+// kernel counts the samples lost on a full buffer and tells userspace.
+#[map(name = "events")]
+pub static EVENTS: PerfEventByteArray = PerfEventByteArray::new(0);
+
+#[inline(always)]
+pub(crate) fn emit<C: EbpfContext>(ctx: &C, stack: &StackInfo) {
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            stack as *const StackInfo as *const u8,
+            core::mem::size_of::<StackInfo>(),
+        )
+    };
+    emit_raw(ctx, data);
+}
+
+/// Publishes a sample longer than `StackInfo`, starting with one.
+#[inline(always)]
+pub(crate) fn emit_raw<C: EbpfContext>(ctx: &C, data: &[u8]) {
+    EVENTS.output(ctx, data, 0);
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
@@ -0,0 +1,129 @@
+use aya_ebpf::{
+    bindings::{bpf_perf_event_data, BPF_F_USER_STACK},
+    helpers::{bpf_get_smp_processor_id, bpf_ktime_get_ns},
+    macros::{map, perf_event},
+    maps::{Array, HashMap, StackTrace},
+    programs::PerfEventContext,
+    EbpfContext,
+};
+use doctor_common::{aggregate, dwarf_unwind, python_unwind, skip_idle, StackInfo};
+
+use crate::{emit, python::unwind_python, snapshot::emit_snapshot, unwind::unwind_user};
+
+const STACK_SIZE: u32 = 100000;
+
+#[map(name = "stack_traces")]
+pub static mut STACK_TRACE: StackTrace = StackTrace::with_max_entries(STACK_SIZE, 0);
+
+/// Stacks are counted in one of the two maps while userspace drains the
+/// other, `counts_slot` tells which.
+#[map(name = "counts")]
+pub static mut COUNTS: HashMap<StackInfo, u64> = HashMap::with_max_entries(STACK_SIZE, 0);
+
+#[map(name = "counts_alt")]
+pub static mut COUNTS_ALT: HashMap<StackInfo, u64> = HashMap::with_max_entries(STACK_SIZE, 0);
+
+#[map(name = "counts_slot")]
+static COUNTS_SLOT: Array<u32> = Array::with_max_entries(1, 0);
+
+#[perf_event]
+pub fn doctor(ctx: PerfEventContext) -> u32 {
+    let pid = match try_cpu_profier(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    return pid;
+}
+
+#[inline(always)]
+unsafe fn try_get_stack_info(ctx: &PerfEventContext) -> StackInfo {
+    let (cpu, tgid, pid, cmd) = (
+        bpf_get_smp_processor_id(),
+        ctx.tgid(),
+        ctx.pid(),
+        ctx.command().unwrap_or_default(),
+    );
+
+    let user_stack_hash = if dwarf_unwind() {
+        unwind_user(ctx).unwrap_or_default()
+    } else {
+        0
+    };
+    let (user_stack_id, kernel_stack_id) = (
+        match user_stack_hash {
+            0 => STACK_TRACE
+                .get_stackid(ctx, BPF_F_USER_STACK.into())
+                .ok()
+                .and_then(|v| Some(v as i32)),
+            _ => None,
+        },
+        STACK_TRACE
+            .get_stackid(ctx, 0)
+            .ok()
+            .and_then(|v| Some(v as i32)),
+    );
+
+    // zeroed first so the padding bytes are initialized, the struct is used as
+    // a map key
+    let mut info: StackInfo = core::mem::zeroed();
+    info.cpu = cpu;
+    info.tgid = tgid;
+    info.pid = pid;
+    info.cmd = cmd;
+    info.user_stack_id = user_stack_id;
+    info.kernel_stack_id = kernel_stack_id;
+    info.user_stack_hash = user_stack_hash;
+    if python_unwind() {
+        info.python_stack_hash = unwind_python(tgid, pid).unwrap_or_default();
+    }
+    info.ts = bpf_ktime_get_ns();
+    info.period = (*(ctx.as_ptr() as *const bpf_perf_event_data)).sample_period;
+    info
+}
+
+fn kernel_idel_stack(stack: &StackInfo) -> bool {
+    if stack.pid == 0 && stack.cmd.starts_with("swapper".as_bytes()) {
+        true
+    } else {
+        false
+    }
+}
+
+unsafe fn try_profile(ctx: &PerfEventContext) -> Result<u32, u32> {
+    if skip_idle() && ctx.pid() == 0 {
+        // not profiling idle
+        return Ok(0);
+    }
+
+    let stack_info = try_get_stack_info(&ctx);
+    if kernel_idel_stack(&stack_info) {
+        return Ok(0);
+    }
+
+    if aggregate() {
+        // counts are per stack, not per sample
+        let mut key = stack_info;
+        key.ts = 0;
+        key.period = 0;
+        let counts = match COUNTS_SLOT.get(0) {
+            Some(1) => &COUNTS_ALT,
+            _ => &COUNTS,
+        };
+        match counts.get_ptr_mut(&key) {
+            Some(cnt) => {
+                *cnt += 1;
+            }
+            None => {
+                counts.insert(&key, &1, 0);
+            }
+        }
+    } else if !emit_snapshot(ctx, &stack_info) {
+        // samples without a snapshot fall back to the frame pointer stack
+        emit(ctx, &stack_info);
+    }
+    Ok(0)
+}
+
+fn try_cpu_profier(ctx: PerfEventContext) -> Result<u32, u32> {
+    unsafe { try_profile(&ctx) }
+}
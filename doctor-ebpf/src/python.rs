@@ -0,0 +1,133 @@
+use aya_ebpf::{
+    bindings::bpf_pidns_info,
+    helpers::{bpf_get_ns_current_pid_tgid, bpf_probe_read_user},
+    macros::map,
+    maps::{HashMap, LruHashMap, PerCpuArray},
+};
+use doctor_common::{
+    PyFrame, PyProc, PyStack, MAX_PY_FRAMES, MAX_PY_THREADS, PY_ENTRY_FRAME,
+    PY_FRAME_OWNED_BY_CSTACK, PY_NONE,
+};
+
+use crate::unwind::stack_key;
+
+const MAX_PROCS: u32 = 4096;
+const MAX_STACKS: u32 = 65536;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// tgid -> `_PyRuntime` and struct offsets, filled by userspace.
+#[map(name = "python_procs")]
+pub static PYTHON_PROCS: HashMap<u32, PyProc> = HashMap::with_max_entries(MAX_PROCS, 0);
+
+/// Python stacks by `stack_key`, removed by userspace once read.
+#[map(name = "python_stacks")]
+pub static PYTHON_STACKS: LruHashMap<u64, PyStack> = LruHashMap::with_max_entries(MAX_STACKS, 0);
+
+// a stack does not fit in the 512 bytes of BPF stack
+#[map(name = "python_scratch")]
+static SCRATCH: PerCpuArray<PyStack> = PerCpuArray::with_max_entries(1, 0);
+
+/// Walks the interpreter frames of the current thread. Returns their key in
+/// `python_stacks`, or None when the process is not a known CPython or the
+/// thread has no Python frames.
+#[inline(always)]
+pub unsafe fn unwind_python(tgid: u32, pid: u32) -> Option<u64> {
+    let proc = PYTHON_PROCS.get(&tgid)?;
+    let off = &proc.offsets;
+    let tstate = find_thread(proc, pid)?;
+    let mut frame: u64 = read(tstate + off.tstate_frame)?;
+    if off.cframe_current_frame != PY_NONE && frame != 0 {
+        frame = read(frame + off.cframe_current_frame)?;
+    }
+    let stack = &mut *SCRATCH.get_ptr_mut(0)?;
+
+    let mut len = 0;
+    for _ in 0..MAX_PY_FRAMES {
+        if frame == 0 {
+            break;
+        }
+        let (Some(code), Some(instr), Some(previous), Some(owner)) = (
+            read::<u64>(frame + off.frame_code),
+            read::<u64>(frame + off.frame_instr),
+            read::<u64>(frame + off.frame_previous),
+            read::<u8>(frame + off.frame_owner),
+        ) else {
+            break;
+        };
+        if owner == PY_FRAME_OWNED_BY_CSTACK {
+            // the frame before is the first one of an evaluation loop call
+            if len > 0 {
+                if let Some(entry) = stack.frames.get_mut(len - 1) {
+                    entry.code |= PY_ENTRY_FRAME;
+                }
+            }
+        } else if let Some(slot) = stack.frames.get_mut(len) {
+            let entry = off.frame_is_entry != PY_NONE
+                && read::<u8>(frame + off.frame_is_entry).unwrap_or(0) != 0;
+            *slot = PyFrame {
+                code: code | entry as u64,
+                instr,
+            };
+            len += 1;
+        }
+        frame = previous;
+    }
+    if len == 0 {
+        return None;
+    }
+    stack.len = len as u64;
+
+    let mut id = FNV_OFFSET;
+    for i in 0..MAX_PY_FRAMES {
+        if i >= len {
+            break;
+        }
+        let frame = &stack.frames[i];
+        id = (id ^ frame.code).wrapping_mul(FNV_PRIME);
+        id = (id ^ frame.instr).wrapping_mul(FNV_PRIME);
+    }
+    let id = stack_key(id)?;
+    PYTHON_STACKS.insert(&id, stack, 0).ok()?;
+    Some(id)
+}
+
+/// Thread state of the thread `pid` in the main interpreter.
+#[inline(always)]
+unsafe fn find_thread(proc: &PyProc, pid: u32) -> Option<u64> {
+    let off = &proc.offsets;
+    // the tid of the current thread in the namespace of the process
+    let pid = match proc.pidns_ino {
+        0 => pid,
+        ino => {
+            let mut ns: bpf_pidns_info = core::mem::zeroed();
+            let size = core::mem::size_of::<bpf_pidns_info>() as u32;
+            if bpf_get_ns_current_pid_tgid(proc.pidns_dev, ino, &mut ns, size) != 0 {
+                return None;
+            }
+            ns.pid
+        }
+    };
+    let interp: u64 = read(proc.runtime + off.runtime_interpreters_head)?;
+    if interp == 0 {
+        return None;
+    }
+    let mut tstate: u64 = read(interp + off.interp_threads_head)?;
+    for _ in 0..MAX_PY_THREADS {
+        if tstate == 0 {
+            return None;
+        }
+        let id: u64 = read(tstate + off.tstate_native_thread_id)?;
+        if id == pid as u64 {
+            return Some(tstate);
+        }
+        tstate = read(tstate + off.tstate_next)?;
+    }
+    None
+}
+
+#[inline(always)]
+unsafe fn read<T>(addr: u64) -> Option<T> {
+    bpf_probe_read_user(addr as *const T).ok()
+}
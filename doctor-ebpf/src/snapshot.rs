@@ -0,0 +1,59 @@
+use aya_ebpf::{
+    bindings::bpf_perf_event_data, helpers::bpf_probe_read_user_buf, macros::map,
+    maps::PerCpuArray, programs::PerfEventContext, EbpfContext,
+};
+use doctor_common::{
+    snapshot_size, StackInfo, StackSnapshot, MAX_SNAPSHOT_SIZE, SNAPSHOT_HEADER_SIZE,
+};
+
+use crate::emit_raw;
+
+// the copy is halved when the stack ends before the configured size
+const COPY_TRIES: usize = 4;
+
+#[map(name = "snapshot_scratch")]
+static SCRATCH: PerCpuArray<StackSnapshot> = PerCpuArray::with_max_entries(1, 0);
+
+/// Publishes the sample with the user registers and the top of the user
+/// stack instead of a user stack id. False when snapshots are off or the
+/// task was interrupted in the kernel, where its user registers are not at
+/// hand.
+#[inline(always)]
+pub unsafe fn emit_snapshot(ctx: &PerfEventContext, info: &StackInfo) -> bool {
+    let mut size = snapshot_size() as usize;
+    if size == 0 {
+        return false;
+    }
+    let regs = &(*(ctx.as_ptr() as *const bpf_perf_event_data)).regs;
+    if regs.cs & 3 == 0 {
+        return false;
+    }
+    let snapshot = match SCRATCH.get_ptr_mut(0) {
+        Some(snapshot) => &mut *snapshot,
+        None => return false,
+    };
+    snapshot.info = *info;
+    snapshot.ip = regs.rip;
+    snapshot.sp = regs.rsp;
+    snapshot.bp = regs.rbp;
+
+    let mut copied = 0;
+    for _ in 0..COPY_TRIES {
+        size = size.min(MAX_SNAPSHOT_SIZE);
+        if size == 0 {
+            break;
+        }
+        let dst = &mut snapshot.data[..size];
+        if bpf_probe_read_user_buf(regs.rsp as *const u8, dst).is_ok() {
+            copied = size;
+            break;
+        }
+        size /= 2;
+    }
+    snapshot.size = copied as u64;
+
+    let len = (SNAPSHOT_HEADER_SIZE + copied).min(core::mem::size_of::<StackSnapshot>());
+    let bytes = core::slice::from_raw_parts(snapshot as *const _ as *const u8, len);
+    emit_raw(ctx, bytes);
+    true
+}
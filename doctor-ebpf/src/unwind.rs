@@ -0,0 +1,162 @@
+use aya_ebpf::{
+    bindings::bpf_perf_event_data,
+    helpers::{bpf_get_smp_processor_id, bpf_probe_read_user},
+    macros::map,
+    maps::{Array, HashMap, LruHashMap, PerCpuArray},
+    programs::PerfEventContext,
+    EbpfContext,
+};
+use doctor_common::{
+    aggregate, DwarfStack, ProcUnwind, UnwindMapping, UnwindRow, CFA_PLT, CFA_RBP, CFA_RSP,
+    MAX_DWARF_FRAMES, RBP_SAVED, UNWIND_ROWS,
+};
+
+const MAX_PROCS: u32 = 4096;
+const MAX_STACKS: u32 = 65536;
+// binary search steps over MAX_UNWIND_MAPPINGS mappings and UNWIND_ROWS rows
+const MAPPING_STEPS: usize = 7;
+const ROW_STEPS: usize = 21;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Unwind tables of all loaded ELFs, one sorted range per ELF.
+#[map(name = "unwind_rows")]
+pub static UNWIND_ROWS_MAP: Array<UnwindRow> = Array::with_max_entries(UNWIND_ROWS, 0);
+
+/// tgid -> executable mappings, filled by userspace.
+#[map(name = "unwind_procs")]
+pub static UNWIND_PROCS: HashMap<u32, ProcUnwind> = HashMap::with_max_entries(MAX_PROCS, 0);
+
+/// Unwound stacks by `stack_key`, removed by userspace once read. Stacks of
+/// samples lost on a full event buffer are never read, the least recently
+/// used go first.
+#[map(name = "dwarf_stacks")]
+pub static DWARF_STACKS: LruHashMap<u64, DwarfStack> = LruHashMap::with_max_entries(MAX_STACKS, 0);
+
+// samples taken on the cpu so far, see `stack_key`
+#[map(name = "sample_seq")]
+static SAMPLE_SEQ: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+// a stack does not fit in the 512 bytes of BPF stack
+#[map(name = "dwarf_scratch")]
+static SCRATCH: PerCpuArray<DwarfStack> = PerCpuArray::with_max_entries(1, 0);
+
+/// Unwinds the user stack of the current task with the tables loaded by
+/// userspace. Returns its key in `dwarf_stacks`, or None when the process has
+/// no tables or was interrupted in the kernel, where its user registers are
+/// not at hand.
+#[inline(always)]
+pub unsafe fn unwind_user(ctx: &PerfEventContext) -> Option<u64> {
+    let proc = UNWIND_PROCS.get(&ctx.tgid())?;
+    let regs = &(*(ctx.as_ptr() as *const bpf_perf_event_data)).regs;
+    if regs.cs & 3 == 0 {
+        return None;
+    }
+    let stack = &mut *SCRATCH.get_ptr_mut(0)?;
+
+    let (mut ip, mut sp, mut bp) = (regs.rip, regs.rsp, regs.rbp);
+    let mut len = 0;
+    for i in 0..MAX_DWARF_FRAMES {
+        stack.ips[i] = ip;
+        len = i + 1;
+        let mapping = match find_mapping(proc, ip) {
+            Some(mapping) => mapping,
+            None => break,
+        };
+        // return addresses point after the call, look up the call itself
+        let pc = ip.wrapping_sub(mapping.bias).wrapping_sub((i > 0) as u64);
+        let row = match find_row(mapping, pc) {
+            Some(row) => row,
+            None => break,
+        };
+        let cfa = match row.cfa_rule {
+            CFA_RSP => sp.wrapping_add(row.cfa_offset as i64 as u64),
+            CFA_RBP => bp.wrapping_add(row.cfa_offset as i64 as u64),
+            CFA_PLT => sp + if ip & 15 >= 11 { 16 } else { 8 },
+            _ => break,
+        };
+        if row.rbp_rule == RBP_SAVED {
+            let saved = cfa.wrapping_add(row.rbp_offset as i64 as u64);
+            bp = bpf_probe_read_user(saved as *const u64).unwrap_or(0);
+        }
+        // the return address is pushed right below the CFA on x86_64
+        ip = match bpf_probe_read_user((cfa - 8) as *const u64) {
+            Ok(ip) => ip,
+            Err(_) => break,
+        };
+        sp = cfa;
+        if ip == 0 {
+            break;
+        }
+    }
+    stack.len = len as u64;
+
+    let mut id = FNV_OFFSET;
+    for i in 0..MAX_DWARF_FRAMES {
+        if i >= len {
+            break;
+        }
+        id = (id ^ stack.ips[i]).wrapping_mul(FNV_PRIME);
+    }
+    let id = stack_key(id)?;
+    DWARF_STACKS.insert(&id, stack, 0).ok()?;
+    Some(id)
+}
+
+/// Key of a stack with hash `hash`, never 0. Counted stacks share the key of
+/// their hash with the same stacks; a published sample gets a key of its own
+/// so userspace can remove its stack once read.
+#[inline(always)]
+pub unsafe fn stack_key(hash: u64) -> Option<u64> {
+    if aggregate() {
+        return Some(hash | 1);
+    }
+    let seq = &mut *SAMPLE_SEQ.get_ptr_mut(0)?;
+    *seq += 1;
+    Some((*seq << 16) | (bpf_get_smp_processor_id() as u64 & 0xffff))
+}
+
+#[inline(always)]
+unsafe fn find_mapping(proc: &ProcUnwind, ip: u64) -> Option<&UnwindMapping> {
+    let (mut lo, mut hi) = (0, proc.len as usize);
+    for _ in 0..MAPPING_STEPS {
+        if lo >= hi {
+            break;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let mapping = proc.mappings.get(mid)?;
+        if ip < mapping.start {
+            hi = mid;
+        } else if ip >= mapping.end {
+            lo = mid + 1;
+        } else {
+            return Some(mapping);
+        }
+    }
+    None
+}
+
+/// Last row of the mapping's table at or before `pc`.
+#[inline(always)]
+unsafe fn find_row(mapping: &UnwindMapping, pc: u64) -> Option<&UnwindRow> {
+    let (mut lo, mut hi) = (
+        mapping.table_start,
+        mapping.table_start + mapping.table_len,
+    );
+    for _ in 0..ROW_STEPS {
+        if lo >= hi {
+            break;
+        }
+        let mid = lo + (hi - lo) / 2;
+        if UNWIND_ROWS_MAP.get(mid)?.pc <= pc {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == mapping.table_start {
+        return None;
+    }
+    UNWIND_ROWS_MAP.get(lo - 1)
+}
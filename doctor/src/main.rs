@@ -1,38 +1,376 @@
 use aya_log::EbpfLogger;
-use clap::Parser;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Error};
-use aya::maps::{HashMap, MapData, Queue, StackTraceMap};
-use aya::programs::{perf_event, PerfEvent};
+use aya::maps::{Array, HashMap, MapData, MapError, StackTraceMap};
+use aya::programs::{perf_event, PerfEvent, TracePoint};
 use aya::util::online_cpus;
-use aya::{include_bytes_aligned, Ebpf};
-use doctor_common::StackInfo;
+use aya::{include_bytes_aligned, Ebpf, EbpfLoader};
+use doctor_common::{DwarfStack, PyStack, StackInfo, MAX_SNAPSHOT_SIZE};
 use log::{debug, info, warn};
-use profiler::perf_record::PerfRecord;
+use profiler::event::{dropped_samples, EventSource, Sample, QUEUED_SAMPLES};
+use profiler::formater::{
+    flamegraph::{FlamegraphFormater, FlamegraphOptions},
+    folded::{FoldedFormater, FoldedOptions},
+    gecko::GeckoFormater,
+    pprof::PprofFormater,
+    raw::{RawFormater, RawReader},
+    speedscope::SpeedscopeFormater,
+    Formater, Unit,
+};
+use profiler::perf_record::{PerfRecord, PerfStackFrame};
+use profiler::process::ProcessTracker;
+use profiler::symbolizer::{
+    debuginfo::DebugDirs,
+    debuginfod::Debuginfod,
+    kallsyms::KernelImages,
+    symbolizer::{Symbolizer, SymbolizerOptions},
+};
+use profiler::unwind::{
+    loader::DwarfUnwinder, python::PythonUnwinder, snapshot::SnapshotUnwinder,
+};
 
-use tokio::signal;
+use tokio::{signal, sync::mpsc};
 
 use crate::profiler::translator::Translator;
 mod profiler;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Options of `record`, the default command
+    #[command(flatten)]
+    record: ProfileOptions,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Profile, the default command
+    Record(ProfileOptions),
+    /// Symbolize a profile recorded with `record --no-symbolize`
+    Symbolize(SymbolizeOptions),
+}
 
+#[derive(Args, Debug)]
 struct ProfileOptions {
     #[arg(short, long)]
     pid: Option<u32>,
+    /// What to profile
+    #[arg(short, long, value_enum, default_value = "cpu")]
+    mode: ProfileMode,
+    /// Session length in seconds, 0 runs until Ctrl-C
     #[arg(short, long, default_value = "5")]
     duration: u32,
+    /// Samples per second per cpu [default: 1000]
     #[arg(short, long)]
     frequency: Option<u32>,
+    /// Sample every N ns of cpu time instead of at a frequency
+    #[arg(long, conflicts_with = "frequency")]
+    period: Option<u64>,
     #[arg(long)]
     debug: Option<bool>,
+    /// How user stacks are unwound
+    #[arg(long, value_enum, default_value = "fp")]
+    unwind: UnwindMode,
+    /// Walk the frames of CPython 3.11 to 3.13 threads and show the Python
+    /// functions in place of the interpreter loop in user stacks
+    #[arg(long)]
+    python: bool,
+    /// KB of user stack copied into every sample with `--unwind snapshot`
+    #[arg(long, default_value = "8")]
+    stack_size: u32,
+    /// Count stacks in the kernel instead of streaming every sample, each
+    /// unique stack is symbolized once when the counts are drained
+    #[arg(long)]
+    aggregate: bool,
+    /// Drain the in-kernel counts every N seconds instead of at the end
+    #[arg(long, requires = "aggregate")]
+    aggregate_interval: Option<u64>,
+    /// Kernel image with symbols, and DWARF for inlined functions and lines,
+    /// to symbolize kernel frames with
+    #[arg(long)]
+    vmlinux: Option<PathBuf>,
+    /// System.map of the running kernel, for when /proc/kallsyms hides
+    /// addresses
+    #[arg(long)]
+    system_map: Option<PathBuf>,
+    /// Write raw addresses, the executable mappings of the processes and the
+    /// build-ids of their files instead of symbols, for `doctor symbolize`
+    /// to symbolize elsewhere
+    #[arg(long, requires = "output", conflicts_with_all = ["python", "format"])]
+    no_symbolize: bool,
+    #[command(flatten)]
+    out: OutputOptions,
+    #[command(flatten)]
+    symbols: SymbolOptions,
+}
+
+#[derive(Args, Debug)]
+struct SymbolizeOptions {
+    /// Raw profile written by `record --no-symbolize`
+    input: PathBuf,
+    #[command(flatten)]
+    out: OutputOptions,
+    #[command(flatten)]
+    symbols: SymbolOptions,
+}
+
+#[derive(Args, Debug)]
+struct OutputOptions {
+    /// Write the aggregated profile to this file instead of printing samples
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output format, guessed from the output file extension by default
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+    /// Folded and flamegraph output: put kernel frames before user frames
+    #[arg(long)]
+    kernel_first: bool,
+    /// Folded output: add the pid to the root frame
+    #[arg(long)]
+    with_pid: bool,
+    /// Folded output: add the pid and tid to the root frame
+    #[arg(long)]
+    with_tid: bool,
+}
+
+#[derive(Args, Debug)]
+struct SymbolOptions {
+    /// Directory of the symbol cache and debuginfod downloads, kept across
+    /// runs [default: $XDG_CACHE_HOME/doctor/symbols or ~/.cache/doctor/symbols]
+    #[arg(long)]
+    symbol_cache: Option<PathBuf>,
+    /// MB of symbol tables kept in the symbol cache, 0 disables it
+    #[arg(long, default_value = "1024")]
+    symbol_cache_size: u64,
+    /// Directory of separate debug files, searched like /usr/lib/debug by
+    /// build-id and .gnu_debuglink, can be repeated
+    #[arg(long)]
+    debug_dir: Vec<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum ProfileMode {
+    /// sample stacks running on a cpu
+    Cpu,
+    /// record stacks blocked in the scheduler, weighted by ns until wakeup
+    Offcpu,
+    /// on-cpu samples and off-cpu time together, every stack tagged with the
+    /// thread state: running, runnable, sleeping or io
+    Wall,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum UnwindMode {
+    /// frame pointers, in the kernel
+    Fp,
+    /// .eh_frame/.debug_frame unwind tables loaded into the kernel, for
+    /// binaries built without frame pointers (x86_64, on-cpu samples)
+    Dwarf,
+    /// copy the registers and the top of the user stack into every sample
+    /// and unwind it in userspace (x86_64, on-cpu samples)
+    Snapshot,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// gzipped pprof profile.proto
+    Pprof,
+    /// collapsed stacks for flamegraph.pl and inferno
+    Folded,
+    /// interactive SVG flamegraph
+    Flamegraph,
+    /// interactive SVG icicle graph, the inverted flamegraph
+    Icicle,
+    /// speedscope JSON, one timeline per thread
+    Speedscope,
+    /// Firefox Profiler (Gecko) JSON, one track per thread
+    Firefox,
 }
 
-fn load_ebpf(opts: ProfileOptions) -> Result<Ebpf, Error> {
+impl OutputFormat {
+    fn guess(output: &Path) -> Self {
+        if output.to_string_lossy().ends_with(".speedscope.json") {
+            return OutputFormat::Speedscope;
+        }
+        match output.extension().and_then(|e| e.to_str()) {
+            Some("svg") => OutputFormat::Flamegraph,
+            Some("folded") | Some("txt") => OutputFormat::Folded,
+            _ => OutputFormat::Pprof,
+        }
+    }
+}
+
+const DEFAULT_FREQUENCY: u64 = 1000;
+const MAX_STACK_SIZE_KB: u32 = (MAX_SNAPSHOT_SIZE / 1024) as u32;
+const MAX_SAMPLE_RATE: &str = "/proc/sys/kernel/perf_event_max_sample_rate";
+
+impl ProfileOptions {
+    fn sample_policy(&self) -> perf_event::SamplePolicy {
+        match (self.period, self.frequency) {
+            (Some(period), _) => perf_event::SamplePolicy::Period(period),
+            (None, Some(frequency)) => perf_event::SamplePolicy::Frequency(frequency as u64),
+            (None, None) => perf_event::SamplePolicy::Frequency(DEFAULT_FREQUENCY),
+        }
+    }
+
+    /// ns of cpu time one sample stands for.
+    fn sample_period(&self) -> u64 {
+        match self.sample_policy() {
+            perf_event::SamplePolicy::Period(period) => period,
+            perf_event::SamplePolicy::Frequency(frequency) => 1_000_000_000 / frequency,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.frequency == Some(0) || self.period == Some(0) {
+            return Err(anyhow!("sample frequency and period must be positive"));
+        }
+        // a sample per ns at most, the period of higher frequencies is 0
+        if self
+            .frequency
+            .is_some_and(|frequency| frequency > 1_000_000_000)
+        {
+            return Err(anyhow!("--frequency must be at most 1000000000"));
+        }
+        let rate = 1_000_000_000 / self.sample_period();
+        match std::fs::read_to_string(MAX_SAMPLE_RATE) {
+            Ok(max) => {
+                let max = max.trim().parse::<u64>()?;
+                if rate > max {
+                    return Err(anyhow!(
+                        "sample rate {}/s exceeds {} ({}), lower --frequency or raise the limit",
+                        rate,
+                        MAX_SAMPLE_RATE,
+                        max
+                    ));
+                }
+            }
+            Err(e) => warn!("read {}: {}", MAX_SAMPLE_RATE, e),
+        }
+
+        if self.aggregate && self.mode != ProfileMode::Cpu {
+            return Err(anyhow!("--aggregate only counts on-cpu samples"));
+        }
+        if self.unwind == UnwindMode::Snapshot {
+            if self.aggregate {
+                return Err(anyhow!("--aggregate does not keep stack snapshots"));
+            }
+            if !(1..=MAX_STACK_SIZE_KB).contains(&self.stack_size) {
+                return Err(anyhow!(
+                    "--stack-size must be between 1 and {} KB",
+                    MAX_STACK_SIZE_KB
+                ));
+            }
+        }
+        if let (true, false, Some(output)) = (self.aggregate, self.no_symbolize, &self.out.output) {
+            let format = self
+                .out
+                .format
+                .unwrap_or_else(|| OutputFormat::guess(output));
+            if matches!(format, OutputFormat::Speedscope | OutputFormat::Firefox) {
+                return Err(anyhow!(
+                    "--aggregate drops sample times, {:?} output needs every sample",
+                    format
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn formater(&self, output: &Path) -> Result<Box<dyn Formater>, Error> {
+        Ok(match self.no_symbolize {
+            true => {
+                let writer = BufWriter::new(File::create(output)?);
+                Box::new(RawFormater::new(self.mode.name(), writer))
+            }
+            false => self.out.formater(self.mode, output),
+        })
+    }
+}
+
+impl ProfileMode {
+    fn name(&self) -> &'static str {
+        match self {
+            ProfileMode::Cpu => "cpu",
+            ProfileMode::Offcpu => "offcpu",
+            ProfileMode::Wall => "wall",
+        }
+    }
+
+    /// Off-cpu records only add up with on-cpu ones as time.
+    fn unit(&self) -> Unit {
+        match self {
+            ProfileMode::Cpu => Unit::Samples,
+            ProfileMode::Offcpu | ProfileMode::Wall => Unit::Nanoseconds,
+        }
+    }
+}
+
+impl OutputOptions {
+    fn formater(&self, mode: ProfileMode, output: &Path) -> Box<dyn Formater> {
+        let wall = mode == ProfileMode::Wall;
+        match self.format.unwrap_or_else(|| OutputFormat::guess(output)) {
+            OutputFormat::Pprof => Box::new(match mode {
+                ProfileMode::Cpu => PprofFormater::new(),
+                ProfileMode::Offcpu => PprofFormater::with_value_type("offcpu"),
+                ProfileMode::Wall => PprofFormater::with_value_type("wall"),
+            }),
+            OutputFormat::Folded => Box::new(FoldedFormater::new(FoldedOptions {
+                kernel_first: self.kernel_first,
+                with_pid: self.with_pid,
+                // wall-clock profiles are read per thread
+                with_tid: self.with_tid || wall,
+                with_state: wall,
+                unit: mode.unit(),
+            })),
+            format @ (OutputFormat::Flamegraph | OutputFormat::Icicle) => {
+                Box::new(FlamegraphFormater::new(FlamegraphOptions {
+                    inverted: matches!(format, OutputFormat::Icicle),
+                    kernel_first: self.kernel_first,
+                    with_state: wall,
+                    unit: mode.unit(),
+                }))
+            }
+            OutputFormat::Speedscope => Box::new(SpeedscopeFormater::new()),
+            OutputFormat::Firefox => Box::new(GeckoFormater::new()),
+        }
+    }
+}
+
+impl SymbolOptions {
+    /// Symbolizer keeping symbol tables in the on-disk cache, or only in
+    /// memory when it cannot be opened, e.g. by a second doctor, and fetching
+    /// missing debug info from the servers in DEBUGINFOD_URLS, in the
+    /// background unless `debuginfod_wait`.
+    fn symbolizer(&self, debuginfod_wait: bool) -> Result<Symbolizer, Error> {
+        Ok(Symbolizer::new(SymbolizerOptions {
+            cache_dir: self.symbol_cache.clone().or_else(default_symbol_cache),
+            cache_size: self.symbol_cache_size * 1024 * 1024,
+            debug_dirs: DebugDirs::new(self.debug_dir.clone()),
+            debuginfod_urls: Debuginfod::env_urls(),
+            debuginfod_wait,
+        })?)
+    }
+}
+
+fn default_symbol_cache() -> Option<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("doctor/symbols"))
+}
+
+fn load_ebpf(opts: &ProfileOptions) -> Result<Ebpf, Error> {
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
@@ -48,18 +386,63 @@ fn load_ebpf(opts: ProfileOptions) -> Result<Ebpf, Error> {
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
+    // `doctor` publishes samples through a ring buffer, which needs Linux 5.8,
+    // `doctor-perf` is the same program on top of a perf event array.
     #[cfg(debug_assertions)]
-    let mut bpf = Ebpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/doctor"
-    ))?;
+    let (ringbuf_obj, perf_obj) = (
+        include_bytes_aligned!("../../target/bpfel-unknown-none/debug/doctor"),
+        include_bytes_aligned!("../../target/bpfel-unknown-none/debug/doctor-perf"),
+    );
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/doctor"
-    ))?;
+    let (ringbuf_obj, perf_obj) = (
+        include_bytes_aligned!("../../target/bpfel-unknown-none/release/doctor"),
+        include_bytes_aligned!("../../target/bpfel-unknown-none/release/doctor-perf"),
+    );
+    let aggregate = opts.aggregate as u8;
+    let target_tgid = opts.pid.unwrap_or_default();
+    let wall_clock = (opts.mode == ProfileMode::Wall) as u8;
+    let dwarf_unwind = (opts.unwind == UnwindMode::Dwarf) as u8;
+    let python_unwind = opts.python as u8;
+    let snapshot_size = match opts.unwind {
+        UnwindMode::Snapshot => opts.stack_size * 1024,
+        _ => 0,
+    };
+    let load = |obj: &[u8]| {
+        EbpfLoader::new()
+            .set_global("AGGREGATE", &aggregate, true)
+            .set_global("TARGET_TGID", &target_tgid, true)
+            .set_global("WALL_CLOCK", &wall_clock, true)
+            .set_global("DWARF_UNWIND", &dwarf_unwind, true)
+            .set_global("PYTHON_UNWIND", &python_unwind, true)
+            .set_global("SNAPSHOT_SIZE", &snapshot_size, true)
+            .load(obj)
+    };
+    let mut bpf = match load(ringbuf_obj) {
+        Ok(bpf) => bpf,
+        Err(e) => {
+            warn!(
+                "ring buffer unavailable ({}), falling back to perf buffers",
+                e
+            );
+            load(perf_obj)?
+        }
+    };
     if let Err(e) = EbpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
+    match opts.mode {
+        ProfileMode::Cpu => attach_cpu(&mut bpf, opts)?,
+        ProfileMode::Offcpu => attach_offcpu(&mut bpf)?,
+        ProfileMode::Wall => {
+            attach_cpu(&mut bpf, opts)?;
+            attach_offcpu(&mut bpf)?;
+        }
+    }
+    Ok(bpf)
+}
+
+fn attach_cpu(bpf: &mut Ebpf, opts: &ProfileOptions) -> Result<(), Error> {
     // This will raise scheduled events on each CPU at 1 HZ, triggered by the kernel based
     // on clock ticks.
     let program: &mut PerfEvent = bpf.program_mut("doctor").unwrap().try_into()?;
@@ -71,7 +454,7 @@ fn load_ebpf(opts: ProfileOptions) -> Result<Ebpf, Error> {
             perf_event::PerfTypeId::Software,
             perf_event::perf_sw_ids::PERF_COUNT_SW_CPU_CLOCK as u64,
             perf_event::PerfEventScope::OneProcessAnyCpu { pid },
-            perf_event::SamplePolicy::Frequency(1000),
+            opts.sample_policy(),
             false,
         )?;
     } else {
@@ -81,78 +464,514 @@ fn load_ebpf(opts: ProfileOptions) -> Result<Ebpf, Error> {
                 perf_event::PerfTypeId::Software,
                 perf_event::perf_sw_ids::PERF_COUNT_SW_CPU_CLOCK as u64,
                 perf_event::PerfEventScope::AllProcessesOneCpu { cpu }, // where put options
-                perf_event::SamplePolicy::Frequency(1000),
+                opts.sample_policy(),
                 false,
             )?;
         }
     }
-    Ok(bpf)
+    Ok(())
+}
+
+/// Blocked stacks are taken when a thread is switched out, and published
+/// with the time blocked when it is woken up.
+fn attach_offcpu(bpf: &mut Ebpf) -> Result<(), Error> {
+    for (name, event) in [
+        ("doctor_sched_switch", "sched_switch"),
+        ("doctor_sched_wakeup", "sched_wakeup"),
+    ] {
+        let program: &mut TracePoint = bpf
+            .program_mut(name)
+            .ok_or_else(|| anyhow!("program {} not found", name))?
+            .try_into()?;
+        program.load()?;
+        program.attach("sched", event)?;
+    }
+    Ok(())
 }
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
     // read cmdline opt
-    let opts = ProfileOptions::parse();
-
-    let mut bpf = load_ebpf(opts)?;
-    const STACK_INFO_SIZE: usize = std::mem::size_of::<StackInfo>();
-
-    let mut stacks = Queue::<_, [u8; STACK_INFO_SIZE]>::try_from(bpf.take_map("STACKS").unwrap())?;
-    let _stack_count =
-        HashMap::<_, [u8; STACK_INFO_SIZE], u64>::try_from(bpf.take_map("counts").unwrap())
-            .unwrap();
-    let stack_traces = StackTraceMap::try_from(bpf.map("stack_traces").unwrap()).unwrap();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Symbolize(opts)) => symbolize(opts),
+        Some(Command::Record(opts)) => record(opts).await,
+        None => record(cli.record).await,
+    }
+}
 
-    info!("Waiting for Ctrl-C...");
-    let running = Arc::new(AtomicBool::new(true));
+async fn record(opts: ProfileOptions) -> Result<(), anyhow::Error> {
+    opts.validate()?;
 
-    let running_clone = Arc::clone(&running);
-    let handle = tokio::spawn(async move {
-        let _ = signal::ctrl_c().await;
-        running.store(false, Ordering::SeqCst);
-    });
+    let mut bpf = load_ebpf(&opts)?;
 
-    let mut translator = Translator::new("/".into());
-    while running_clone.load(Ordering::SeqCst) {
-        match stacks.pop(0) {
-            Ok(v) => {
-                let stack: StackInfo = unsafe { *v.as_ptr().cast() };
+    let (tx, mut rx) = mpsc::channel(QUEUED_SAMPLES);
+    let lost = EventSource::new(&mut bpf, "events")?.spawn(tx)?;
+    let mut counts = CountMaps::new(&mut bpf)?;
+    let mut unwinders = Unwinders {
+        dwarf: match opts.unwind {
+            UnwindMode::Dwarf => Some(DwarfUnwinder::new(&mut bpf)?),
+            UnwindMode::Fp | UnwindMode::Snapshot => None,
+        },
+        python: match opts.python {
+            true => Some(PythonUnwinder::new(&mut bpf)?),
+            false => None,
+        },
+        processes: ProcessTracker::default(),
+    };
+    let mut snapshots = SnapshotUnwinder::new();
+    if let (Some(unwinder), Some(pid)) = (unwinders.dwarf.as_mut(), opts.pid) {
+        unwinder.prepare(pid)?;
+    }
+    if let (Some(python), Some(pid)) = (unwinders.python.as_mut(), opts.pid) {
+        python.prepare(pid)?;
+    }
+    let mut stack_maps = StackMaps::new(&mut bpf, !opts.aggregate)?;
 
-                let record = deconstruct_stack(&stack, &stack_traces, &mut translator).unwrap();
+    if opts.duration > 0 {
+        info!("Profiling for {}s, Ctrl-C to stop early...", opts.duration);
+    } else {
+        info!("Waiting for Ctrl-C...");
+    }
+    let deadline = tokio::time::sleep(Duration::from_secs(opts.duration as u64));
+    tokio::pin!(deadline);
 
-                println!("{}", record);
+    let kernel = KernelImages {
+        vmlinux: opts.vmlinux.clone(),
+        system_map: opts.system_map.clone(),
+    };
+    let mut translator = Translator::new("/".into(), kernel, opts.symbols.symbolizer(false)?);
+    if opts.no_symbolize {
+        translator = translator.without_symbols();
+    }
+    let mut formater = opts
+        .out
+        .output
+        .as_ref()
+        .map(|o| opts.formater(o))
+        .transpose()?;
+    let mut drain = tokio::time::interval(Duration::from_secs(
+        opts.aggregate_interval.unwrap_or(1).max(1),
+    ));
+    let mut exits = tokio::time::interval(EXITS_INTERVAL);
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let sample = tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = &mut deadline, if opts.duration > 0 => break,
+            _ = drain.tick(), if opts.aggregate_interval.is_some() => {
+                drain_counts(&mut counts, &mut stack_maps, &mut unwinders, &mut translator, &mut formater, &opts).await?;
+                continue;
             }
-            _ => {
-                info!("Nothing");
-                tokio::time::sleep(Duration::from_millis(1000)).await;
+            _ = exits.tick() => {
+                unwinders.forget_exited(&mut translator, &mut snapshots);
+                unwinders.rescan();
+                continue;
             }
+            sample = rx.recv() => match sample {
+                Some(sample) => sample,
+                None => break,
+            },
+        };
+
+        unwinders.prepare(sample.stack.tgid);
+        // stack ids are evicted or overwritten under load
+        match deconstruct_sample(&sample, &mut stack_maps, &mut snapshots, &mut translator) {
+            Ok(record) => emit(&mut formater, &record),
+            Err(e) => debug!("drop sample of {}: {}", sample.stack.pid, e),
         }
     }
 
+    // samples already published before the session ended
+    while let Ok(sample) = rx.try_recv() {
+        if let Ok(record) =
+            deconstruct_sample(&sample, &mut stack_maps, &mut snapshots, &mut translator)
+        {
+            emit(&mut formater, &record);
+        }
+    }
+
+    if opts.aggregate {
+        // both maps, the one counted in until now last
+        for _ in 0..2 {
+            drain_counts(
+                &mut counts,
+                &mut stack_maps,
+                &mut unwinders,
+                &mut translator,
+                &mut formater,
+                &opts,
+            )
+            .await?;
+        }
+    }
+
+    let dropped = dropped_samples(&bpf)? + lost.load(Ordering::Relaxed);
+    if dropped > 0 {
+        warn!(
+            "{} samples dropped on a full event buffer or queue",
+            dropped
+        );
+    }
+
+    if let (Some(mut formater), Some(output)) = (formater, &opts.out.output) {
+        formater.write_file(output)?;
+        info!("profile written to {:?}", output);
+    }
+
     info!("Exiting... ");
-    handle.await?;
     Ok(())
 }
 
+/// Symbolizes the raw profile of `record --no-symbolize` into the output
+/// format.
+fn symbolize(opts: SymbolizeOptions) -> Result<(), anyhow::Error> {
+    let output = opts
+        .out
+        .output
+        .as_ref()
+        .ok_or_else(|| anyhow!("symbolize writes to --output"))?;
+    let input = File::open(&opts.input).map_err(|e| anyhow!("open {:?}: {}", opts.input, e))?;
+    let mut reader = RawReader::new(BufReader::new(input))?;
+    let mode = ProfileMode::from_str(&reader.mode, false).map_err(|e| anyhow!(e))?;
+    // kernel frames were symbolized when recorded
+    let mut translator = Translator::new(
+        "/".into(),
+        KernelImages::default(),
+        opts.symbols.symbolizer(true)?,
+    );
+    let mut formater = opts.out.formater(mode, output);
+    while let Some((mut record, mappings)) = reader.next_record()? {
+        record.frames = translator.translate_raw(record.frames, mappings);
+        formater.add(&record);
+    }
+
+    formater.write_file(output)?;
+    info!("profile written to {:?}", output);
+    Ok(())
+}
+
+fn emit(formater: &mut Option<Box<dyn Formater>>, record: &PerfRecord) {
+    match formater.as_mut() {
+        Some(formater) => formater.add(record),
+        None => println!("{}", record),
+    }
+}
+
+const STACK_INFO_SIZE: usize = std::mem::size_of::<StackInfo>();
+/// How often processes are checked for exits, to drop their unwind state,
+/// and for libraries they mapped since they were prepared.
+const EXITS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long samples counting in a map when it is swapped out take to finish.
+const COUNTS_SETTLE: Duration = Duration::from_millis(10);
+
+type Counts = HashMap<MapData, [u8; STACK_INFO_SIZE], u64>;
+
+/// The two maps the eBPF program counts stacks in, one at a time.
+struct CountMaps {
+    maps: [Counts; 2],
+    slot: Array<MapData, u32>,
+    active: usize,
+}
+
+impl CountMaps {
+    fn new(bpf: &mut Ebpf) -> Result<Self, Error> {
+        let mut map = |name| {
+            bpf.take_map(name)
+                .ok_or_else(|| anyhow!("map {} not found", name))
+        };
+        Ok(Self {
+            maps: [
+                HashMap::try_from(map("counts")?)?,
+                HashMap::try_from(map("counts_alt")?)?,
+            ],
+            slot: Array::try_from(map("counts_slot")?)?,
+            active: 0,
+        })
+    }
+
+    /// Counts in the other map from now on. Returns the map counted in until
+    /// now and the one counted in from now on.
+    fn swap(&mut self) -> Result<(&mut Counts, &Counts), Error> {
+        self.active ^= 1;
+        self.slot.set(0, self.active as u32, 0)?;
+        let [first, second] = &mut self.maps;
+        Ok(match self.active {
+            0 => (second, first),
+            _ => (first, second),
+        })
+    }
+}
+
+/// Takes the in-kernel stack counts, every unique stack becomes one record
+/// weighted by its count. Stacks are counted in the other map meanwhile, so
+/// no increment is lost.
+async fn drain_counts(
+    counts: &mut CountMaps,
+    stack_maps: &mut StackMaps,
+    unwinders: &mut Unwinders,
+    translator: &mut Translator,
+    formater: &mut Option<Box<dyn Formater>>,
+    opts: &ProfileOptions,
+) -> Result<(), Error> {
+    let (counts, active) = counts.swap()?;
+    tokio::time::sleep(COUNTS_SETTLE).await;
+    let keys = counts.keys().collect::<Result<Vec<_>, _>>()?;
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    debug!("draining {} stacks", keys.len());
+
+    let mut drained = StackKeys::default();
+    for key in keys {
+        let count = match counts.get(&key, 0) {
+            Ok(count) => count,
+            Err(_) => continue,
+        };
+        counts.remove(&key)?;
+
+        let stack: StackInfo = unsafe { std::ptr::read_unaligned(key.as_ptr().cast()) };
+        // stacks counted from now on get the unwind tables
+        unwinders.prepare(stack.tgid);
+        drained.add(&stack);
+        match deconstruct_stack(&stack, stack_maps, translator) {
+            Ok(mut record) => {
+                record.count = count;
+                record.cycle = opts.sample_period();
+                record.ts = ts;
+                emit(formater, &record);
+            }
+            Err(e) => debug!("drop stack of {}: {}", stack.pid, e),
+        }
+    }
+
+    // the stacks of stacks counted since stay for the next drain
+    let mut counted = StackKeys::default();
+    for key in active.keys() {
+        let stack: StackInfo = unsafe { std::ptr::read_unaligned(key?.as_ptr().cast()) };
+        counted.add(&stack);
+    }
+    stack_maps.release(&drained, &counted);
+    Ok(())
+}
+
+/// Keys in the stack maps of counted stacks.
+#[derive(Default)]
+struct StackKeys {
+    traces: HashSet<u32>,
+    dwarf: HashSet<u64>,
+    python: HashSet<u64>,
+}
+
+impl StackKeys {
+    fn add(&mut self, stack: &StackInfo) {
+        let ids = [stack.user_stack_id, stack.kernel_stack_id];
+        self.traces
+            .extend(ids.into_iter().flatten().map(|id| id as u32));
+        if stack.user_stack_hash != 0 {
+            self.dwarf.insert(stack.user_stack_hash);
+        }
+        if stack.python_stack_hash != 0 {
+            self.python.insert(stack.python_stack_hash);
+        }
+    }
+}
+
+/// Maps the stacks of the samples are looked up in.
+struct StackMaps {
+    traces: StackTraceMap<MapData>,
+    dwarf: HashMap<MapData, u64, DwarfStack>,
+    python: HashMap<MapData, u64, PyStack>,
+    /// remove the dwarf and python stacks of a published sample once read,
+    /// counted ones are shared by every sample of the stack
+    consume: bool,
+}
+
+impl StackMaps {
+    fn new(bpf: &mut Ebpf, consume: bool) -> Result<Self, Error> {
+        let mut map = |name| {
+            bpf.take_map(name)
+                .ok_or_else(|| anyhow!("map {} not found", name))
+        };
+        Ok(Self {
+            traces: StackTraceMap::try_from(map("stack_traces")?)?,
+            dwarf: HashMap::try_from(map("dwarf_stacks")?)?,
+            python: HashMap::try_from(map("python_stacks")?)?,
+            consume,
+        })
+    }
+
+    fn dwarf_stack(&mut self, key: u64) -> Result<DwarfStack, MapError> {
+        let stack = self.dwarf.get(&key, 0)?;
+        if self.consume {
+            self.dwarf.remove(&key)?;
+        }
+        Ok(stack)
+    }
+
+    fn python_stack(&mut self, key: u64) -> Result<PyStack, MapError> {
+        let stack = self.python.get(&key, 0)?;
+        if self.consume {
+            self.python.remove(&key)?;
+        }
+        Ok(stack)
+    }
+
+    /// Removes the stacks of drained counts, but those counted again.
+    fn release(&mut self, drained: &StackKeys, counted: &StackKeys) {
+        for id in drained.traces.difference(&counted.traces) {
+            let _ = self.traces.remove(id);
+        }
+        for key in drained.dwarf.difference(&counted.dwarf) {
+            let _ = self.dwarf.remove(key);
+        }
+        for key in drained.python.difference(&counted.python) {
+            let _ = self.python.remove(key);
+        }
+    }
+}
+
+/// Per-process unwind state, dropped once the process exits.
+struct Unwinders {
+    dwarf: Option<DwarfUnwinder>,
+    python: Option<PythonUnwinder>,
+    processes: ProcessTracker,
+}
+
+impl Unwinders {
+    fn prepare(&mut self, tgid: u32) {
+        self.processes.track(tgid);
+        if let Some(unwinder) = self.dwarf.as_mut() {
+            if let Err(e) = unwinder.prepare(tgid) {
+                debug!("unwind tables of {}: {}", tgid, e);
+            }
+        }
+        if let Some(python) = self.python.as_mut() {
+            if let Err(e) = python.prepare(tgid) {
+                debug!("python interpreter of {}: {}", tgid, e);
+            }
+        }
+    }
+
+    /// Loads the unwind tables of libraries mapped since processes were
+    /// prepared.
+    fn rescan(&self) {
+        if let Some(unwinder) = self.dwarf.as_ref() {
+            if let Err(e) = unwinder.rescan() {
+                debug!("rescan unwind tables: {}", e);
+            }
+        }
+    }
+
+    /// Drops the state of exited processes, with their symbols in `translator`
+    /// and their maps in `snapshots`.
+    fn forget_exited(&mut self, translator: &mut Translator, snapshots: &mut SnapshotUnwinder) {
+        for tgid in self.processes.exited() {
+            translator.forget(tgid);
+            snapshots.forget(tgid);
+            if let Some(unwinder) = self.dwarf.as_mut() {
+                if let Err(e) = unwinder.forget(tgid) {
+                    debug!("unwind tables of {}: {}", tgid, e);
+                }
+            }
+            if let Some(python) = self.python.as_mut() {
+                if let Err(e) = python.forget(tgid) {
+                    debug!("python interpreter of {}: {}", tgid, e);
+                }
+            }
+        }
+    }
+}
+
+/// Like `deconstruct_stack`, with the user stack unwound from the snapshot
+/// when the sample has one.
+fn deconstruct_sample(
+    sample: &Sample,
+    stack_maps: &mut StackMaps,
+    snapshots: &mut SnapshotUnwinder,
+    translator: &mut Translator,
+) -> Result<PerfRecord, Error> {
+    let Some(snapshot) = &sample.snapshot else {
+        return deconstruct_stack(&sample.stack, stack_maps, translator);
+    };
+    let stack = StackInfo {
+        user_stack_id: None,
+        python_stack_hash: 0,
+        ..sample.stack
+    };
+    let mut record = deconstruct_stack(&stack, stack_maps, translator)?;
+
+    let (ips, truncated) = snapshots.unwind(stack.tgid, snapshot);
+    let uframes = translator.translate_usyms(stack.tgid, ips)?;
+    record.frames.extend(translate_python(
+        &sample.stack,
+        stack_maps,
+        translator,
+        uframes,
+    ));
+    if truncated {
+        // outermost user frame: the unwind ran past the copied stack
+        record.frames.push(PerfStackFrame::new(
+            0,
+            "[truncated]".into(),
+            PathBuf::new(),
+            0,
+        ));
+    }
+    Ok(record)
+}
+
 fn deconstruct_stack(
     stack: &StackInfo,
-    stack_traces: &StackTraceMap<&MapData>,
+    stack_maps: &mut StackMaps,
     translator: &mut Translator,
 ) -> Result<PerfRecord, Error> {
     let mut kframes = None;
     let mut uframes = None;
 
     if let Some(id) = stack.kernel_stack_id {
-        kframes = stack_traces
+        kframes = stack_maps
+            .traces
             .get(&(id as u32), 0)
             .map(|trace| translator.translate_ktrace(&trace).ok())?;
     }
 
-    if let Some(id) = stack.user_stack_id {
-        uframes = stack_traces
+    if stack.user_stack_hash != 0 {
+        let dwarf = stack_maps.dwarf_stack(stack.user_stack_hash)?;
+        uframes = translator
+            .translate_usyms(stack.tgid, dwarf.ips().to_vec())
+            .ok();
+    } else if let Some(id) = stack.user_stack_id {
+        uframes = stack_maps
+            .traces
             .get(&(id as u32), 0)
-            .map(|trace| translator.translate_utrace(stack.pid, &trace).ok())?;
+            .map(|trace| translator.translate_utrace(stack.tgid, &trace).ok())?;
+    }
+    if stack.python_stack_hash != 0 {
+        let native = uframes.unwrap_or_default();
+        uframes = Some(translate_python(stack, stack_maps, translator, native));
     }
 
     Ok(PerfRecord::from(stack, kframes, uframes))
 }
+
+/// Native user frames with the Python frames of the sample in place of the
+/// interpreter loop, unchanged when the sample has none.
+fn translate_python(
+    stack: &StackInfo,
+    stack_maps: &mut StackMaps,
+    translator: &mut Translator,
+    native: Vec<PerfStackFrame>,
+) -> Vec<PerfStackFrame> {
+    if stack.python_stack_hash == 0 {
+        return native;
+    }
+    // evicted from the map, the native frames are still right
+    let Ok(python) = stack_maps.python_stack(stack.python_stack_hash) else {
+        return native;
+    };
+    translator.translate_python(stack.tgid, &python, native)
+}
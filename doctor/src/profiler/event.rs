@@ -0,0 +1,178 @@
+use std::{
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Error};
+use aya::{
+    maps::{AsyncPerfEventArray, Map, MapData, PerCpuArray, RingBuf},
+    util::online_cpus,
+    Ebpf,
+};
+use bytes::BytesMut;
+use doctor_common::{StackInfo, StackSnapshot, SNAPSHOT_HEADER_SIZE};
+use log::{debug, warn};
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::mpsc::{error::TrySendError, Sender},
+};
+
+use super::unwind::snapshot::UserSnapshot;
+
+const PERF_BUFFERS: usize = 16;
+const PERF_PAGES: usize = 256;
+/// Samples queued for the consumer, which falls behind while debug info
+/// loads: past them samples are dropped as on a full kernel buffer.
+pub const QUEUED_SAMPLES: usize = 16 * 1024;
+
+pub struct Sample {
+    pub stack: StackInfo,
+    /// taken with `--unwind snapshot`, the user stack is unwound from it
+    pub snapshot: Option<UserSnapshot>,
+}
+
+/// Where the eBPF program publishes samples: a ring buffer when the kernel
+/// supports it, per-cpu perf buffers otherwise.
+pub enum EventSource {
+    RingBuf(RingBuf<MapData>),
+    PerfEventArray(AsyncPerfEventArray<MapData>),
+}
+
+impl EventSource {
+    pub fn new(bpf: &mut Ebpf, name: &str) -> Result<Self, Error> {
+        match bpf.take_map(name) {
+            Some(map @ Map::RingBuf(_)) => Ok(EventSource::RingBuf(RingBuf::try_from(map)?)),
+            Some(map @ Map::PerfEventArray(_)) => Ok(EventSource::PerfEventArray(
+                AsyncPerfEventArray::try_from(map)?,
+            )),
+            Some(_) => Err(anyhow!("unexpected map type of {}", name)),
+            None => Err(anyhow!("map {} not found", name)),
+        }
+    }
+
+    /// Spawns the consumers, every sample is sent to `tx` as soon as the
+    /// kernel publishes it. Returns the count of samples the perf buffers
+    /// lost or `tx` had no room for, the ring buffer counts its own in the
+    /// `dropped` map.
+    pub fn spawn(self, tx: Sender<Sample>) -> Result<Arc<AtomicU64>, Error> {
+        let lost = Arc::new(AtomicU64::new(0));
+        match self {
+            EventSource::RingBuf(ring) => {
+                // SAFETY: the ring buffer owns its map fd, which stays open until
+                // the AsyncFd and the ring buffer inside are dropped
+                let mut fd = unsafe { AsyncFd::register_with_interest(ring, Interest::READABLE) }
+                    .map_err(|e| anyhow!("register ring buffer fd: {}", e))?;
+                let lost = lost.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let mut guard = match fd.readable_mut().await {
+                            Ok(guard) => guard,
+                            Err(e) => {
+                                warn!("ring buffer poll failed: {}", e);
+                                return;
+                            }
+                        };
+                        let ring = guard.get_inner_mut();
+                        while let Some(item) = ring.next() {
+                            if let Some(sample) = parse(&item) {
+                                if !send(&tx, sample, &lost) {
+                                    return;
+                                }
+                            }
+                        }
+                        guard.clear_ready();
+                    }
+                });
+            }
+            EventSource::PerfEventArray(mut perf) => {
+                for cpu in online_cpus().map_err(|e| anyhow!("get online cpus failed {e:?}"))? {
+                    let mut buf = perf.open(cpu, Some(PERF_PAGES))?;
+                    let (tx, lost) = (tx.clone(), lost.clone());
+                    tokio::spawn(async move {
+                        let mut buffers = (0..PERF_BUFFERS)
+                            .map(|_| BytesMut::with_capacity(size_of::<StackInfo>()))
+                            .collect::<Vec<_>>();
+                        loop {
+                            let events = match buf.read_events(&mut buffers).await {
+                                Ok(events) => events,
+                                Err(e) => {
+                                    warn!("perf buffer of cpu {} read failed: {}", cpu, e);
+                                    return;
+                                }
+                            };
+                            if events.lost > 0 {
+                                debug!("cpu {} lost {} samples", cpu, events.lost);
+                                lost.fetch_add(events.lost as u64, Ordering::Relaxed);
+                            }
+                            for data in buffers.iter().take(events.read) {
+                                if let Some(sample) = parse(data) {
+                                    if !send(&tx, sample, &lost) {
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                    });
+                }
+            }
+        }
+        Ok(lost)
+    }
+}
+
+/// Samples the eBPF program could not publish on a full ring buffer, summed
+/// over the cpus.
+pub fn dropped_samples(bpf: &Ebpf) -> Result<u64, Error> {
+    // only the ring buffer program has it
+    let Some(map) = bpf.map("dropped") else {
+        return Ok(0);
+    };
+    let dropped = PerCpuArray::<_, u64>::try_from(map)?.get(&0, 0)?;
+    Ok(dropped.iter().sum())
+}
+
+/// Sends `sample` unless `tx` is full, counted in `lost` then. False once
+/// the consumer is gone.
+fn send(tx: &Sender<Sample>, sample: Sample, lost: &AtomicU64) -> bool {
+    match tx.try_send(sample) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            lost.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+fn parse(data: &[u8]) -> Option<Sample> {
+    if data.len() < size_of::<StackInfo>() {
+        debug!("short sample of {} bytes", data.len());
+        return None;
+    }
+    let stack = unsafe { std::ptr::read_unaligned(data.as_ptr().cast()) };
+    if data.len() < SNAPSHOT_HEADER_SIZE {
+        return Some(Sample {
+            stack,
+            snapshot: None,
+        });
+    }
+
+    let field = |offset: usize| {
+        let bytes = &data[offset..offset + 8];
+        u64::from_ne_bytes(bytes.try_into().unwrap())
+    };
+    let size = field(std::mem::offset_of!(StackSnapshot, size)) as usize;
+    let stack_bytes = &data[SNAPSHOT_HEADER_SIZE..];
+    Some(Sample {
+        stack,
+        snapshot: Some(UserSnapshot {
+            ip: field(std::mem::offset_of!(StackSnapshot, ip)),
+            sp: field(std::mem::offset_of!(StackSnapshot, sp)),
+            bp: field(std::mem::offset_of!(StackSnapshot, bp)),
+            stack: stack_bytes[..size.min(stack_bytes.len())].to_vec(),
+        }),
+    })
+}
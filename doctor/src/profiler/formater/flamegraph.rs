@@ -0,0 +1,388 @@
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::Error;
+
+use super::{Formater, Unit};
+use crate::profiler::{perf_record::PerfRecord, symbolizer::kallsyms::KERNEL_DSO};
+
+const IMAGE_WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const FONT_SIZE: f64 = 12.0;
+const FONT_WIDTH: f64 = 0.59;
+const X_PAD: f64 = 10.0;
+const TOP_PAD: f64 = 44.0;
+const BOTTOM_PAD: f64 = 34.0;
+// frames narrower than this many pixels are not drawn
+const MIN_WIDTH: f64 = 0.1;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FlamegraphOptions {
+    /// Icicle graph: root at the top, stacks growing downwards.
+    pub inverted: bool,
+    pub kernel_first: bool,
+    /// Add the thread state as a `[state]` frame on top of the leaf.
+    pub with_state: bool,
+    pub unit: Unit,
+}
+
+#[derive(Default)]
+struct Node {
+    dso: String,
+    value: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        self.children
+            .values()
+            .map(|c| c.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Renders aggregated stacks into a self-contained interactive SVG, colored
+/// by the DSO of each frame.
+pub struct FlamegraphFormater {
+    options: FlamegraphOptions,
+    root: Node,
+}
+
+struct Frame<'a> {
+    name: &'a str,
+    dso: &'a str,
+    value: u64,
+    offset: u64,
+    depth: usize,
+}
+
+impl FlamegraphFormater {
+    pub fn new(options: FlamegraphOptions) -> Self {
+        Self {
+            options,
+            root: Node::default(),
+        }
+    }
+
+    fn frames(&self) -> Vec<Frame<'_>> {
+        let mut frames = Vec::new();
+        let mut pending = vec![("all", &self.root, 0, 0)];
+        while let Some((name, node, offset, depth)) = pending.pop() {
+            frames.push(Frame {
+                name,
+                dso: &node.dso,
+                value: node.value,
+                offset,
+                depth,
+            });
+            let mut child_offset = offset;
+            for (child_name, child) in &node.children {
+                pending.push((child_name, child, child_offset, depth + 1));
+                child_offset += child.value;
+            }
+        }
+        frames
+    }
+
+    fn render(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        let depth = self.root.depth() + 1;
+        let height = depth as f64 * FRAME_HEIGHT + TOP_PAD + BOTTOM_PAD;
+        let total = self.root.value.max(1) as f64;
+        let width = IMAGE_WIDTH - 2.0 * X_PAD;
+        let title = if self.options.inverted {
+            "Icicle Graph"
+        } else {
+            "Flame Graph"
+        };
+        let unit = self.options.unit.name();
+
+        writeln!(
+            writer,
+            r#"<?xml version="1.0" standalone="no"?>
+<svg version="1.1" width="{IMAGE_WIDTH}" height="{height}" onload="init(evt)" viewBox="0 0 {IMAGE_WIDTH} {height}" xmlns="http://www.w3.org/2000/svg" data-inverted="{inverted}" data-width="{width}" data-xpad="{X_PAD}" data-fontw="{fw}">
+<style type="text/css">
+text {{ font-family: monospace; font-size: {FONT_SIZE}px; fill: rgb(0,0,0); }}
+#title {{ text-anchor: middle; font-size: 17px; }}
+#search, #unzoom {{ cursor: pointer; }}
+#frames > g {{ cursor: pointer; }}
+#frames > g:hover > rect {{ stroke: black; stroke-width: 0.5; }}
+.hide {{ display: none; }}
+.parent {{ opacity: 0.5; }}
+</style>
+<script type="text/ecmascript"><![CDATA[{SCRIPT}]]></script>
+<rect x="0" y="0" width="100%" height="100%" fill="rgb(248,248,248)"/>
+<text id="title" x="{cx}" y="24">{title}</text>
+<text id="unzoom" class="hide" x="{X_PAD}" y="24">Reset Zoom</text>
+<text id="search" x="{sx}" y="24">Search</text>
+<text id="matched" x="{sx}" y="{by}"> </text>
+<text id="details" x="{X_PAD}" y="{by}"> </text>
+<g id="frames">"#,
+            inverted = self.options.inverted as u8,
+            fw = FONT_SIZE * FONT_WIDTH,
+            cx = IMAGE_WIDTH / 2.0,
+            sx = IMAGE_WIDTH - X_PAD - 100.0,
+            by = height - 12.0,
+        )?;
+
+        for frame in self.frames() {
+            let w = frame.value as f64 / total * width;
+            if w < MIN_WIDTH {
+                continue;
+            }
+            let x = X_PAD + frame.offset as f64 / total * width;
+            let y = if self.options.inverted {
+                TOP_PAD + frame.depth as f64 * FRAME_HEIGHT
+            } else {
+                height - BOTTOM_PAD - (frame.depth + 1) as f64 * FRAME_HEIGHT
+            };
+            let name = escape(frame.name);
+            let color = color(frame.dso);
+            writeln!(
+                writer,
+                r#"<g data-x="{fx}" data-w="{fw}" data-d="{d}" data-n="{name}" data-c="{color}"><title>{name} ({dso}, {v} {unit}, {p:.2}%)</title><rect x="{x:.2}" y="{y}" width="{w:.2}" height="{h}" fill="{color}" rx="2" ry="2"/><text x="{tx:.2}" y="{ty}">{label}</text></g>"#,
+                fx = frame.offset as f64 / total,
+                fw = frame.value as f64 / total,
+                d = frame.depth,
+                dso = escape(frame.dso),
+                v = frame.value,
+                p = frame.value as f64 / total * 100.0,
+                h = FRAME_HEIGHT - 1.0,
+                tx = x + 3.0,
+                ty = y + FRAME_HEIGHT - 4.5,
+                label = escape(&fit(frame.name, w)),
+            )?;
+        }
+        writeln!(writer, "</g>\n</svg>")?;
+        Ok(())
+    }
+}
+
+impl Formater for FlamegraphFormater {
+    fn add(&mut self, record: &PerfRecord) {
+        let weight = self.options.unit.weight(record);
+        let mut node = &mut self.root;
+        node.value += weight;
+        node = node.children.entry(record.cmdline.clone()).or_default();
+        node.value += weight;
+        for frame in record.root_first(self.options.kernel_first) {
+            node = node
+                .children
+                .entry(frame.folded_name().into_owned())
+                .or_default();
+            if node.dso.is_empty() {
+                node.dso = frame.elf.to_string_lossy().to_string();
+            }
+            node.value += weight;
+        }
+        if self.options.with_state {
+            node = node
+                .children
+                .entry(format!("[{}]", record.state.name()))
+                .or_default();
+            node.value += weight;
+        }
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        self.render(writer)
+    }
+}
+
+fn fit(name: &str, width: f64) -> String {
+    let chars = ((width - 6.0) / (FONT_SIZE * FONT_WIDTH)) as usize;
+    if chars < 3 {
+        String::new()
+    } else if name.chars().count() <= chars {
+        name.to_owned()
+    } else {
+        let mut s = name.chars().take(chars - 2).collect::<String>();
+        s.push_str("..");
+        s
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// one stable hue per DSO, so frames of the same binary share a color
+fn color(dso: &str) -> String {
+    if dso.is_empty() {
+        return "rgb(200,200,200)".into();
+    }
+    if dso == KERNEL_DSO {
+        return "hsl(25,85%,60%)".into();
+    }
+    let hash = dso.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!(
+        "hsl({},{}%,{}%)",
+        hash % 360,
+        55 + (hash >> 16) % 25,
+        55 + (hash >> 32) % 15
+    )
+}
+
+const SCRIPT: &str = r#"
+var svg, frames, details, matched, unzoombtn, width, xpad, fontw, searching;
+function init(evt) {
+    svg = document.documentElement;
+    frames = document.getElementById("frames");
+    details = document.getElementById("details").firstChild;
+    matched = document.getElementById("matched");
+    unzoombtn = document.getElementById("unzoom");
+    width = +svg.getAttribute("data-width");
+    xpad = +svg.getAttribute("data-xpad");
+    fontw = +svg.getAttribute("data-fontw");
+}
+function find_group(node) {
+    while (node && node.parentElement) {
+        if (node.parentElement.id == "frames") return node;
+        node = node.parentElement;
+    }
+    return null;
+}
+window.addEventListener("click", function(e) {
+    var g = find_group(e.target);
+    if (g) {
+        zoom(g);
+    } else if (e.target.id == "unzoom") {
+        unzoom();
+    } else if (e.target.id == "search") {
+        search_prompt();
+    }
+});
+window.addEventListener("mouseover", function(e) {
+    var g = find_group(e.target);
+    if (g) details.nodeValue = g.querySelector("title").textContent;
+});
+window.addEventListener("mouseout", function(e) {
+    if (find_group(e.target)) details.nodeValue = " ";
+});
+function fit(name, w) {
+    var chars = Math.floor((w - 6) / fontw);
+    if (chars < 3) return "";
+    if (name.length <= chars) return name;
+    return name.substring(0, chars - 2) + "..";
+}
+function place(g, fx, fw) {
+    var rect = g.querySelector("rect"), text = g.querySelector("text");
+    var x = xpad + fx * width, w = fw * width;
+    rect.setAttribute("x", x);
+    rect.setAttribute("width", w);
+    text.setAttribute("x", x + 3);
+    text.textContent = fit(g.getAttribute("data-n"), w);
+}
+function zoom(node) {
+    var x = +node.getAttribute("data-x"), w = +node.getAttribute("data-w");
+    var d = +node.getAttribute("data-d"), eps = 1e-9;
+    unzoombtn.classList.remove("hide");
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i];
+        var gx = +g.getAttribute("data-x"), gw = +g.getAttribute("data-w");
+        var gd = +g.getAttribute("data-d");
+        g.classList.remove("hide", "parent");
+        if (gd < d && gx <= x + eps && gx + gw >= x + w - eps) {
+            g.classList.add("parent");
+            place(g, 0, 1);
+        } else if (gd >= d && gx >= x - eps && gx + gw <= x + w + eps) {
+            place(g, (gx - x) / w, gw / w);
+        } else {
+            g.classList.add("hide");
+        }
+    }
+}
+function unzoom() {
+    unzoombtn.classList.add("hide");
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i];
+        g.classList.remove("hide", "parent");
+        place(g, +g.getAttribute("data-x"), +g.getAttribute("data-w"));
+    }
+}
+function search_prompt() {
+    if (searching) {
+        search(null);
+        return;
+    }
+    var term = prompt("Search frames (regexp):", "");
+    if (term) search(term);
+}
+function search(term) {
+    var re = term ? new RegExp(term) : null, spans = [];
+    for (var i = 0; i < frames.children.length; i++) {
+        var g = frames.children[i], rect = g.querySelector("rect");
+        if (re && re.test(g.getAttribute("data-n"))) {
+            rect.setAttribute("fill", "rgb(230,0,230)");
+            spans.push([+g.getAttribute("data-x"), +g.getAttribute("data-w")]);
+        } else {
+            rect.setAttribute("fill", g.getAttribute("data-c"));
+        }
+    }
+    searching = !!re;
+    document.getElementById("search").textContent = re ? "Reset Search" : "Search";
+    if (!re) {
+        matched.textContent = " ";
+        return;
+    }
+    // nested matches cover the same samples, count each span once
+    spans.sort(function(a, b) { return a[0] - b[0]; });
+    var total = 0, end = 0;
+    spans.forEach(function(s) {
+        var start = Math.max(s[0], end);
+        if (s[0] + s[1] > start) total += s[0] + s[1] - start;
+        end = Math.max(end, s[0] + s[1]);
+    });
+    matched.textContent = "Matched: " + (total * 100).toFixed(1) + "%";
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::perf_record::{PerfStackFrame, ThreadState};
+
+    fn record(syms: &[&str]) -> PerfRecord {
+        PerfRecord {
+            pid: 1,
+            tgid: 1,
+            cpu_id: 0,
+            cmdline: "app".into(),
+            ts: 0,
+            cycle: 1,
+            count: 1,
+            state: ThreadState::Running,
+            frames: syms
+                .iter()
+                .map(|s| PerfStackFrame::new(0, s.to_string(), "/bin/app".into(), 0))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_flamegraph_svg() {
+        let mut formater = FlamegraphFormater::new(FlamegraphOptions {
+            inverted: true,
+            ..Default::default()
+        });
+        formater.add(&record(&["foo<T>", "main"]));
+        formater.add(&record(&["bar", "main"]));
+        formater.add(&record(&["bar", "main"]));
+
+        let mut out = Vec::new();
+        formater.write(&mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+
+        assert!(svg.contains(r#"data-inverted="1""#));
+        assert!(svg.contains("<title>main (/bin/app, 3 samples, 100.00%)</title>"));
+        assert!(svg.contains("<title>bar (/bin/app, 2 samples, 66.67%)</title>"));
+        assert!(svg.contains(r#"data-n="foo&lt;T&gt;""#));
+        // all, app, main, and the two leaves
+        assert_eq!(svg.matches("<g data-x").count(), 5);
+    }
+}
@@ -0,0 +1,175 @@
+use std::{collections::HashMap, io::Write};
+
+use anyhow::Error;
+
+use super::{Formater, Unit};
+use crate::profiler::perf_record::PerfRecord;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FoldedOptions {
+    /// Put kernel frames between the root and the user frames instead of on
+    /// top of them.
+    pub kernel_first: bool,
+    /// Root frame becomes `comm-pid`.
+    pub with_pid: bool,
+    /// Root frame becomes `comm-pid/tid`, implies `with_pid`.
+    pub with_tid: bool,
+    /// Add the thread state as a `[state]` frame on top of the leaf.
+    pub with_state: bool,
+    pub unit: Unit,
+}
+
+/// Collapsed stacks, one `comm;frame1;frame2;... count` line per unique
+/// stack, root first, as consumed by flamegraph.pl and inferno.
+pub struct FoldedFormater {
+    options: FoldedOptions,
+    stacks: HashMap<String, u64>,
+}
+
+impl FoldedFormater {
+    pub fn new(options: FoldedOptions) -> Self {
+        Self {
+            options,
+            stacks: HashMap::new(),
+        }
+    }
+
+    pub fn fold(&self, record: &PerfRecord) -> String {
+        let root = if self.options.with_tid {
+            format!("{}-{}/{}", record.cmdline, record.tgid, record.pid)
+        } else if self.options.with_pid {
+            format!("{}-{}", record.cmdline, record.tgid)
+        } else {
+            record.cmdline.clone()
+        };
+
+        let mut folded = escape(&root);
+        for frame in record.root_first(self.options.kernel_first) {
+            folded.push(';');
+            folded.push_str(&escape(&frame.folded_name()));
+        }
+        if self.options.with_state {
+            folded.push_str(&format!(";[{}]", record.state.name()));
+        }
+        folded
+    }
+
+    /// Unique stacks with their merged counts, sorted by stack.
+    pub fn stacks(&self) -> Vec<(&str, u64)> {
+        let mut stacks = self
+            .stacks
+            .iter()
+            .map(|(s, c)| (s.as_str(), *c))
+            .collect::<Vec<_>>();
+        stacks.sort_unstable();
+        stacks
+    }
+}
+
+// `;` separates frames and a line holds exactly one stack
+fn escape(name: &str) -> String {
+    name.replace(';', ":").replace('\n', " ")
+}
+
+impl Formater for FoldedFormater {
+    fn add(&mut self, record: &PerfRecord) {
+        *self.stacks.entry(self.fold(record)).or_default() += self.options.unit.weight(record);
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        for (stack, count) in self.stacks() {
+            writeln!(writer, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::{
+        perf_record::{FrameKind, PerfStackFrame, ThreadState},
+        symbolizer::kallsyms::KERNEL_DSO,
+    };
+
+    fn record() -> PerfRecord {
+        let kernel = |sym: &str| PerfStackFrame {
+            kind: FrameKind::Kernel,
+            ..PerfStackFrame::new(0, sym.into(), KERNEL_DSO.into(), 0)
+        };
+        let user = |sym: &str| PerfStackFrame::new(0, sym.into(), "/bin/app".into(), 0);
+        PerfRecord {
+            pid: 11,
+            tgid: 10,
+            cpu_id: 0,
+            cmdline: "app".into(),
+            ts: 0,
+            cycle: 1,
+            count: 1,
+            state: ThreadState::Running,
+            frames: vec![
+                kernel("do_syscall_64_[k]"),
+                kernel("entry_SYSCALL_64_[k]"),
+                user("write"),
+                user("main"),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut formater = FoldedFormater::new(FoldedOptions::default());
+        formater.add(&record());
+        formater.add(&record());
+        let mut out = Vec::new();
+        formater.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "app;main;write;entry_SYSCALL_64_[k];do_syscall_64_[k] 2\n"
+        );
+
+        let formater = FoldedFormater::new(FoldedOptions {
+            kernel_first: true,
+            with_tid: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            formater.fold(&record()),
+            "app-10/11;entry_SYSCALL_64_[k];do_syscall_64_[k];main;write"
+        );
+    }
+
+    #[test]
+    fn test_fold_wall_clock() {
+        let mut formater = FoldedFormater::new(FoldedOptions {
+            with_state: true,
+            unit: Unit::Nanoseconds,
+            ..Default::default()
+        });
+        for blocked in [1_000, 2_500] {
+            formater.add(&PerfRecord {
+                cycle: blocked,
+                state: ThreadState::Io,
+                ..record()
+            });
+        }
+        formater.add(&PerfRecord {
+            cycle: 1_000_000,
+            count: 3,
+            ..record()
+        });
+        assert_eq!(
+            formater.stacks(),
+            vec![
+                (
+                    "app;main;write;entry_SYSCALL_64_[k];do_syscall_64_[k];[io]",
+                    3_500
+                ),
+                (
+                    "app;main;write;entry_SYSCALL_64_[k];do_syscall_64_[k];[running]",
+                    3_000_000
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+use anyhow::Error;
+use serde_json::{json, Value};
+
+use super::{
+    timeline::{Thread, Timeline, TimelineFrame},
+    Formater,
+};
+use crate::profiler::perf_record::PerfRecord;
+
+const GECKO_VERSION: u32 = 24;
+const CATEGORY_USER: u32 = 0;
+const CATEGORY_KERNEL: u32 = 1;
+
+/// Gecko profile format, imported by the Firefox Profiler
+/// (https://profiler.firefox.com). Every process is a sub-profile, with a
+/// track for each of its threads.
+#[derive(Default)]
+pub struct GeckoFormater {
+    timeline: Timeline,
+}
+
+impl GeckoFormater {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn thread(&self, thread: &Thread, start: u64) -> Value {
+        let mut strings: Vec<&str> = Vec::new();
+        let mut string_ids: HashMap<&str, usize> = HashMap::new();
+        // timeline frame -> thread frame
+        let mut frame_ids: HashMap<usize, usize> = HashMap::new();
+        let mut frames = Vec::new();
+        // (prefix, frame) -> stack
+        let mut stack_ids: HashMap<(Option<usize>, usize), usize> = HashMap::new();
+        let mut stacks = Vec::new();
+        let mut samples = Vec::new();
+
+        for sample in thread.sorted() {
+            let mut prefix = None;
+            for id in &sample.stack {
+                let frame = *frame_ids.entry(*id).or_insert_with(|| {
+                    let TimelineFrame { name, kernel, .. } = &self.timeline.frames[*id];
+                    let location = *string_ids.entry(name).or_insert_with(|| {
+                        strings.push(name);
+                        strings.len() - 1
+                    });
+                    let category = if *kernel {
+                        CATEGORY_KERNEL
+                    } else {
+                        CATEGORY_USER
+                    };
+                    // location, relevantForJS, innerWindowID, implementation,
+                    // line, column, category, subcategory
+                    frames.push(json!([location, false, 0, null, null, null, category, 0]));
+                    frames.len() - 1
+                });
+                let stack = *stack_ids.entry((prefix, frame)).or_insert_with(|| {
+                    stacks.push(json!([prefix, frame]));
+                    stacks.len() - 1
+                });
+                prefix = Some(stack);
+            }
+            let time = (sample.ts - start) as f64 / 1e6;
+            samples.push(json!([prefix, time, 0]));
+        }
+
+        json!({
+            "name": format!("{} ({})", thread.comm, thread.tid),
+            "processName": thread.comm,
+            "processType": "default",
+            "pid": thread.tgid,
+            "tid": thread.tid,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "samples": {
+                "schema": { "stack": 0, "time": 1, "eventDelay": 2 },
+                "data": samples,
+            },
+            "markers": {
+                "schema": { "name": 0, "startTime": 1, "endTime": 2, "phase": 3, "category": 4, "data": 5 },
+                "data": [],
+            },
+            "stackTable": {
+                "schema": { "prefix": 0, "frame": 1 },
+                "data": stacks,
+            },
+            "frameTable": {
+                "schema": {
+                    "location": 0, "relevantForJS": 1, "innerWindowID": 2, "implementation": 3,
+                    "line": 4, "column": 5, "category": 6, "subcategory": 7,
+                },
+                "data": frames,
+            },
+            "stringTable": strings,
+        })
+    }
+
+    fn build(&self) -> Value {
+        let start = self.timeline.start();
+        let interval = self
+            .timeline
+            .threads
+            .values()
+            .map(|t| t.interval())
+            .min()
+            .unwrap_or(1);
+        let meta = json!({
+            "version": GECKO_VERSION,
+            "startTime": start as f64 / 1e6,
+            "shutdownTime": null,
+            "interval": interval as f64 / 1e6,
+            "stackwalk": 1,
+            "debug": 0,
+            "gcpoison": 0,
+            "asyncstack": 0,
+            "processType": 0,
+            "presymbolicated": true,
+            "product": "doctor",
+            "categories": [
+                { "name": "User", "color": "yellow", "subcategories": ["Other"] },
+                { "name": "Kernel", "color": "orange", "subcategories": ["Other"] },
+            ],
+            "markerSchema": [],
+        });
+
+        // tgid -> threads
+        let mut processes: BTreeMap<u32, Vec<Value>> = BTreeMap::new();
+        for thread in self.timeline.threads.values() {
+            processes
+                .entry(thread.tgid)
+                .or_default()
+                .push(self.thread(thread, start));
+        }
+        // the sub-profiles start with the session, their times are not shifted
+        let processes = processes
+            .into_values()
+            .map(|threads| profile(&meta, threads, Vec::new()))
+            .collect();
+        profile(&meta, Vec::new(), processes)
+    }
+}
+
+fn profile(meta: &Value, threads: Vec<Value>, processes: Vec<Value>) -> Value {
+    json!({
+        "meta": meta,
+        "libs": [],
+        "threads": threads,
+        "processes": processes,
+        "pausedRanges": [],
+    })
+}
+
+impl Formater for GeckoFormater {
+    fn add(&mut self, record: &PerfRecord) {
+        self.timeline.add(record);
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        serde_json::to_writer(writer, &self.build())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::perf_record::{PerfStackFrame, ThreadState};
+
+    fn record(tgid: u32, tid: u32, ts: u64, syms: &[&str]) -> PerfRecord {
+        PerfRecord {
+            pid: tid,
+            tgid,
+            cpu_id: 0,
+            cmdline: format!("app{}", tgid),
+            ts,
+            cycle: 1,
+            count: 1,
+            state: ThreadState::Running,
+            frames: syms
+                .iter()
+                .map(|s| PerfStackFrame::new(0, s.to_string(), "/bin/app".into(), 0))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_gecko_processes() {
+        let mut formater = GeckoFormater::new();
+        formater.add(&record(10, 10, 1_000_000, &["foo", "main"]));
+        formater.add(&record(20, 21, 1_500_000, &["baz", "start"]));
+        formater.add(&record(10, 11, 2_000_000, &["bar", "main"]));
+        formater.add(&record(10, 10, 3_000_000, &["foo", "main"]));
+        let mut out = Vec::new();
+        formater.write(&mut out).unwrap();
+        let value: Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(value["meta"]["version"], GECKO_VERSION);
+        assert_eq!(value["meta"]["startTime"], 1.0);
+        assert_eq!(value["threads"], json!([]));
+        let processes = value["processes"].as_array().unwrap();
+        assert_eq!(processes.len(), 2);
+
+        // one sub-profile per process, with its threads
+        let app = &processes[0];
+        assert_eq!(app["meta"]["startTime"], value["meta"]["startTime"]);
+        assert_eq!(app["processes"], json!([]));
+        let threads = app["threads"].as_array().unwrap();
+        let tids: Vec<_> = threads.iter().map(|t| (&t["pid"], &t["tid"])).collect();
+        assert_eq!(tids, [(&json!(10), &json!(10)), (&json!(10), &json!(11))]);
+        assert_eq!(threads[0]["processName"], "app10");
+        assert_eq!(processes[1]["threads"][0]["tid"], 21);
+
+        let thread = &threads[0];
+        let schema = |table: &str| thread[table]["schema"].clone();
+        assert_eq!(
+            schema("samples"),
+            json!({ "stack": 0, "time": 1, "eventDelay": 2 })
+        );
+        assert_eq!(schema("stackTable"), json!({ "prefix": 0, "frame": 1 }));
+        assert_eq!(schema("frameTable")["location"], 0);
+        assert_eq!(schema("frameTable")["category"], 6);
+
+        // samples resolve to their stack, leaf first
+        let samples = thread["samples"]["data"].as_array().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1][1], 2.0);
+        let mut stack = &samples[0][0];
+        let mut names = Vec::new();
+        while let Some(id) = stack.as_u64() {
+            let entry = &thread["stackTable"]["data"][id as usize];
+            let (prefix, frame) = (&entry[0], &entry[1]);
+            let location = &thread["frameTable"]["data"][frame.as_u64().unwrap() as usize][0];
+            names.push(thread["stringTable"][location.as_u64().unwrap() as usize].clone());
+            stack = prefix;
+        }
+        assert_eq!(names, ["foo", "main"]);
+    }
+}
@@ -0,0 +1,58 @@
+pub mod flamegraph;
+pub mod folded;
+pub mod gecko;
+pub mod pprof;
+pub mod raw;
+pub mod speedscope;
+pub mod timeline;
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Error;
+
+use super::perf_record::PerfRecord;
+
+/// A formater aggregates `PerfRecord`s of a session and renders them into an
+/// output format once the session is over.
+pub trait Formater {
+    fn add(&mut self, record: &PerfRecord);
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error>;
+
+    /// Writes the output to `path` once the session is over.
+    fn write_file(&mut self, path: &Path) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// What the values of the stack based formats count.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Unit {
+    /// on-cpu samples
+    #[default]
+    Samples,
+    /// time, which adds up on-cpu and off-cpu records
+    Nanoseconds,
+}
+
+impl Unit {
+    pub fn weight(&self, record: &PerfRecord) -> u64 {
+        match self {
+            Unit::Samples => record.count,
+            Unit::Nanoseconds => record.time(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Unit::Samples => "samples",
+            Unit::Nanoseconds => "ns",
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use flate2::{write::GzEncoder, Compression};
+use prost::Message;
+
+use super::Formater;
+use crate::profiler::perf_record::{PerfRecord, PerfStackFrame};
+
+// Messages of https://github.com/google/pprof/blob/main/proto/profile.proto,
+// only the fields doctor fills are declared.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub mapping: Vec<Mapping>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(int64, tag = "9")]
+    pub time_nanos: i64,
+    #[prost(int64, tag = "10")]
+    pub duration_nanos: i64,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+    #[prost(message, repeated, tag = "3")]
+    pub label: Vec<Label>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(int64, tag = "1")]
+    pub key: i64,
+    #[prost(int64, tag = "2")]
+    pub str: i64,
+    #[prost(int64, tag = "3")]
+    pub num: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Mapping {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "2")]
+    pub memory_start: u64,
+    #[prost(uint64, tag = "3")]
+    pub memory_limit: u64,
+    #[prost(uint64, tag = "4")]
+    pub file_offset: u64,
+    #[prost(int64, tag = "5")]
+    pub filename: i64,
+    #[prost(int64, tag = "6")]
+    pub build_id: i64,
+    #[prost(bool, tag = "7")]
+    pub has_functions: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "2")]
+    pub mapping_id: u64,
+    #[prost(uint64, tag = "3")]
+    pub address: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+    #[prost(int64, tag = "2")]
+    pub line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+    #[prost(int64, tag = "3")]
+    pub system_name: i64,
+    #[prost(int64, tag = "4")]
+    pub filename: i64,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct SampleKey {
+    locations: Vec<u64>,
+    pid: u32,
+    tgid: u32,
+    comm: i64,
+    state: i64,
+}
+
+/// Aggregates records into a pprof `profile.proto`, written gzipped as
+/// expected by `go tool pprof`. Mappings are files, shared by the processes
+/// that map them, so addresses are the offsets of frames in their file.
+pub struct PprofFormater {
+    start: SystemTime,
+    strings: Vec<String>,
+    string_ids: HashMap<String, i64>,
+    mappings: HashMap<PathBuf, u64>,
+    functions: HashMap<(i64, i64), u64>,
+    locations: HashMap<(u64, u64, u64), u64>, // mapping, address, function -> location
+    samples: HashMap<SampleKey, [i64; 2]>,
+    first_ts: Option<u64>,
+    last_ts: Option<u64>,
+    label_keys: [i64; 4],
+    profile: Profile,
+}
+
+impl Default for PprofFormater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PprofFormater {
+    pub fn new() -> Self {
+        Self::with_value_type("cpu")
+    }
+
+    /// `value` names the ns valued sample type, e.g. `offcpu` for blocked time.
+    pub fn with_value_type(value: &str) -> Self {
+        let mut formater = Self {
+            start: SystemTime::now(),
+            strings: Vec::new(),
+            string_ids: HashMap::new(),
+            mappings: HashMap::new(),
+            functions: HashMap::new(),
+            locations: HashMap::new(),
+            samples: HashMap::new(),
+            first_ts: None,
+            last_ts: None,
+            label_keys: [0; 4],
+            profile: Profile::default(),
+        };
+        // string_table[0] must always be ""
+        formater.string_id("");
+        let cpu = ValueType {
+            r#type: formater.string_id(value),
+            unit: formater.string_id("nanoseconds"),
+        };
+        formater.profile.sample_type = vec![
+            ValueType {
+                r#type: formater.string_id("samples"),
+                unit: formater.string_id("count"),
+            },
+            cpu.clone(),
+        ];
+        formater.profile.period_type = Some(cpu);
+        formater.label_keys = [
+            formater.string_id("pid"),
+            formater.string_id("tid"),
+            formater.string_id("comm"),
+            formater.string_id("state"),
+        ];
+        formater
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.string_ids.insert(s.to_owned(), id);
+        id
+    }
+
+    fn mapping_id(&mut self, elf: &Path, build_id: Option<&str>) -> u64 {
+        if let Some(id) = self.mappings.get(elf) {
+            return *id;
+        }
+        let id = self.profile.mapping.len() as u64 + 1;
+        let filename = self.string_id(&elf.to_string_lossy());
+        let build_id = self.string_id(build_id.unwrap_or_default());
+        self.profile.mapping.push(Mapping {
+            id,
+            filename,
+            build_id,
+            has_functions: true,
+            ..Default::default()
+        });
+        self.mappings.insert(elf.to_path_buf(), id);
+        id
+    }
+
+    fn function_id(&mut self, frame: &PerfStackFrame) -> u64 {
+        let name = self.string_id(&frame.sym);
+        let filename = match &frame.file {
+            Some(file) => self.string_id(file),
+            None => self.string_id(&frame.elf.to_string_lossy()),
+        };
+        if let Some(id) = self.functions.get(&(name, filename)) {
+            return *id;
+        }
+        let id = self.profile.function.len() as u64 + 1;
+        self.profile.function.push(Function {
+            id,
+            name,
+            system_name: name,
+            filename,
+        });
+        self.functions.insert((name, filename), id);
+        id
+    }
+
+    /// Location of a frame and the frames inlined into it, innermost first.
+    fn location_id(&mut self, frames: &[PerfStackFrame]) -> u64 {
+        let frame = &frames[frames.len() - 1];
+        let mapping_id = self.mapping_id(&frame.elf, frame.build_id.as_deref());
+        // JIT and Python offsets are within their function
+        let key = (mapping_id, frame.f_ost, self.function_id(frame));
+        if let Some(id) = self.locations.get(&key) {
+            return *id;
+        }
+        let line = frames
+            .iter()
+            .map(|f| Line {
+                function_id: self.function_id(f),
+                line: f.line.unwrap_or_default() as i64,
+            })
+            .collect();
+        let id = self.profile.location.len() as u64 + 1;
+        self.profile.location.push(Location {
+            id,
+            mapping_id,
+            address: frame.f_ost,
+            line,
+        });
+        self.locations.insert(key, id);
+        // the mapping starts at offset 0 of its file
+        let mapping = &mut self.profile.mapping[mapping_id as usize - 1];
+        mapping.memory_limit = mapping.memory_limit.max(frame.f_ost.saturating_add(1));
+        id
+    }
+
+    fn build(&self) -> Profile {
+        let mut profile = self.profile.clone();
+        let [pid_key, tid_key, comm_key, state_key] = self.label_keys;
+
+        profile.sample = self
+            .samples
+            .iter()
+            .map(|(key, values)| Sample {
+                location_id: key.locations.clone(),
+                value: values.to_vec(),
+                label: vec![
+                    Label {
+                        key: pid_key,
+                        num: key.tgid as i64,
+                        ..Default::default()
+                    },
+                    Label {
+                        key: tid_key,
+                        num: key.pid as i64,
+                        ..Default::default()
+                    },
+                    Label {
+                        key: comm_key,
+                        str: key.comm,
+                        ..Default::default()
+                    },
+                    Label {
+                        key: state_key,
+                        str: key.state,
+                        ..Default::default()
+                    },
+                ],
+            })
+            .collect();
+        profile.string_table = self.strings.clone();
+        profile.time_nanos = self.first_ts.unwrap_or_else(|| {
+            self.start
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        }) as i64;
+        profile.duration_nanos = match (self.first_ts, self.last_ts) {
+            (Some(first), Some(last)) => (last - first) as i64,
+            _ => self
+                .start
+                .elapsed()
+                .map(|d| d.as_nanos() as i64)
+                .unwrap_or_default(),
+        };
+        profile
+    }
+}
+
+impl Formater for PprofFormater {
+    fn add(&mut self, record: &PerfRecord) {
+        // pprof expects the leaf first, which is how frames are already ordered,
+        // inlined frames are lines of the location of their caller
+        let mut locations = Vec::new();
+        let mut start = 0;
+        for (i, frame) in record.frames.iter().enumerate() {
+            if !frame.inlined {
+                locations.push(self.location_id(&record.frames[start..=i]));
+                start = i + 1;
+            }
+        }
+        let comm = self.string_id(&record.cmdline);
+        let state = self.string_id(record.state.name());
+        let key = SampleKey {
+            locations,
+            pid: record.pid,
+            tgid: record.tgid,
+            comm,
+            state,
+        };
+        let values = self.samples.entry(key).or_default();
+        values[0] += record.count as i64;
+        values[1] += (record.cycle * record.count) as i64;
+
+        if self.first_ts.is_none_or(|ts| record.ts < ts) {
+            self.first_ts = Some(record.ts);
+        }
+        if self.last_ts.is_none_or(|ts| record.ts > ts) {
+            self.last_ts = Some(record.ts);
+        }
+        if record.on_cpu() {
+            self.profile.period = record.cycle as i64;
+        }
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        encoder.write_all(&self.build().encode_to_vec())?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::profiler::perf_record::ThreadState;
+
+    fn record(syms: &[(u64, &str)]) -> PerfRecord {
+        PerfRecord {
+            pid: 2,
+            tgid: 1,
+            cpu_id: 0,
+            cmdline: "app".into(),
+            ts: 0,
+            cycle: 1_000_000,
+            count: 1,
+            state: ThreadState::Running,
+            frames: syms
+                .iter()
+                .map(|(ip, s)| PerfStackFrame::new(*ip, s.to_string(), "/bin/app".into(), *ip))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_pprof_aggregate() {
+        let mut formater = PprofFormater::new();
+        let mut first = record(&[(0x10, "foo"), (0x30, "main")]);
+        first.ts = 1_000;
+        let mut last = record(&[(0x20, "bar"), (0x30, "main")]);
+        last.ts = 3_000;
+        formater.add(&first);
+        formater.add(&record(&[(0x10, "foo"), (0x30, "main")]));
+        formater.add(&last);
+
+        let mut out = Vec::new();
+        formater.write(&mut out).unwrap();
+        let mut raw = Vec::new();
+        GzDecoder::new(out.as_slice())
+            .read_to_end(&mut raw)
+            .unwrap();
+        let profile = Profile::decode(raw.as_slice()).unwrap();
+
+        assert_eq!(profile.string_table[0], "");
+        // the span of the samples, the second has ts 0
+        assert_eq!((profile.time_nanos, profile.duration_nanos), (0, 3_000));
+        assert_eq!(profile.mapping.len(), 1);
+        assert_eq!(profile.function.len(), 3);
+        assert_eq!(profile.sample.len(), 2);
+        let total: i64 = profile.sample.iter().map(|s| s.value[0]).sum();
+        assert_eq!(total, 3);
+        let cpu: i64 = profile.sample.iter().map(|s| s.value[1]).sum();
+        assert_eq!(cpu, 3_000_000);
+
+        let foo = profile
+            .sample
+            .iter()
+            .find(|s| s.value[0] == 2)
+            .expect("merged sample");
+        let leaf = &profile.location[foo.location_id[0] as usize - 1];
+        let func = &profile.function[leaf.line[0].function_id as usize - 1];
+        assert_eq!(profile.string_table[func.name as usize], "foo");
+    }
+
+    #[test]
+    fn test_pprof_file_offsets() {
+        let mut formater = PprofFormater::new();
+        let mut first = record(&[(0x1010, "foo"), (0x1030, "main")]);
+        // the same code mapped elsewhere by another process
+        let mut second = record(&[(0x7f00_1010, "foo"), (0x7f00_1030, "main")]);
+        second.tgid = 3;
+        for (frame, offset) in first.frames.iter_mut().zip([0x10, 0x30]) {
+            frame.f_ost = offset;
+        }
+        for (frame, offset) in second.frames.iter_mut().zip([0x10, 0x30]) {
+            frame.f_ost = offset;
+        }
+        formater.add(&first);
+        formater.add(&second);
+
+        let profile = formater.build();
+        assert_eq!(profile.location.len(), 2);
+        let addresses: Vec<u64> = profile.location.iter().map(|l| l.address).collect();
+        assert_eq!(addresses, [0x10, 0x30]);
+        let mapping = &profile.mapping[0];
+        assert_eq!((mapping.memory_start, mapping.file_offset), (0, 0));
+        assert!(addresses.iter().all(|a| *a < mapping.memory_limit));
+    }
+
+    #[test]
+    fn test_pprof_inlined() {
+        let mut formater = PprofFormater::new();
+        let mut inlined = record(&[(0x10, "inner"), (0x10, "outer"), (0x30, "main")]);
+        inlined.frames[0].inlined = true;
+        inlined.frames[0].file = Some("src/inner.rs".into());
+        inlined.frames[0].line = Some(7);
+        formater.add(&inlined);
+
+        let profile = formater.build();
+        assert_eq!(profile.location.len(), 2);
+        let lines = &profile.location[0].line;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].line, 7);
+        let inner = &profile.function[lines[0].function_id as usize - 1];
+        assert_eq!(profile.string_table[inner.name as usize], "inner");
+        assert_eq!(
+            profile.string_table[inner.filename as usize],
+            "src/inner.rs"
+        );
+        let outer = &profile.function[lines[1].function_id as usize - 1];
+        assert_eq!(profile.string_table[outer.name as usize], "outer");
+    }
+}
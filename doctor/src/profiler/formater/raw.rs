@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use super::Formater;
+use crate::profiler::{
+    perf_record::{FrameKind, PerfRecord, PerfStackFrame, ThreadState},
+    process::ProcessMetadata,
+    symbolizer::{elf::ElfMetadata, symbol_store::FileId},
+};
+
+// bumped when the file format changes
+const RAW_VERSION: u32 = 1;
+
+/// An executable file mapping of a process, with the build-id of its file.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    pub offset: u64,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+}
+
+/// One JSON object per line: the header first, then the mappings of a
+/// process before the samples that need them.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Line {
+    Header { version: u32, mode: String },
+    Mappings { tgid: u32, mappings: Vec<Mapping> },
+    Sample(Sample),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sample {
+    pid: u32,
+    tgid: u32,
+    cpu: u32,
+    cmdline: String,
+    ts: u64,
+    cycle: u64,
+    count: u64,
+    state: String,
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Frame {
+    /// user frame, symbolized from the mappings of its process
+    Address(u64),
+    /// kernel and JIT frames, only the recording host can symbolize them
+    Symbolized {
+        ip: u64,
+        sym: String,
+        dso: PathBuf,
+        offset: u64,
+        #[serde(default)]
+        kernel: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line: Option<u32>,
+        #[serde(default)]
+        inlined: bool,
+    },
+}
+
+/// Samples of `record --no-symbolize`: user frames as addresses, with a
+/// snapshot of the executable mappings of each process, for `doctor
+/// symbolize` to symbolize on another machine. Lines are written as the
+/// samples come, not kept until the session is over.
+pub struct RawFormater<W: Write> {
+    writer: W,
+    error: Option<Error>, // first write that failed, reported at the end
+    mappings: HashMap<u32, Vec<Mapping>>, // tgid -> last snapshot written
+    build_ids: HashMap<FileId, Option<String>>,
+}
+
+impl<W: Write> RawFormater<W> {
+    /// Writes the header to `writer` now, and each sample when it is added.
+    pub fn new(mode: &str, writer: W) -> Self {
+        let mut formater = Self {
+            writer,
+            error: None,
+            mappings: HashMap::new(),
+            build_ids: HashMap::new(),
+        };
+        formater.write_line(&Line::Header {
+            version: RAW_VERSION,
+            mode: mode.into(),
+        });
+        formater
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.writer.flush()?),
+        }
+    }
+
+    fn write_line(&mut self, line: &Line) {
+        if self.error.is_some() {
+            return;
+        }
+        let written = serde_json::to_writer(&mut self.writer, line)
+            .map_err(Error::from)
+            .and_then(|_| Ok(self.writer.write_all(b"\n")?));
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+
+    /// Executable file mappings of `tgid` now.
+    fn snapshot(&mut self, tgid: u32) -> Result<Vec<Mapping>, Error> {
+        let proc = ProcessMetadata::new(tgid)?;
+        let mut mappings = Vec::new();
+        for map in proc.maps() {
+            let Some(path) = ProcessMetadata::dso_path(map) else {
+                continue;
+            };
+            mappings.push(Mapping {
+                start: map.address.0,
+                end: map.address.1,
+                offset: map.offset,
+                build_id: self.build_id(&proc.rootfs, &path),
+                path,
+            });
+        }
+        Ok(mappings)
+    }
+
+    fn build_id(&mut self, rootfs: &Path, dso: &Path) -> Option<String> {
+        let path = rootfs.join(dso.strip_prefix("/").ok()?);
+        let file = FileId::new(&path).ok()?;
+        self.build_ids
+            .entry(file)
+            .or_insert_with(|| ElfMetadata::read_build_id(&path).ok().flatten())
+            .clone()
+    }
+}
+
+impl<W: Write> Formater for RawFormater<W> {
+    fn add(&mut self, record: &PerfRecord) {
+        let covered =
+            |mappings: &[Mapping], ip: u64| mappings.iter().any(|m| (m.start..m.end).contains(&ip));
+        let known = self.mappings.get(&record.tgid).map(Vec::as_slice);
+        let missing = record
+            .frames
+            .iter()
+            .filter(|f| f.kind == FrameKind::Unsymbolized)
+            .any(|f| !known.is_some_and(|known| covered(known, f.ip)));
+        // first seen, or it mapped more since
+        if missing {
+            match self.snapshot(record.tgid) {
+                Ok(mappings) if self.mappings.get(&record.tgid) != Some(&mappings) => {
+                    self.write_line(&Line::Mappings {
+                        tgid: record.tgid,
+                        mappings: mappings.clone(),
+                    });
+                    self.mappings.insert(record.tgid, mappings);
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("mappings of {}: {}", record.tgid, e),
+            }
+        }
+
+        let frames = record
+            .frames
+            .iter()
+            .map(|f| match f.kind {
+                FrameKind::Unsymbolized => Frame::Address(f.ip),
+                kind => Frame::Symbolized {
+                    ip: f.ip,
+                    sym: f.sym.clone(),
+                    dso: f.elf.clone(),
+                    offset: f.f_ost,
+                    kernel: kind == FrameKind::Kernel,
+                    file: f.file.clone(),
+                    line: f.line,
+                    inlined: f.inlined,
+                },
+            })
+            .collect();
+        self.write_line(&Line::Sample(Sample {
+            pid: record.pid,
+            tgid: record.tgid,
+            cpu: record.cpu_id,
+            cmdline: record.cmdline.clone(),
+            ts: record.ts,
+            cycle: record.cycle,
+            count: record.count,
+            state: record.state.name().into(),
+            frames,
+        }));
+    }
+
+    fn write(&self, _writer: &mut dyn Write) -> Result<(), Error> {
+        Err(anyhow!("raw profiles are written as they are recorded"))
+    }
+
+    /// The lines are in the writer of `new` already, `path` is its file.
+    fn write_file(&mut self, _path: &Path) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+/// Reads back what `RawFormater` writes.
+pub struct RawReader<R> {
+    lines: std::io::Lines<R>,
+    /// profile mode the samples were recorded in
+    pub mode: String,
+    mappings: HashMap<u32, Vec<Mapping>>,
+}
+
+impl<R: BufRead> RawReader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or_else(|| anyhow!("empty raw profile"))??;
+        let mode = match serde_json::from_str(&header) {
+            Ok(Line::Header { version, mode }) if version == RAW_VERSION => mode,
+            Ok(Line::Header { version, .. }) => {
+                return Err(anyhow!("raw profile version {} is not supported", version))
+            }
+            _ => return Err(anyhow!("not a raw profile of `record --no-symbolize`")),
+        };
+        Ok(Self {
+            lines,
+            mode,
+            mappings: HashMap::new(),
+        })
+    }
+
+    /// Next record, its user frames unsymbolized, with the mappings its
+    /// process had then.
+    pub fn next_record(&mut self) -> Result<Option<(PerfRecord, &[Mapping])>, Error> {
+        for line in self.lines.by_ref() {
+            let sample = match serde_json::from_str(&line?)? {
+                Line::Mappings { tgid, mappings } => {
+                    self.mappings.insert(tgid, mappings);
+                    continue;
+                }
+                Line::Sample(sample) => sample,
+                Line::Header { .. } => return Err(anyhow!("header in the middle of the profile")),
+            };
+            let frames = sample
+                .frames
+                .into_iter()
+                .map(|frame| match frame {
+                    Frame::Address(ip) => PerfStackFrame {
+                        kind: FrameKind::Unsymbolized,
+                        ..PerfStackFrame::new(ip, String::new(), PathBuf::new(), 0)
+                    },
+                    Frame::Symbolized {
+                        ip,
+                        sym,
+                        dso,
+                        offset,
+                        kernel,
+                        file,
+                        line,
+                        inlined,
+                    } => PerfStackFrame {
+                        kind: match kernel {
+                            true => FrameKind::Kernel,
+                            false => FrameKind::User,
+                        },
+                        file,
+                        line,
+                        inlined,
+                        ..PerfStackFrame::new(ip, sym, dso, offset)
+                    },
+                })
+                .collect();
+            let record = PerfRecord {
+                pid: sample.pid,
+                tgid: sample.tgid,
+                cpu_id: sample.cpu,
+                cmdline: sample.cmdline,
+                ts: sample.ts,
+                cycle: sample.cycle,
+                count: sample.count,
+                state: ThreadState::from_name(&sample.state).unwrap_or(ThreadState::Running),
+                frames,
+            };
+            let mappings = self.mappings.get(&sample.tgid).map(Vec::as_slice);
+            return Ok(Some((record, mappings.unwrap_or_default())));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::{
+        symbolizer::{
+            kallsyms::KernelImages,
+            symbolizer::{Symbolizer, SymbolizerOptions},
+        },
+        translator::Translator,
+    };
+
+    #[inline(never)]
+    fn raw_target() -> u64 {
+        std::hint::black_box(42)
+    }
+
+    fn translator() -> Translator {
+        let symbolizer = Symbolizer::new(SymbolizerOptions::default()).unwrap();
+        Translator::new("/".into(), KernelImages::default(), symbolizer)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_raw_roundtrip() {
+        assert_eq!(raw_target(), 42);
+        let pid = std::process::id();
+        let ip = raw_target as *const () as u64 + 1;
+        let mut frames = translator()
+            .without_symbols()
+            .translate_usyms(pid, vec![ip])
+            .unwrap();
+        assert_eq!(frames[0].kind, FrameKind::Unsymbolized);
+        frames.insert(
+            0,
+            PerfStackFrame {
+                kind: FrameKind::Kernel,
+                ..PerfStackFrame::new(1, "schedule_[k]".into(), "[kernel.kallsyms]".into(), 1)
+            },
+        );
+        let record = PerfRecord {
+            pid,
+            tgid: pid,
+            cpu_id: 3,
+            cmdline: "doctor".into(),
+            ts: 1000,
+            cycle: 1_000_000,
+            count: 2,
+            state: ThreadState::Running,
+            frames,
+        };
+
+        let mut raw = Vec::new();
+        let mut formater = RawFormater::new("cpu", &mut raw);
+        formater.add(&record);
+        formater.add(&record);
+        formater.flush().unwrap();
+        drop(formater);
+        // the mappings are written once
+        let raw_lines = String::from_utf8_lossy(&raw);
+        let mappings = raw_lines.lines().filter(|l| l.starts_with("{\"mappings\""));
+        assert_eq!(mappings.count(), 1);
+
+        let mut reader = RawReader::new(raw.as_slice()).unwrap();
+        assert_eq!(reader.mode, "cpu");
+        let mut translator = translator();
+        let mut records = 0;
+        while let Some((record, mappings)) = reader.next_record().unwrap() {
+            assert_eq!((record.count, record.cpu_id), (2, 3));
+            let frames = translator.translate_raw(record.frames, mappings);
+            assert_eq!(frames[0].sym, "schedule_[k]");
+            assert!(frames[0].is_kernel());
+            let user = frames.last().unwrap();
+            assert!(user.sym.ends_with("raw::tests::raw_target"), "{}", user.sym);
+            assert_eq!(user.elf, std::env::current_exe().unwrap());
+            records += 1;
+        }
+        assert_eq!(records, 2);
+    }
+}
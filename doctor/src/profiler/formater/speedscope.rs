@@ -0,0 +1,139 @@
+use std::io::Write;
+
+use anyhow::Error;
+use serde_json::{json, Value};
+
+use super::{timeline::Timeline, Formater};
+use crate::profiler::perf_record::PerfRecord;
+
+/// speedscope file format (https://www.speedscope.app/file-format-schema.json),
+/// one sampled profile per thread with real sample times.
+#[derive(Default)]
+pub struct SpeedscopeFormater {
+    timeline: Timeline,
+}
+
+impl SpeedscopeFormater {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn build(&self) -> Value {
+        let start = self.timeline.start();
+        let frames = self
+            .timeline
+            .frames
+            .iter()
+            .map(|f| json!({ "name": f.name, "file": f.file }))
+            .collect::<Vec<_>>();
+
+        let profiles = self
+            .timeline
+            .threads
+            .values()
+            .map(|thread| {
+                let interval = thread.interval();
+                let samples = thread.sorted();
+                let mut stacks = Vec::with_capacity(samples.len());
+                let mut weights = Vec::with_capacity(samples.len());
+                for (i, sample) in samples.iter().enumerate() {
+                    let gap = samples
+                        .get(i + 1)
+                        .map(|next| next.ts - sample.ts)
+                        .unwrap_or(interval);
+                    // a sample stands for one interval, longer gaps are idle,
+                    // off-cpu samples last as long as the thread was blocked
+                    let (weight, idle) = match sample.duration {
+                        Some(duration) => (duration, gap > duration),
+                        None => (gap.min(interval), gap > 2 * interval),
+                    };
+                    stacks.push(sample.stack.clone());
+                    weights.push(weight);
+                    if idle {
+                        stacks.push(Vec::new());
+                        weights.push(gap - weight);
+                    }
+                }
+                let start_value = samples.first().map(|s| s.ts - start).unwrap_or_default();
+                json!({
+                    "type": "sampled",
+                    "name": format!("{} [{}] tid {}", thread.comm, thread.tgid, thread.tid),
+                    "unit": "nanoseconds",
+                    "startValue": start_value,
+                    "endValue": start_value + weights.iter().sum::<u64>(),
+                    "samples": stacks,
+                    "weights": weights,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "shared": { "frames": frames },
+            "profiles": profiles,
+            "name": "doctor",
+            "activeProfileIndex": 0,
+            "exporter": "doctor",
+        })
+    }
+}
+
+impl Formater for SpeedscopeFormater {
+    fn add(&mut self, record: &PerfRecord) {
+        self.timeline.add(record);
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        serde_json::to_writer(writer, &self.build())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::perf_record::{PerfStackFrame, ThreadState};
+
+    fn record(tid: u32, ts: u64, syms: &[&str]) -> PerfRecord {
+        PerfRecord {
+            pid: tid,
+            tgid: 1,
+            cpu_id: 0,
+            cmdline: "app".into(),
+            ts,
+            cycle: 1,
+            count: 1,
+            state: ThreadState::Running,
+            frames: syms
+                .iter()
+                .map(|s| PerfStackFrame::new(0, s.to_string(), "/bin/app".into(), 0))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_speedscope_threads() {
+        let mut formater = SpeedscopeFormater::new();
+        formater.add(&record(1, 1_000, &["foo", "main"]));
+        formater.add(&record(2, 1_500, &["bar", "main"]));
+        formater.add(&record(1, 2_000, &["foo", "main"]));
+        formater.add(&record(1, 3_000, &["foo", "main"]));
+        // one idle gap
+        formater.add(&record(1, 9_000, &["main"]));
+
+        let value = formater.build();
+        let profiles = value["profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 2);
+
+        let main_thread = &profiles[0];
+        assert_eq!(main_thread["startValue"], 0);
+        assert_eq!(
+            main_thread["weights"],
+            json!([1000, 1000, 1000, 5000, 1000])
+        );
+        assert_eq!(main_thread["samples"][0], json!([0, 1]));
+        assert_eq!(main_thread["samples"][3], json!([]));
+        assert_eq!(profiles[1]["startValue"], 500);
+        assert_eq!(value["shared"]["frames"][0]["name"], "main");
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::profiler::perf_record::PerfRecord;
+
+pub struct TimelineFrame {
+    pub name: String,
+    pub file: String,
+    pub kernel: bool,
+}
+
+pub struct TimelineSample {
+    pub ts: u64,
+    /// indexes into `Timeline::frames`, root first
+    pub stack: Vec<usize>,
+    /// known length of the sample, the time blocked for off-cpu samples
+    pub duration: Option<u64>,
+}
+
+pub struct Thread {
+    pub tgid: u32,
+    pub tid: u32,
+    pub comm: String,
+    pub samples: Vec<TimelineSample>,
+}
+
+impl Thread {
+    /// Samples ordered by time.
+    pub fn sorted(&self) -> Vec<&TimelineSample> {
+        let mut samples = self.samples.iter().collect::<Vec<_>>();
+        samples.sort_by_key(|s| s.ts);
+        samples
+    }
+
+    /// Sampling interval, estimated as the median gap between samples.
+    pub fn interval(&self) -> u64 {
+        let samples = self.sorted();
+        let mut gaps = samples
+            .windows(2)
+            .map(|w| w[1].ts - w[0].ts)
+            .filter(|gap| *gap > 0)
+            .collect::<Vec<_>>();
+        gaps.sort_unstable();
+        gaps.get(gaps.len() / 2).copied().unwrap_or(1)
+    }
+}
+
+/// Samples kept in order per thread, for the timeline based formats.
+#[derive(Default)]
+pub struct Timeline {
+    pub frames: Vec<TimelineFrame>,
+    frame_ids: HashMap<(String, String), usize>,
+    /// (tgid, tid) -> thread, so threads of a process are adjacent
+    pub threads: BTreeMap<(u32, u32), Thread>,
+}
+
+impl Timeline {
+    pub fn add(&mut self, record: &PerfRecord) {
+        let stack = record
+            .root_first(false)
+            .into_iter()
+            .map(|f| {
+                let key = (
+                    f.folded_name().into_owned(),
+                    f.elf.to_string_lossy().to_string(),
+                );
+                match self.frame_ids.get(&key) {
+                    Some(id) => *id,
+                    None => {
+                        let id = self.frames.len();
+                        self.frames.push(TimelineFrame {
+                            name: key.0.clone(),
+                            file: key.1.clone(),
+                            kernel: f.is_kernel(),
+                        });
+                        self.frame_ids.insert(key, id);
+                        id
+                    }
+                }
+            })
+            .collect();
+
+        self.threads
+            .entry((record.tgid, record.pid))
+            .or_insert_with(|| Thread {
+                tgid: record.tgid,
+                tid: record.pid,
+                comm: record.cmdline.clone(),
+                samples: Vec::new(),
+            })
+            .samples
+            .push(TimelineSample {
+                ts: record.ts,
+                stack,
+                duration: (!record.on_cpu()).then(|| record.time()),
+            });
+    }
+
+    /// Timestamp of the first sample of the session.
+    pub fn start(&self) -> u64 {
+        self.threads
+            .values()
+            .flat_map(|t| t.samples.iter().map(|s| s.ts))
+            .min()
+            .unwrap_or_default()
+    }
+}
@@ -1,7 +1,9 @@
 pub mod error;
+pub mod event;
 pub mod formater;
 pub mod perf_record;
 pub mod process;
 pub mod profiler;
 pub mod symbolizer;
 pub mod translator;
+pub mod unwind;
@@ -1,22 +1,100 @@
-use std::{fmt, path::PathBuf};
+use std::{
+    borrow::Cow,
+    fmt,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use doctor_common::StackInfo;
+use doctor_common::{StackInfo, THREAD_IO, THREAD_RUNNABLE, THREAD_SLEEPING};
+
+/// Offset between CLOCK_REALTIME and CLOCK_MONOTONIC, the clock behind
+/// `bpf_ktime_get_ns`. Taken once so all samples of a session share it.
+fn monotonic_offset() -> u64 {
+    static OFFSET: OnceLock<u64> = OnceLock::new();
+    *OFFSET.get_or_init(|| {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        let monotonic = ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64;
+        let realtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        realtime.saturating_sub(monotonic)
+    })
+}
+
+/// Converts a `bpf_ktime_get_ns` timestamp to ns since the UNIX epoch.
+pub fn ktime_to_wall(ktime: u64) -> u64 {
+    ktime + monotonic_offset()
+}
 
 pub struct PerfRecord {
     pub pid: u32,
     pub tgid: u32,
     pub cpu_id: u32,
     pub cmdline: String,
-    pub ts: u64,
-    pub cycle: u64,
+    pub ts: u64,    // ns since the UNIX epoch
+    pub cycle: u64, // sample period, ns of cpu time for the cpu-clock event, ns blocked off-cpu
+    pub count: u64, // samples merged into this record
+    pub state: ThreadState,
     pub frames: Vec<PerfStackFrame>, // pub kframes: Option<Vec<PerfStackFrame>>,
 }
 
+/// What the thread was doing, on-cpu samples are always `Running`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ThreadState {
+    Running,
+    Runnable,
+    Sleeping,
+    Io,
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Running => "running",
+            ThreadState::Runnable => "runnable",
+            ThreadState::Sleeping => "sleeping",
+            ThreadState::Io => "io",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            ThreadState::Running,
+            ThreadState::Runnable,
+            ThreadState::Sleeping,
+            ThreadState::Io,
+        ]
+        .into_iter()
+        .find(|state| state.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameKind {
+    Kernel,
+    User,
+    /// user frame of `record --no-symbolize`, symbolized later from its ip
+    Unsymbolized,
+}
+
 pub struct PerfStackFrame {
-    ip: u64,
+    pub ip: u64,
     pub sym: String,
-    elf: PathBuf,
-    f_ost: u64,
+    pub elf: PathBuf,
+    pub f_ost: u64,
+    pub kind: FrameKind,
+    pub file: Option<String>, // source file, from DWARF
+    pub line: Option<u32>,
+    /// inlined into the next frame, which has the same ip
+    pub inlined: bool,
+    /// GNU build-id of `elf`, hex encoded
+    pub build_id: Option<String>,
 }
 
 impl PerfStackFrame {
@@ -26,6 +104,24 @@ impl PerfStackFrame {
             sym,
             elf,
             f_ost,
+            kind: FrameKind::User,
+            file: None,
+            line: None,
+            inlined: false,
+            build_id: None,
+        }
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        self.kind == FrameKind::Kernel
+    }
+
+    /// Name with the `_[i]` suffix stackcollapse-perf gives inlined frames.
+    pub fn folded_name(&self) -> Cow<'_, str> {
+        if self.inlined {
+            Cow::Owned(format!("{}_[i]", self.sym))
+        } else {
+            Cow::Borrowed(&self.sym)
         }
     }
 }
@@ -33,17 +129,31 @@ impl PerfStackFrame {
 impl fmt::Display for PerfRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut format_str = format!(
-            "{} {} {} {}\n",
-            self.pid, self.cpu_id, self.tgid, self.cmdline
+            "{} {} {} {} {} {} {} {}\n",
+            self.pid,
+            self.cpu_id,
+            self.tgid,
+            self.cmdline,
+            self.ts,
+            self.cycle,
+            self.count,
+            self.state.name()
         );
         for frame in &self.frames {
             format_str.push_str(
                 format!(
-                    "    0x{:x} f_0x{:x} {}({:?})\n",
-                    frame.ip, frame.f_ost, frame.sym, frame.elf
+                    "    0x{:x} f_0x{:x} {}({:?})",
+                    frame.ip,
+                    frame.f_ost,
+                    frame.folded_name(),
+                    frame.elf
                 )
                 .as_str(),
             );
+            if let (Some(file), Some(line)) = (&frame.file, frame.line) {
+                format_str.push_str(format!(" {}:{}", file, line).as_str());
+            }
+            format_str.push('\n');
         }
         write!(f, "{}", format_str)
     }
@@ -69,10 +179,40 @@ impl PerfRecord {
             pid: stack.pid,
             tgid: stack.tgid,
             cpu_id: stack.cpu,
-            cmdline: String::from_utf8_lossy(&stack.cmd).to_string(),
-            ts: 0,
-            cycle: 1,
+            cmdline: String::from_utf8_lossy(&stack.cmd)
+                .trim_end_matches('\0')
+                .to_string(),
+            ts: ktime_to_wall(stack.ts),
+            cycle: stack.period.max(1),
+            count: 1,
+            state: match stack.state {
+                THREAD_RUNNABLE => ThreadState::Runnable,
+                THREAD_SLEEPING => ThreadState::Sleeping,
+                THREAD_IO => ThreadState::Io,
+                _ => ThreadState::Running,
+            },
             frames,
         }
     }
+
+    pub fn on_cpu(&self) -> bool {
+        self.state == ThreadState::Running
+    }
+
+    /// ns the record stands for, cpu time on-cpu and time blocked off-cpu.
+    pub fn time(&self) -> u64 {
+        self.cycle * self.count
+    }
+
+    /// Frames ordered from the root to the leaf, with the kernel part of the
+    /// stack either below (`kernel_first`) or on top of the user part.
+    pub fn root_first(&self, kernel_first: bool) -> Vec<&PerfStackFrame> {
+        let (kframes, uframes): (Vec<&PerfStackFrame>, Vec<&PerfStackFrame>) =
+            self.frames.iter().rev().partition(|f| f.is_kernel());
+        if kernel_first {
+            kframes.into_iter().chain(uframes).collect()
+        } else {
+            uframes.into_iter().chain(kframes).collect()
+        }
+    }
 }
@@ -1,4 +1,7 @@
-use std::path::PathBuf;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::PathBuf,
+};
 
 use anyhow::{anyhow, Error};
 use procfs::process::{MMPermissions, MemoryMap};
@@ -34,6 +37,11 @@ impl ProcessMetadata {
         })
     }
 
+    /// Executable, read-only mappings.
+    pub fn maps(&self) -> &[MemoryMap] {
+        &self.maps
+    }
+
     pub fn find_mapper(&self, addr: u64) -> Result<&MemoryMap, Error> {
         self.maps
             .iter()
@@ -43,18 +51,63 @@ impl ProcessMetadata {
 
     pub fn abs_addr(&self, v_addr: u64) -> Result<(PathBuf, u64), Error> {
         let mapper = self.find_mapper(v_addr)?;
+        match Self::dso_path(mapper) {
+            Some(path) => Ok((path, mapper.offset + (v_addr - mapper.address.0))),
+            None => Err(anyhow!("dso not found")),
+        }
+    }
+
+    /// File of a file mapping, as it was when mapped.
+    pub fn dso_path(mapper: &MemoryMap) -> Option<PathBuf> {
         match &mapper.pathname {
             procfs::process::MMapPath::Path(p) => {
                 let path = match p.to_str().unwrap().strip_suffix(" (deleted)") {
                     Some(striped) => PathBuf::from(striped),
                     None => p.clone(),
                 };
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Processes seen in samples, by start time so a reused pid is another
+/// process.
+#[derive(Default)]
+pub struct ProcessTracker {
+    seen: HashMap<u32, u64>,
+}
 
-                Ok((path, mapper.offset + (v_addr - mapper.address.0)))
+impl ProcessTracker {
+    /// Tracks `tgid` from the first time it is seen.
+    pub fn track(&mut self, tgid: u32) {
+        if let Entry::Vacant(entry) = self.seen.entry(tgid) {
+            if let Some(start) = start_time(tgid) {
+                entry.insert(start);
             }
-            _ => Err(anyhow!("dso not found")),
         }
     }
+
+    /// Tracked processes that exited since the last call, or whose pid was
+    /// reused, no longer tracked.
+    pub fn exited(&mut self) -> Vec<u32> {
+        let mut exited = Vec::new();
+        self.seen.retain(|&tgid, &mut start| {
+            let alive = start_time(tgid) == Some(start);
+            if !alive {
+                exited.push(tgid);
+            }
+            alive
+        });
+        exited
+    }
+}
+
+/// Clock ticks since boot when `pid` started.
+fn start_time(pid: u32) -> Option<u64> {
+    let proc = procfs::process::Process::new(pid as i32).ok()?;
+    proc.stat().ok().map(|stat| stat.starttime)
 }
 
 #[cfg(test)]
@@ -71,4 +124,21 @@ mod tests {
             println!("{:#?}", proc.abs_addr(v_addr));
         }
     }
+
+    #[test]
+    fn test_process_exit() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let mut tracker = ProcessTracker::default();
+        tracker.track(std::process::id());
+        tracker.track(child.id());
+        assert!(tracker.exited().is_empty());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(tracker.exited(), vec![child.id()]);
+        assert!(tracker.exited().is_empty());
+    }
 }
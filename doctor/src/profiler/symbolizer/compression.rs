@@ -0,0 +1,359 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::Read,
+    os::{fd::FromRawFd, unix::fs::FileExt},
+    path::Path,
+};
+
+use flate2::read::ZlibDecoder;
+use goblin::elf::{
+    compression_header::ELFCOMPRESS_ZLIB,
+    program_header::{PT_LOAD, PT_NULL},
+    section_header::{
+        SHF_ALLOC, SHF_COMPRESSED, SHT_DYNSYM, SHT_NOBITS, SHT_NOTE, SHT_NULL, SHT_STRTAB,
+    },
+    Elf,
+};
+use log::debug;
+
+use super::error::SymbolizerError;
+
+// not in goblin yet
+const ELFCOMPRESS_ZSTD: u32 = 2;
+
+/// The ELF xz compressed in `.gnu_debugdata`, MiniDebugInfo: a `.symtab` of
+/// the functions `.dynsym` does not export, kept by Fedora and RHEL in
+/// stripped binaries.
+pub fn mini_debuginfo(elf: &Elf, data: &[u8]) -> Option<Vec<u8>> {
+    let xz = section(elf, data, ".gnu_debugdata")?;
+    let mut mini = Vec::new();
+    match lzma_rs::xz_decompress(&mut &xz[..], &mut mini) {
+        Ok(()) => Some(mini),
+        Err(e) => {
+            debug!(".gnu_debugdata: {}", e);
+            None
+        }
+    }
+}
+
+/// A copy of the ELF in memory with its `SHF_COMPRESSED` sections
+/// decompressed, None when none is zstd compressed: the symbol map only
+/// reads zlib ones. Like a debug file, the copy leaves out the code and data
+/// of the program, only the sections the symbol map reads are copied.
+pub fn decompressed_copy(
+    path: &Path,
+    elf: &Elf,
+    data: &[u8],
+) -> Result<Option<File>, SymbolizerError> {
+    let zstd = elf.section_headers.iter().any(|sh| {
+        sh.sh_flags & SHF_COMPRESSED as u64 != 0
+            && sh
+                .file_range()
+                .and_then(|range| data.get(range))
+                .and_then(|raw| chdr_type(elf, raw))
+                == Some(ELFCOMPRESS_ZSTD)
+    });
+    if !zstd {
+        return Ok(None);
+    }
+
+    // the ELF and program headers, then the sections and their headers
+    let header = &elf.header;
+    let headers = |offset: u64, count: u16, size: u16| {
+        let start = offset as usize;
+        data.get(start..start + count as usize * size as usize)
+            .map(<[u8]>::to_vec)
+    };
+    let (Some(mut phdrs), Some(mut shdrs)) = (
+        headers(header.e_phoff, header.e_phnum, header.e_phentsize),
+        headers(header.e_shoff, header.e_shnum, header.e_shentsize),
+    ) else {
+        return Ok(None);
+    };
+    // the segments other than PT_LOAD, which place the sections, have their
+    // data left out
+    for (i, ph) in elf.program_headers.iter().enumerate() {
+        if ph.p_type != PT_LOAD {
+            put_u32(elf, &mut phdrs, i * header.e_phentsize as usize, PT_NULL);
+        }
+    }
+    let mut ehdr = data[..header.e_ehsize as usize].to_vec();
+    let mut offset = ehdr.len() + phdrs.len();
+    let mut sections = Vec::new();
+    for (i, sh) in elf.section_headers.iter().enumerate() {
+        let at = i * header.e_shentsize as usize;
+        let kept = sh.sh_flags & SHF_ALLOC as u64 == 0
+            || matches!(sh.sh_type, SHT_NOTE | SHT_DYNSYM | SHT_STRTAB);
+        let raw = sh.file_range().and_then(|range| data.get(range));
+        let raw = match raw {
+            Some(raw) if kept && sh.sh_type != SHT_NOBITS => raw,
+            _ => {
+                if sh.sh_type != SHT_NULL {
+                    put_u32(elf, &mut shdrs, at + 4, SHT_NOBITS);
+                }
+                continue;
+            }
+        };
+        let mut flags = sh.sh_flags;
+        let section = match sh.sh_flags & SHF_COMPRESSED as u64 != 0 {
+            true => match decompress(elf, raw) {
+                Some(section) => {
+                    flags &= !(SHF_COMPRESSED as u64);
+                    Cow::Owned(section)
+                }
+                None => {
+                    debug!("{:?}: section {} not decompressed", path, i);
+                    Cow::Borrowed(raw)
+                }
+            },
+            false => Cow::Borrowed(raw),
+        };
+        offset = offset.next_multiple_of(sh.sh_addralign.max(1) as usize);
+        // sh_flags, sh_offset and sh_size
+        let fields: [(usize, u64); 3] = if elf.is_64 {
+            [
+                (at + 8, flags),
+                (at + 24, offset as u64),
+                (at + 32, section.len() as u64),
+            ]
+        } else {
+            [
+                (at + 8, flags),
+                (at + 16, offset as u64),
+                (at + 20, section.len() as u64),
+            ]
+        };
+        for (at, value) in fields {
+            put_word(elf, &mut shdrs, at, value);
+        }
+        let len = section.len();
+        sections.push((offset, section));
+        offset += len;
+    }
+    let shoff = offset.next_multiple_of(8);
+    // e_phoff and e_shoff
+    let (phoff_at, shoff_at) = if elf.is_64 { (32, 40) } else { (28, 32) };
+    let phoff = if phdrs.is_empty() { 0 } else { ehdr.len() };
+    put_word(elf, &mut ehdr, phoff_at, phoff as u64);
+    put_word(elf, &mut ehdr, shoff_at, shoff as u64);
+
+    let io_failed = |e| SymbolizerError::DecompressIoFailed(path.into(), e);
+    let fd = unsafe { libc::memfd_create(c"doctor-elf".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io_failed(std::io::Error::last_os_error()));
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    let parts = [
+        (0, &ehdr[..]),
+        (ehdr.len(), &phdrs[..]),
+        (shoff, &shdrs[..]),
+    ];
+    for (at, part) in parts
+        .into_iter()
+        .chain(sections.iter().map(|(at, section)| (*at, &section[..])))
+    {
+        file.write_all_at(part, at as u64).map_err(io_failed)?;
+    }
+    Ok(Some(file))
+}
+
+fn section<'a>(elf: &Elf, data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let sh = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(name))?;
+    data.get(sh.file_range()?)
+}
+
+fn chdr_type(elf: &Elf, raw: &[u8]) -> Option<u32> {
+    Some(read_u32(elf, raw.get(..4)?))
+}
+
+/// Section data after its compression header, zlib or zstd.
+fn decompress(elf: &Elf, raw: &[u8]) -> Option<Vec<u8>> {
+    // ch_type, then ch_size and ch_addralign, 64 bit words after a reserved
+    // one in ELF64
+    let (size, header) = if elf.is_64 {
+        (read_u64(elf, raw.get(8..16)?), 24)
+    } else {
+        (read_u32(elf, raw.get(4..8)?) as u64, 12)
+    };
+    let body = raw.get(header..)?;
+    let mut section = Vec::with_capacity(size as usize);
+    match chdr_type(elf, raw)? {
+        ELFCOMPRESS_ZLIB => {
+            ZlibDecoder::new(body).read_to_end(&mut section).ok()?;
+        }
+        ELFCOMPRESS_ZSTD => {
+            section = zstd::bulk::decompress(body, size as usize).ok()?;
+        }
+        _ => return None,
+    }
+    (section.len() as u64 == size).then_some(section)
+}
+
+fn read_u32(elf: &Elf, raw: &[u8]) -> u32 {
+    let raw = raw.try_into().unwrap();
+    if elf.little_endian {
+        u32::from_le_bytes(raw)
+    } else {
+        u32::from_be_bytes(raw)
+    }
+}
+
+fn read_u64(elf: &Elf, raw: &[u8]) -> u64 {
+    let raw = raw.try_into().unwrap();
+    if elf.little_endian {
+        u64::from_le_bytes(raw)
+    } else {
+        u64::from_be_bytes(raw)
+    }
+}
+
+fn put_u32(elf: &Elf, buf: &mut [u8], at: usize, value: u32) {
+    match elf.little_endian {
+        true => buf[at..at + 4].copy_from_slice(&value.to_le_bytes()),
+        false => buf[at..at + 4].copy_from_slice(&value.to_be_bytes()),
+    }
+}
+
+/// Writes an address sized word, 32 bit in ELF32.
+fn put_word(elf: &Elf, buf: &mut [u8], at: usize, value: u64) {
+    match (elf.is_64, elf.little_endian) {
+        (true, true) => buf[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+        (true, false) => buf[at..at + 8].copy_from_slice(&value.to_be_bytes()),
+        (false, true) => buf[at..at + 4].copy_from_slice(&(value as u32).to_le_bytes()),
+        (false, false) => buf[at..at + 4].copy_from_slice(&(value as u32).to_be_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::elf::ElfMetadata;
+    use super::*;
+    use std::process::Command;
+
+    const SOURCE: &str = "
+        __attribute__((noinline)) static int local_fn(int x) { return x * 3; }
+        int exported_fn(int x) { return local_fn(x) + 1; }
+    ";
+
+    fn run(cmd: &str, args: &[&Path]) -> bool {
+        Command::new(cmd)
+            .args(args)
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    fn names(elf: &ElfMetadata) -> Vec<String> {
+        elf.table()
+            .symbols
+            .into_iter()
+            .filter_map(|s| s.name)
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compressed_symbols() {
+        let dir = std::env::temp_dir().join(format!("doctor-compression-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, lib, zstd, zdebug, mini) = (
+            dir.join("lib.c"),
+            dir.join("lib.so"),
+            dir.join("zstd.so"),
+            dir.join("zdebug.so"),
+            dir.join("mini"),
+        );
+        std::fs::write(&source, SOURCE).unwrap();
+        let built = run(
+            "gcc",
+            &[
+                "-g".as_ref(),
+                "-O1".as_ref(),
+                "-shared".as_ref(),
+                "-fPIC".as_ref(),
+                "-o".as_ref(),
+                &lib,
+                &source,
+            ],
+        ) && run(
+            "objcopy",
+            &["--compress-debug-sections=zstd".as_ref(), &lib, &zstd],
+        ) && run(
+            "objcopy",
+            &["--compress-debug-sections=zlib-gnu".as_ref(), &lib, &zdebug],
+        );
+        // MiniDebugInfo the way Fedora makes it
+        let built = built
+            && run("objcopy", &["--only-keep-debug".as_ref(), &lib, &mini])
+            && run(
+                "objcopy",
+                &["-S".as_ref(), "--keep-symbol=local_fn".as_ref(), &mini],
+            )
+            && run("xz", &[&mini])
+            && run("strip", &["--strip-all".as_ref(), &lib])
+            && run(
+                "objcopy",
+                &[
+                    format!("--add-section=.gnu_debugdata={}.xz", mini.display()).as_ref(),
+                    &lib,
+                ],
+            );
+        if !built {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        // the copy of zstd debug sections has them decompressed, without
+        // the code
+        let data = std::fs::read(&zstd).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        let mut copy = Vec::new();
+        decompressed_copy(&zstd, &elf, &data)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut copy)
+            .unwrap();
+        let copy = Elf::parse(&copy).unwrap();
+        let section = |name| {
+            copy.section_headers
+                .iter()
+                .find(|sh| copy.shdr_strtab.get_at(sh.sh_name) == Some(name))
+                .unwrap()
+        };
+        assert_eq!(section(".text").sh_type, SHT_NOBITS);
+        assert_eq!(section(".debug_info").sh_flags & SHF_COMPRESSED as u64, 0);
+
+        // zstd and old-style `.zdebug_*` debug sections still give source
+        // lines
+        let zdebug_data = std::fs::read(&zdebug).unwrap();
+        let zdebug_elf = Elf::parse(&zdebug_data).unwrap();
+        assert!(zdebug_elf
+            .section_headers
+            .iter()
+            .any(|sh| zdebug_elf.shdr_strtab.get_at(sh.sh_name) == Some(".zdebug_info")));
+        for path in [&zstd, &zdebug] {
+            let metadata = ElfMetadata::new(path.clone()).unwrap();
+            let table = metadata.table();
+            let sym = table
+                .symbols
+                .iter()
+                .find(|s| s.name.as_deref() == Some("exported_fn"))
+                .unwrap();
+            let found = metadata
+                .find_vaddr(sym.addr + table.pt_loads[0].p_vaddr)
+                .unwrap();
+            assert!(found.file.unwrap().ends_with("lib.c"), "{:?}", path);
+        }
+
+        // exports and the MiniDebugInfo functions of a stripped library
+        let stripped = ElfMetadata::new(lib.clone()).unwrap();
+        let names = names(&stripped);
+        assert!(names.iter().any(|n| n == "exported_fn"), "{:?}", names);
+        assert!(names.iter().any(|n| n == "local_fn"), "{:?}", names);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use goblin::elf::Elf;
+use memmap2::MmapOptions;
+
+use super::error::SymbolizerError;
+
+/// Where distributions install the `-dbg`/`-debuginfo` packages.
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Directories separate debug files are looked for in, `/usr/lib/debug` and
+/// the configured ones, each inside the target's root and the host's.
+#[derive(Clone, Debug, Default)]
+pub struct DebugDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl DebugDirs {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs }
+    }
+
+    /// Debug file of `dso`, a path inside `rootfs`, by the build-id path
+    /// first and then by `.gnu_debuglink`, like gdb.
+    pub fn find(&self, rootfs: &Path, dso: &Path, build_id: Option<&str>) -> Option<PathBuf> {
+        let mut roots = vec![rootfs];
+        if rootfs != Path::new("/") {
+            roots.push(Path::new("/"));
+        }
+        let dirs: Vec<&Path> = std::iter::once(Path::new(DEBUG_DIR))
+            .chain(self.dirs.iter().map(PathBuf::as_path))
+            .collect();
+
+        if let Some(id) = build_id.filter(|id| id.len() > 2) {
+            let file = format!(".build-id/{}/{}.debug", &id[..2], &id[2..]);
+            for root in &roots {
+                for dir in &dirs {
+                    let path = join(root, dir).join(&file);
+                    if path.is_file() {
+                        return Some(path);
+                    }
+                }
+            }
+        }
+
+        let binary = join(rootfs, dso);
+        let (name, crc) = match read_debuglink(&binary) {
+            Ok(link) => link?,
+            Err(e) => {
+                log::debug!("{}", e);
+                return None;
+            }
+        };
+        let parent = dso.parent()?;
+        for root in &roots {
+            let dir = join(root, parent);
+            let candidates = [dir.join(&name), dir.join(".debug").join(&name)]
+                .into_iter()
+                .chain(
+                    dirs.iter()
+                        .map(|d| join(&join(root, d), parent).join(&name)),
+                );
+            for path in candidates {
+                if path != binary && path.is_file() && file_crc(&path).ok() == Some(crc) {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// `path`, absolute, inside `root`.
+fn join(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// File name and CRC32 of the debug file in `.gnu_debuglink`.
+fn read_debuglink(path: &Path) -> Result<Option<(String, u32)>, SymbolizerError> {
+    let file =
+        File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+    let data = unsafe { MmapOptions::new().map(&file) }
+        .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?;
+    let Ok(elf) = Elf::parse(&data) else {
+        return Ok(None);
+    };
+    let Some(section) = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".gnu_debuglink"))
+    else {
+        return Ok(None);
+    };
+    let Some(link) = section.file_range().and_then(|range| data.get(range)) else {
+        return Ok(None);
+    };
+
+    // the name, NUL terminated and padded to 4 bytes, then the CRC
+    let Some(len) = link.iter().position(|b| *b == 0) else {
+        return Ok(None);
+    };
+    let at = (len + 4) & !3;
+    let Some(crc) = link.get(at..at + 4) else {
+        return Ok(None);
+    };
+    let crc = crc.try_into().unwrap();
+    let crc = if elf.little_endian {
+        u32::from_le_bytes(crc)
+    } else {
+        u32::from_be_bytes(crc)
+    };
+    Ok(Some((
+        String::from_utf8_lossy(&link[..len]).into_owned(),
+        crc,
+    )))
+}
+
+fn file_crc(path: &Path) -> std::io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finalize()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_find_debug_file() {
+        let root = std::env::temp_dir().join(format!("doctor-debuginfo-{}", std::process::id()));
+        let bin = root.join("opt/app/bin");
+        std::fs::create_dir_all(bin.join(".debug")).unwrap();
+        let exe = bin.join("app");
+        std::fs::copy(std::env::current_exe().unwrap(), &exe).unwrap();
+        let debug = bin.join(".debug/app.debug");
+        std::fs::write(&debug, b"debug info").unwrap();
+        let linked = Command::new("objcopy")
+            .arg(format!("--add-gnu-debuglink={}", debug.display()))
+            .arg(&exe)
+            .status();
+        if !linked.map(|s| s.success()).unwrap_or(false) {
+            std::fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+
+        let dirs = DebugDirs::new(vec!["/srv/debug".into()]);
+        let dso = Path::new("/opt/app/bin/app");
+        assert_eq!(dirs.find(&root, dso, None), Some(debug.clone()));
+        // a debug file of another build
+        std::fs::write(&debug, b"other debug info").unwrap();
+        assert_eq!(dirs.find(&root, dso, None), None);
+
+        // the build-id path wins, in the configured directories too
+        let by_id = root.join("srv/debug/.build-id/ab/cdef.debug");
+        std::fs::create_dir_all(by_id.parent().unwrap()).unwrap();
+        std::fs::write(&by_id, b"").unwrap();
+        assert_eq!(dirs.find(&root, dso, Some("abcdef")), Some(by_id));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
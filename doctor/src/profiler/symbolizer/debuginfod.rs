@@ -0,0 +1,385 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use super::{elf::ElfMetadata, error::SymbolizerError};
+
+/// Space separated servers, as for elfutils' debuginfod-find.
+pub const DEBUGINFOD_URLS: &str = "DEBUGINFOD_URLS";
+/// Seconds without progress before a request is given up.
+const DEBUGINFOD_TIMEOUT: &str = "DEBUGINFOD_TIMEOUT";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(90);
+/// Seconds a download may take in all.
+const DEBUGINFOD_MAXTIME: &str = "DEBUGINFOD_MAXTIME";
+const DEFAULT_MAXTIME: Duration = Duration::from_secs(120);
+/// How long a build-id no server has is not asked for again.
+const MISS_TTL: Duration = Duration::from_secs(600);
+
+/// What a debuginfod server has of a build.
+#[derive(Clone, Copy, Debug)]
+pub enum Artifact {
+    Debuginfo,
+    Executable,
+}
+
+impl Artifact {
+    fn name(&self) -> &'static str {
+        match self {
+            Artifact::Debuginfo => "debuginfo",
+            Artifact::Executable => "executable",
+        }
+    }
+}
+
+/// What debuginfod has of a build.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fetched {
+    /// its debug info, else its executable, in the cache
+    Found(PathBuf),
+    /// being downloaded in the background
+    Pending,
+    Missing,
+}
+
+/// Client of debuginfod servers, downloads are kept in `cache` as
+/// `<build-id>/<artifact>`, builds no server has as `<build-id>/<artifact>.miss`.
+/// Without `wait`, builds are downloaded in the background and symbolization
+/// goes on without them meanwhile.
+#[derive(Clone)]
+pub struct Debuginfod {
+    urls: Arc<Vec<String>>,
+    cache: PathBuf,
+    client: reqwest::Client,
+    maxtime: Duration,
+    wait: bool,
+    downloads: Arc<Mutex<Downloads>>,
+}
+
+/// Builds downloaded in the background.
+#[derive(Default)]
+struct Downloads {
+    running: HashSet<String>,
+    // in the cache since the last `finished`
+    done: Vec<String>,
+}
+
+impl Debuginfod {
+    pub fn new(
+        urls: Vec<String>,
+        cache: PathBuf,
+        wait: bool,
+    ) -> Result<Debuginfod, SymbolizerError> {
+        let timeout = env_secs(DEBUGINFOD_TIMEOUT).unwrap_or(DEFAULT_TIMEOUT);
+        let client = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .build()
+            .map_err(|e| SymbolizerError::DebuginfodFailed("client".into(), e))?;
+        Ok(Self {
+            urls: Arc::new(
+                urls.into_iter()
+                    .map(|url| url.trim_end_matches('/').to_owned())
+                    .collect(),
+            ),
+            cache,
+            client,
+            maxtime: env_secs(DEBUGINFOD_MAXTIME).unwrap_or(DEFAULT_MAXTIME),
+            wait,
+            downloads: Arc::default(),
+        })
+    }
+
+    /// Servers in `DEBUGINFOD_URLS`.
+    pub fn env_urls() -> Vec<String> {
+        std::env::var(DEBUGINFOD_URLS)
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// The debug info of `build_id`, else its unstripped executable, from the
+    /// cache or the first server that has it.
+    pub fn fetch(&self, build_id: &str) -> Fetched {
+        if !build_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Fetched::Missing;
+        }
+        if let Some(fetched) = self.cached(build_id) {
+            return fetched;
+        }
+        if self.wait {
+            let handle = tokio::runtime::Handle::current();
+            tokio::task::block_in_place(|| handle.block_on(self.download_build(build_id)));
+            return self.cached(build_id).unwrap_or(Fetched::Missing);
+        }
+
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads.running.insert(build_id.into()) {
+            let (debuginfod, build_id) = (self.clone(), build_id.to_owned());
+            tokio::spawn(async move {
+                // a failed build is asked for again once loaded again
+                let found = debuginfod.download_build(&build_id).await;
+                let mut downloads = debuginfod.downloads.lock().unwrap();
+                downloads.running.remove(&build_id);
+                if found {
+                    downloads.done.push(build_id);
+                }
+            });
+        }
+        Fetched::Pending
+    }
+
+    /// Whether `build_id` is being downloaded in the background.
+    pub fn downloading(&self, build_id: &str) -> bool {
+        self.downloads.lock().unwrap().running.contains(build_id)
+    }
+
+    /// Builds downloaded in the background since the last call.
+    pub fn finished(&self) -> Vec<String> {
+        std::mem::take(&mut self.downloads.lock().unwrap().done)
+    }
+
+    /// What the cache has of the build, None when the servers are to be
+    /// asked.
+    fn cached(&self, build_id: &str) -> Option<Fetched> {
+        for artifact in [Artifact::Debuginfo, Artifact::Executable] {
+            let path = self.path(build_id, artifact);
+            if path.is_file() {
+                return Some(Fetched::Found(path));
+            }
+            if !self.missed(build_id, artifact) {
+                return None;
+            }
+        }
+        Some(Fetched::Missing)
+    }
+
+    /// Asks the servers for the debug info of the build, then for its
+    /// executable, true once one is in the cache.
+    async fn download_build(&self, build_id: &str) -> bool {
+        for artifact in [Artifact::Debuginfo, Artifact::Executable] {
+            if self.path(build_id, artifact).is_file()
+                || !self.missed(build_id, artifact)
+                    && self.download_artifact(build_id, artifact).await
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Downloads `artifact` of `build_id` from the first server that has it,
+    /// or marks it missed when none has.
+    async fn download_artifact(&self, build_id: &str, artifact: Artifact) -> bool {
+        let path = self.path(build_id, artifact);
+        // asked for again next time when a server failed
+        let mut not_found = true;
+        for url in self.urls.iter() {
+            let url = format!("{}/buildid/{}/{}", url, build_id, artifact.name());
+            match self.download(&url, &path, build_id).await {
+                Ok(true) => {
+                    log::info!("fetched {}", url);
+                    return true;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    log::warn!("debuginfod: {}", e);
+                    not_found = false;
+                }
+            }
+        }
+        if not_found {
+            let miss = self.miss_path(build_id, artifact);
+            if let Err(e) =
+                fs::create_dir_all(self.cache.join(build_id)).and_then(|_| File::create(&miss))
+            {
+                log::debug!("debuginfod miss {:?}: {}", miss, e);
+            }
+        }
+        false
+    }
+
+    /// Downloads `url` to `path` in at most `maxtime`, false when the server
+    /// does not have it. Anything else than an ELF of `build_id`, such as an
+    /// error page of a proxy, is a failure of the server.
+    async fn download(
+        &self,
+        url: &str,
+        path: &Path,
+        build_id: &str,
+    ) -> Result<bool, SymbolizerError> {
+        let request_failed = |e| SymbolizerError::DebuginfodFailed(url.into(), e);
+        let io_failed = |e| SymbolizerError::DebuginfodIoFailed(path.into(), e);
+        // written aside first, other runs read the cache meanwhile
+        let partial = path.with_extension(format!("{}.part", std::process::id()));
+
+        let download = async {
+            let response = self.client.get(url).send().await.map_err(request_failed)?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            let mut response = response.error_for_status().map_err(request_failed)?;
+            fs::create_dir_all(path.parent().unwrap()).map_err(io_failed)?;
+            let mut file = File::create(&partial).map_err(io_failed)?;
+            while let Some(chunk) = response.chunk().await.map_err(request_failed)? {
+                file.write_all(&chunk).map_err(io_failed)?;
+            }
+            drop(file);
+            if ElfMetadata::read_build_id(&partial)?.as_deref() != Some(build_id) {
+                return Err(SymbolizerError::DebuginfodInvalid(url.into()));
+            }
+            fs::rename(&partial, path).map_err(io_failed)?;
+            Ok(true)
+        };
+        tokio::time::timeout(self.maxtime, download)
+            .await
+            .unwrap_or_else(|_| Err(SymbolizerError::DebuginfodTimeout(url.into())))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&partial);
+            })
+    }
+
+    fn path(&self, build_id: &str, artifact: Artifact) -> PathBuf {
+        self.cache.join(build_id).join(artifact.name())
+    }
+
+    fn miss_path(&self, build_id: &str, artifact: Artifact) -> PathBuf {
+        self.cache
+            .join(build_id)
+            .join(format!("{}.miss", artifact.name()))
+    }
+
+    /// Whether no server had `artifact` of the build lately.
+    fn missed(&self, build_id: &str, artifact: Artifact) -> bool {
+        fs::metadata(self.miss_path(build_id, artifact))
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .is_some_and(|age| age < MISS_TTL)
+    }
+}
+
+fn env_secs(name: &str) -> Option<Duration> {
+    std::env::var(name)
+        .ok()
+        .and_then(|t| t.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debuginfod() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        let exe = std::env::current_exe().unwrap();
+        let Some(id) = ElfMetadata::read_build_id(&exe).unwrap() else {
+            return;
+        };
+        let elf = fs::read(&exe).unwrap();
+        let (build, body) = (format!("GET /buildid/{}/debuginfo ", id), elf.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut line).unwrap();
+                // the rest of the header
+                while reader.read_line(&mut String::new()).unwrap() > 2 {}
+                served.fetch_add(1, Ordering::SeqCst);
+                let response = if line.starts_with(&build) {
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(header.as_bytes()).unwrap();
+                    stream.write_all(&body).unwrap();
+                    continue;
+                } else if line.starts_with("GET /buildid/abcd/debuginfo ") {
+                    // an error page of a proxy
+                    "HTTP/1.1 200 OK\r\ncontent-length: 6\r\nconnection: close\r\n\r\n<html>"
+                } else if line.starts_with("GET /buildid/ef01/") {
+                    "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                } else if line.starts_with("GET /buildid/2345/debuginfo ") {
+                    // stalls past the deadline
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nDW")
+                        .unwrap();
+                    std::thread::sleep(Duration::from_secs(2));
+                    "ARF"
+                } else {
+                    "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                };
+                // the client is gone after a stall
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        let cache = std::env::temp_dir().join(format!("doctor-debuginfod-{}", std::process::id()));
+        let urls = vec![format!("{}/", url)];
+        let mut debuginfod = Debuginfod::new(urls.clone(), cache.join("wait"), true).unwrap();
+        debuginfod.maxtime = Duration::from_secs(1);
+        let path = cache.join("wait").join(&id).join("debuginfo");
+        assert_eq!(debuginfod.fetch(&id), Fetched::Found(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), elf);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // the executable after the debug info
+        assert_eq!(debuginfod.fetch("0000"), Fetched::Missing);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // hits and misses are answered from the cache
+        assert_eq!(debuginfod.fetch(&id), Fetched::Found(path));
+        assert_eq!(debuginfod.fetch("0000"), Fetched::Missing);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // failed servers, other content than the ELF and stalled downloads
+        // are not misses
+        assert_eq!(debuginfod.fetch("ef01"), Fetched::Missing);
+        assert_eq!(debuginfod.fetch("ef01"), Fetched::Missing);
+        assert_eq!(requests.load(Ordering::SeqCst), 7);
+        for build in ["abcd", "2345"] {
+            assert_eq!(debuginfod.fetch(build), Fetched::Missing);
+            let dir = cache.join("wait").join(build);
+            assert!(!dir.join("debuginfo").exists());
+            assert!(!dir.join("debuginfo.miss").exists());
+            assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".part")));
+        }
+
+        // without waiting, the build is downloaded in the background
+        let debuginfod = Debuginfod::new(urls, cache.join("background"), false).unwrap();
+        assert_eq!(debuginfod.fetch(&id), Fetched::Pending);
+        assert_eq!(debuginfod.fetch(&id), Fetched::Pending);
+        for _ in 0..100 {
+            if !debuginfod.downloading(&id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(debuginfod.finished(), vec![id.clone()]);
+        assert!(debuginfod.finished().is_empty());
+        assert!(matches!(debuginfod.fetch(&id), Fetched::Found(_)));
+
+        fs::remove_dir_all(&cache).unwrap();
+    }
+}
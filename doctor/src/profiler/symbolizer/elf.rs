@@ -1,33 +1,63 @@
 use std::{
-    collections::BTreeSet,
-    fs::{metadata, File},
-    os::linux::fs::MetadataExt,
-    path::PathBuf,
+    collections::{BTreeSet, HashSet},
+    fmt,
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 use symbolic::{
     common::Name,
     demangle::{Demangle, DemangleOptions},
 };
 
-use super::{error::SymbolizerError, symbol::Symbol};
+use super::{
+    compression,
+    error::SymbolizerError,
+    golang::GoPclntab,
+    symbol::{InlinedFrame, Symbol},
+    symbol_cache::{SourceFrames, SymbolFrames, SymbolTable},
+};
 
-use goblin::elf::{program_header::PT_LOAD, Elf, ProgramHeader};
+use goblin::{
+    container::{Container, Ctx, Endian},
+    elf::{note::NT_GNU_BUILD_ID, program_header::PT_LOAD, Elf, ProgramHeader, SectionHeader},
+};
 use log::debug;
 use memmap2::MmapOptions;
-use wholesym::{SymbolManager, SymbolManagerConfig};
+use wholesym::{FramesLookupResult, LookupAddress, SymbolManager, SymbolManagerConfig, SymbolMap};
+
+/// Symbol table, symbol map and build-id of an ELF.
+type LoadedElf = (SymbolTable, SymbolMap, Option<String>);
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ElfMetadata {
     path: PathBuf,
     debug_info: BTreeSet<Symbol>,
     pt_loads: Vec<ProgramHeader>,
-    inode: u64,
+    build_id: Option<String>,
+    dwarf: Option<PathBuf>,
+    // source lines and inlined functions, looked up per address, loaded when
+    // first needed for ELFs from the symbol cache
+    symbol_map: Arc<OnceLock<Option<SymbolMap>>>,
+    // source frames of addresses looked up before, in the symbol cache
+    frames: Option<SymbolFrames>,
+    golang: Option<Arc<GoPclntab>>,
+}
+
+impl fmt::Debug for ElfMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ElfMetadata")
+            .field("path", &self.path)
+            .field("debug_info", &self.debug_info.len())
+            .field("build_id", &self.build_id)
+            .field("golang", &self.golang.is_some())
+            .finish()
+    }
 }
 
 impl ElfMetadata {
-    pub fn load_sym_from_elf(
-        path: &PathBuf,
-    ) -> Result<(BTreeSet<Symbol>, Vec<ProgramHeader>), SymbolizerError> {
+    pub fn load_sym_from_elf(path: &PathBuf) -> Result<LoadedElf, SymbolizerError> {
         let file = File::open(path.clone())
             .map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.clone(), e))?;
         let mmap = unsafe {
@@ -36,7 +66,9 @@ impl ElfMetadata {
                 .map_err(|e| SymbolizerError::MMapIOFailed(path.clone(), e))?
         };
 
-        let elf = Elf::parse(&mmap).expect("Failed to parse ELF file");
+        let elf =
+            Elf::parse(&mmap).map_err(|e| SymbolizerError::ParseElfFailed(path.clone(), e))?;
+        let dwarf = has_dwarf(&elf).then(|| path.clone());
 
         let pt_loads = elf
             .program_headers
@@ -45,29 +77,38 @@ impl ElfMetadata {
             .filter(|h| h.p_type == PT_LOAD)
             .collect::<Vec<ProgramHeader>>();
 
-        let mut debug_info = BTreeSet::new();
-        tokio::task::block_in_place(|| {
-            let symbol_manager = SymbolManager::with_config(SymbolManagerConfig::default());
-            let symbol_map_f = symbol_manager.load_symbol_map_for_binary_at_path(path, None);
-            let symbol_map = tokio::runtime::Handle::current()
-                .block_on(symbol_map_f)
-                .unwrap(); // TODO: deal with
-
-            debug!("eso path: {:?}, build {}", &path, symbol_map.debug_id());
-            let opt = DemangleOptions::name_only();
-            symbol_map.iter_symbols().for_each(|s| {
-                let demangled = Name::from(s.1).try_demangle(opt).to_string();
-
-                debug_info.insert(Symbol {
-                    addr: s.0 as u64,
-                    name: Some(demangled),
-                });
-            });
+        let symbol_map = load_symbol_map(path, &elf, &mmap)?;
+        debug!("eso path: {:?}, build {}", &path, symbol_map.debug_id());
+        let mut debug_info: BTreeSet<Symbol> = symbol_map
+            .iter_symbols()
+            .map(|s| Symbol::new(s.0 as u64, Some(demangle(&s.1))))
+            .collect();
 
-            symbol_map.debug_id()
-        });
+        // the symbol map reads the MiniDebugInfo in place of the ELF, the
+        // functions only `.dynsym` has are added back
+        if let Some(base) = pt_loads.first().map(|h| h.p_vaddr) {
+            let mini = compression::mini_debuginfo(&elf, &mmap);
+            let mini = mini.as_deref().and_then(|mini| Elf::parse(mini).ok());
+            let known: HashSet<u64> = debug_info.iter().map(|s| s.addr).collect();
+            let mut extra = BTreeSet::new();
+            for (vaddr, name) in function_symbols(&elf)
+                .chain(mini.iter().flat_map(function_symbols))
+                .filter(|(vaddr, _)| *vaddr >= base)
+            {
+                if !known.contains(&(vaddr - base)) {
+                    extra.insert(Symbol::new(vaddr - base, Some(demangle(name))));
+                }
+            }
+            debug_info.extend(extra);
+        }
 
-        Ok((debug_info, pt_loads))
+        let table = SymbolTable {
+            pt_loads,
+            symbols: debug_info,
+            dwarf,
+            golang: GoPclntab::locate(&elf),
+        };
+        Ok((table, symbol_map, build_id(&elf, &mmap)))
     }
     pub fn load_sym_from_dwarf(path: &PathBuf) -> Result<BTreeSet<Symbol>, SymbolizerError> {
         let mut debug_info = BTreeSet::new();
@@ -76,67 +117,609 @@ impl ElfMetadata {
             let symbol_map_f = symbol_manager.load_symbol_map_for_binary_at_path(path, None);
             let symbol_map = tokio::runtime::Handle::current()
                 .block_on(symbol_map_f)
-                .unwrap();
+                .map_err(|e| SymbolizerError::LoadSymbolMapFailed(path.clone(), e))?;
 
             debug!("eso path: {:?}, build {}", &path, symbol_map.debug_id());
             symbol_map.iter_symbols().for_each(|s| {
-                debug_info.insert(Symbol {
-                    addr: s.0 as u64,
-                    name: Some(s.1.to_string()),
-                });
+                debug_info.insert(Symbol::new(s.0 as u64, Some(s.1.to_string())));
             });
-        });
+            Ok::<_, SymbolizerError>(())
+        })?;
 
         Ok(debug_info)
     }
 
-    pub fn get_inode(path: &PathBuf) -> Result<u64, SymbolizerError> {
-        let f_meta = metadata(path).map_err(SymbolizerError::GetInodeFailed)?;
-        Ok(f_meta.st_ino())
+    /// Whether the ELF has DWARF itself, without a debug file.
+    pub fn read_has_dwarf(path: &Path) -> Result<bool, SymbolizerError> {
+        let file =
+            File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+        let mmap = unsafe {
+            MmapOptions::new()
+                .map(&file)
+                .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?
+        };
+        Ok(Elf::parse(&mmap).is_ok_and(|elf| has_dwarf(&elf)))
+    }
+
+    /// GNU build-id note of the ELF, hex encoded, read without parsing its
+    /// symbols.
+    pub fn read_build_id(path: &Path) -> Result<Option<String>, SymbolizerError> {
+        let file =
+            File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+        let mmap = unsafe {
+            MmapOptions::new()
+                .map(&file)
+                .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?
+        };
+        Ok(parse_headers(&mmap).and_then(|elf| build_id(&elf, &mmap)))
     }
 
     pub fn new(path: PathBuf) -> Result<ElfMetadata, SymbolizerError> {
-        let (debug_info, pt_loads) = ElfMetadata::load_sym_from_elf(&path)?;
-        let inode = Self::get_inode(&path)?;
-        Ok(Self {
+        Self::with_debug_file(path, None)
+    }
+
+    /// An ELF with the symbols and DWARF of its separate debug file, the
+    /// stripped ELF keeps little more than `.dynsym`.
+    pub fn with_debug_file(
+        path: PathBuf,
+        debug: Option<PathBuf>,
+    ) -> Result<ElfMetadata, SymbolizerError> {
+        let (mut table, mut symbol_map, build_id) = ElfMetadata::load_sym_from_elf(&path)?;
+        match debug.map(|debug| (ElfMetadata::load_sym_from_elf(&debug), debug)) {
+            Some((Ok((debug_table, debug_map, _)), debug)) => {
+                debug!("debug file of {:?}: {:?}", path, debug);
+                let addrs: HashSet<u64> = debug_table.symbols.iter().map(|s| s.addr).collect();
+                table.symbols.retain(|s| !addrs.contains(&s.addr));
+                table.symbols.extend(debug_table.symbols);
+                if debug_table.dwarf.is_some() {
+                    table.dwarf = debug_table.dwarf;
+                    symbol_map = debug_map;
+                }
+            }
+            Some((Err(e), debug)) => debug!("debug file {:?}: {}", debug, e),
+            None => {}
+        }
+        // the same lookups as from the symbol cache
+        let symbol_map = OnceLock::from(table.dwarf.is_some().then_some(symbol_map));
+        Ok(Self::with_symbol_map(path, build_id, table, symbol_map))
+    }
+
+    /// An ELF from its table in the symbol cache, its DWARF is read when a
+    /// source line not in its `SymbolFrames` is first looked up.
+    pub fn from_table(path: PathBuf, build_id: Option<String>, table: SymbolTable) -> ElfMetadata {
+        let symbol_map = OnceLock::new();
+        if table.dwarf.is_none() {
+            let _ = symbol_map.set(None);
+        }
+        Self::with_symbol_map(path, build_id, table, symbol_map)
+    }
+
+    fn with_symbol_map(
+        path: PathBuf,
+        build_id: Option<String>,
+        table: SymbolTable,
+        symbol_map: OnceLock<Option<SymbolMap>>,
+    ) -> ElfMetadata {
+        let golang = table
+            .golang
+            .and_then(|location| match GoPclntab::load(&path, location) {
+                Ok(pclntab) => Some(Arc::new(pclntab)),
+                Err(e) => {
+                    debug!("{:?}: {}", path, e);
+                    None
+                }
+            });
+        Self {
             path,
-            debug_info,
-            pt_loads,
-            inode,
-        })
+            debug_info: table.symbols,
+            pt_loads: table.pt_loads,
+            build_id,
+            dwarf: table.dwarf,
+            symbol_map: Arc::new(symbol_map),
+            frames: None,
+            golang,
+        }
+    }
+
+    /// Looks source frames up in `frames` before the DWARF, and keeps there
+    /// the ones read from it.
+    pub fn keep_frames(&mut self, frames: SymbolFrames) {
+        self.frames = Some(frames);
+    }
+
+    /// What the symbol cache keeps of the ELF.
+    pub fn table(&self) -> SymbolTable {
+        SymbolTable {
+            pt_loads: self.pt_loads.clone(),
+            symbols: self.debug_info.clone(),
+            dwarf: self.dwarf.clone(),
+            golang: self
+                .golang
+                .as_ref()
+                .map(|pclntab| pclntab.location().clone()),
+        }
     }
 
-    // translate file offset -> relative offset
+    // translate file offset -> relative offset, segments of lld linked ELFs
+    // are not all as far from their file offset
     fn translate(&self, file_offset: u64) -> Option<u64> {
-        if !self.pt_loads.is_empty() {
-            Some(file_offset - self.pt_loads[0].p_offset)
-        } else {
-            None
+        let first = self.pt_loads.first()?;
+        match self.vaddr(file_offset) {
+            Some(vaddr) => Some(vaddr - first.p_vaddr),
+            None => Some(file_offset - first.p_offset),
         }
     }
 
-    pub fn find_symbol(&self, offset: u64) -> Result<Symbol, SymbolizerError> {
-        match self.translate(offset) {
-            Some(relative_offset) => {
-                let target = Symbol {
-                    addr: relative_offset,
-                    name: None,
-                };
-                match self.debug_info.range(..target).next_back() {
-                    Some(sym) => Ok(sym.clone()),
-                    None => Err(SymbolizerError::SymbolNotFound(
+    // translate file offset -> virtual address
+    fn vaddr(&self, file_offset: u64) -> Option<u64> {
+        self.pt_loads
+            .iter()
+            .find(|h| (h.p_offset..h.p_offset + h.p_filesz).contains(&file_offset))
+            .map(|h| file_offset - h.p_offset + h.p_vaddr)
+    }
+
+    /// Symbols at the file offsets, in their order: the offsets are sorted
+    /// and the symbol table walked once.
+    pub fn find_symbols(&self, offsets: &[u64]) -> Vec<Result<Symbol, SymbolizerError>> {
+        let mut found: Vec<Option<Result<Symbol, SymbolizerError>>> =
+            offsets.iter().map(|_| None).collect();
+        let mut relative = Vec::with_capacity(offsets.len());
+        for (i, &offset) in offsets.iter().enumerate() {
+            // stripped Go binaries only name their functions in the pclntab,
+            // cgo code is not in it
+            if let Some(sym) = self
+                .golang
+                .as_ref()
+                .and_then(|pclntab| pclntab.find(self.vaddr(offset)?))
+            {
+                found[i] = Some(Ok(sym));
+                continue;
+            }
+            match self.translate(offset) {
+                Some(relative_offset) => relative.push((relative_offset, i)),
+                None => {
+                    found[i] = Some(Err(SymbolizerError::TranslateVirtOffsetFailed(
                         self.path.clone(),
-                        relative_offset,
-                    )),
+                        offset,
+                    )))
                 }
             }
+        }
+        relative.sort_unstable();
+
+        // the last symbol before each offset, as in `find_relative`, looked
+        // for from the symbol of the offset before
+        let mut current: Option<&Symbol> = None;
+        for (relative_offset, i) in relative {
+            let target = Symbol::new(relative_offset, None);
+            current = match current {
+                Some(before) => self.debug_info.range(before..&target).next_back(),
+                None => self.debug_info.range(..&target).next_back(),
+            };
+            found[i] = Some(match current {
+                Some(sym) => {
+                    let mut sym = sym.clone();
+                    self.add_debug_frames(&mut sym, relative_offset);
+                    Ok(sym)
+                }
+                None => Err(SymbolizerError::SymbolNotFound(
+                    self.path.clone(),
+                    relative_offset,
+                )),
+            });
+        }
+        found.into_iter().flatten().collect()
+    }
+
+    /// Like `find_symbols`, for a virtual address of the ELF, the way kernel
+    /// addresses map to vmlinux.
+    pub fn find_vaddr(&self, vaddr: u64) -> Result<Symbol, SymbolizerError> {
+        let in_segment = self
+            .pt_loads
+            .iter()
+            .any(|h| (h.p_vaddr..h.p_vaddr + h.p_memsz).contains(&vaddr));
+        match self.pt_loads.first() {
+            // relative addresses start at the first segment
+            Some(first) if in_segment => self.find_relative(vaddr - first.p_vaddr),
+            _ => Err(SymbolizerError::TranslateVirtOffsetFailed(
+                self.path.clone(),
+                vaddr,
+            )),
+        }
+    }
 
-            None => Err(SymbolizerError::TranslateVirtOffsetFailed(
+    fn find_relative(&self, relative_offset: u64) -> Result<Symbol, SymbolizerError> {
+        let target = Symbol::new(relative_offset, None);
+        match self.debug_info.range(..target).next_back() {
+            Some(sym) => {
+                let mut sym = sym.clone();
+                self.add_debug_frames(&mut sym, relative_offset);
+                Ok(sym)
+            }
+            None => Err(SymbolizerError::SymbolNotFound(
                 self.path.clone(),
-                offset,
+                relative_offset,
             )),
         }
     }
+
+    /// Fills in the source position of `relative_offset` and the functions inlined at
+    /// it, when the ELF has DWARF for it.
+    fn add_debug_frames(&self, sym: &mut Symbol, relative_offset: u64) {
+        if self.dwarf.is_none() {
+            return;
+        }
+        let cached = self.frames.as_ref().and_then(|frames| {
+            frames
+                .get(relative_offset)
+                .map_err(|e| debug!("symbol cache: {}", e))
+                .ok()
+                .flatten()
+        });
+        let frames = match cached {
+            Some(frames) => frames,
+            None => {
+                let Some(frames) = self.read_debug_frames(relative_offset) else {
+                    return;
+                };
+                if let Some(Err(e)) = self
+                    .frames
+                    .as_ref()
+                    .map(|cache| cache.insert(relative_offset, &frames))
+                {
+                    debug!("symbol cache: {}", e);
+                }
+                frames
+            }
+        };
+        sym.file = frames.file;
+        sym.line = frames.line;
+        sym.inlines = frames.inlines;
+    }
+
+    /// Source frames of `relative_offset` in the DWARF, None when it cannot
+    /// be read.
+    fn read_debug_frames(&self, relative_offset: u64) -> Option<SourceFrames> {
+        let address = u32::try_from(relative_offset).ok()?;
+        let symbol_map = self.symbol_map.get_or_init(|| {
+            let dwarf = self.dwarf.as_ref()?;
+            read_symbol_map(dwarf)
+                .map_err(|e| debug!("load dwarf of {:?}: {}", dwarf, e))
+                .ok()
+        });
+        let frames = match symbol_map
+            .as_ref()?
+            .lookup_sync(LookupAddress::Relative(address))
+        {
+            Some(info) => match info.frames {
+                Some(FramesLookupResult::Available(frames)) => frames,
+                // split dwarf is not looked up
+                _ => return Some(SourceFrames::default()),
+            },
+            None => return Some(SourceFrames::default()),
+        };
+        // innermost first, the last frame is the function of the symbol
+        let Some((outer, inlines)) = frames.split_last() else {
+            return Some(SourceFrames::default());
+        };
+        Some(SourceFrames {
+            file: outer.file_path.as_ref().map(|p| p.raw_path().to_owned()),
+            line: outer.line_number,
+            inlines: inlines
+                .iter()
+                .map(|frame| InlinedFrame {
+                    name: frame
+                        .function
+                        .as_deref()
+                        .map(demangle)
+                        .unwrap_or_else(|| "unknown".into()),
+                    file: frame.file_path.as_ref().map(|p| p.raw_path().to_owned()),
+                    line: frame.line_number,
+                })
+                .collect(),
+        })
+    }
+}
+
+fn read_symbol_map(path: &Path) -> Result<SymbolMap, SymbolizerError> {
+    let file =
+        File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+    let mmap = unsafe {
+        MmapOptions::new()
+            .map(&file)
+            .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?
+    };
+    match Elf::parse(&mmap) {
+        Ok(elf) => load_symbol_map(path, &elf, &mmap),
+        Err(_) => load_symbol_map_at(path),
+    }
+}
+
+/// Symbol map of the ELF, read from a copy with its debug sections
+/// decompressed when the symbol map cannot read them.
+fn load_symbol_map(path: &Path, elf: &Elf, data: &[u8]) -> Result<SymbolMap, SymbolizerError> {
+    match compression::decompressed_copy(path, elf, data)? {
+        Some(copy) => load_symbol_map_at(Path::new(&format!("/proc/self/fd/{}", copy.as_raw_fd()))),
+        None => load_symbol_map_at(path),
+    }
+}
+
+fn load_symbol_map_at(path: &Path) -> Result<SymbolMap, SymbolizerError> {
+    tokio::task::block_in_place(|| {
+        let symbol_manager = SymbolManager::with_config(SymbolManagerConfig::default());
+        tokio::runtime::Handle::current()
+            .block_on(symbol_manager.load_symbol_map_for_binary_at_path(path, None))
+    })
+    .map_err(|e| SymbolizerError::LoadSymbolMapFailed(path.into(), e))
+}
+
+/// Defined functions of `.symtab` and `.dynsym`, by virtual address.
+fn function_symbols<'a>(elf: &'a Elf) -> impl Iterator<Item = (u64, &'a str)> {
+    let symtab = elf.syms.iter().map(|sym| (sym, &elf.strtab));
+    let dynsym = elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab));
+    symtab
+        .chain(dynsym)
+        .filter(|(sym, _)| sym.is_function() && sym.st_value != 0 && sym.st_shndx != 0)
+        .filter_map(|(sym, strtab)| {
+            let name = strtab.get_at(sym.st_name)?;
+            (!name.is_empty()).then_some((sym.st_value, name))
+        })
+}
+
+/// The ELF with only its program and section headers parsed.
+fn parse_headers(data: &[u8]) -> Option<Elf<'_>> {
+    let header = Elf::parse_header(data).ok()?;
+    let mut elf = Elf::lazy_parse(header).ok()?;
+    let container = match elf.is_64 {
+        true => Container::Big,
+        false => Container::Little,
+    };
+    let ctx = Ctx::new(container, Endian::from(elf.little_endian));
+    elf.program_headers =
+        ProgramHeader::parse(data, header.e_phoff as usize, header.e_phnum as usize, ctx).ok()?;
+    elf.section_headers =
+        SectionHeader::parse(data, header.e_shoff as usize, header.e_shnum as usize, ctx).ok()?;
+    Some(elf)
+}
+
+/// GNU build-id note, hex encoded, from the note segments or sections.
+fn build_id(elf: &Elf, data: &[u8]) -> Option<String> {
+    let mut notes = elf
+        .iter_note_headers(data)
+        .into_iter()
+        .chain(elf.iter_note_sections(data, None))
+        .flatten()
+        .flatten();
+    notes
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU" && !note.desc.is_empty())
+        .map(|note| note.desc.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn has_dwarf(elf: &Elf) -> bool {
+    elf.section_headers.iter().any(|sh| {
+        matches!(
+            elf.shdr_strtab.get_at(sh.sh_name),
+            Some(".debug_info" | ".zdebug_info")
+        )
+    })
+}
+
+fn demangle(name: &str) -> String {
+    Name::from(name)
+        .try_demangle(DemangleOptions::name_only())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::symbol_cache::SymbolCache;
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find_symbol_source() {
+        let elf = ElfMetadata::new(std::env::current_exe().unwrap()).unwrap();
+        let sym = elf
+            .debug_info
+            .iter()
+            .find(|s| s.name.as_deref() == Some("doctor::profiler::symbolizer::elf::demangle"))
+            .unwrap();
+
+        let vaddr = sym.addr + elf.pt_loads[0].p_vaddr;
+        let segment = elf
+            .pt_loads
+            .iter()
+            .find(|h| (h.p_vaddr..h.p_vaddr + h.p_filesz).contains(&vaddr))
+            .unwrap();
+        let offset = vaddr - segment.p_vaddr + segment.p_offset + 1;
+        let found = elf.find_symbols(&[offset]).remove(0).unwrap();
+        assert_eq!(found.name, sym.name);
+        assert!(found.file.unwrap().ends_with("elf.rs"));
+        assert!(found.line.is_some());
+
+        let found = elf
+            .find_vaddr(sym.addr + elf.pt_loads[0].p_vaddr + 1)
+            .unwrap();
+        assert_eq!(found.name, sym.name);
+        assert!(elf.find_vaddr(u64::MAX).is_err());
+
+        // DWARF is read again for ELFs from the symbol cache
+        let cached = ElfMetadata::from_table(elf.path.clone(), None, elf.table());
+        let found = cached
+            .find_vaddr(sym.addr + elf.pt_loads[0].p_vaddr + 1)
+            .unwrap();
+        assert_eq!(found.name, sym.name);
+        assert!(found.file.unwrap().ends_with("elf.rs"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cached_frames() {
+        let dir = std::env::temp_dir().join(format!("doctor-elf-frames-{}", std::process::id()));
+        let cache = SymbolCache::open(&dir, 1 << 30).unwrap();
+        let elf = ElfMetadata::new(std::env::current_exe().unwrap()).unwrap();
+        cache.insert("aa", &elf.table()).unwrap();
+        let sym = elf
+            .debug_info
+            .iter()
+            .find(|s| s.name.as_deref() == Some("doctor::profiler::symbolizer::elf::demangle"))
+            .unwrap();
+
+        let mut first = ElfMetadata::from_table(elf.path.clone(), None, elf.table());
+        first.keep_frames(cache.frames("aa"));
+        let found = first.find_relative(sym.addr + 1).unwrap();
+        assert!(found.file.as_ref().unwrap().ends_with("elf.rs"));
+
+        // a later run answers from the symbol cache, without the DWARF
+        let mut second = ElfMetadata::from_table(elf.path.clone(), None, elf.table());
+        second.keep_frames(cache.frames("aa"));
+        assert_eq!(second.find_relative(sym.addr + 1).unwrap(), found);
+        assert!(second.symbol_map.get().is_none());
+
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_build_id() {
+        let exe = std::env::current_exe().unwrap();
+        let (_, _, parsed) = ElfMetadata::load_sym_from_elf(&exe).unwrap();
+        // from the headers alone, as from the whole ELF
+        assert_eq!(ElfMetadata::read_build_id(&exe).unwrap(), parsed);
+        assert_eq!(ElfMetadata::new(exe).unwrap().build_id, parsed);
+        assert!(parse_headers(b"not an elf").is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find_symbols() {
+        let elf = ElfMetadata::new(std::env::current_exe().unwrap()).unwrap();
+        let (first, last) = (&elf.pt_loads[0], elf.pt_loads.last().unwrap());
+        let mut offsets: Vec<u64> = elf
+            .debug_info
+            .iter()
+            .step_by(97)
+            .map(|sym| sym.addr + first.p_vaddr + 1)
+            .filter_map(|vaddr| {
+                let h = elf
+                    .pt_loads
+                    .iter()
+                    .find(|h| (h.p_vaddr..h.p_vaddr + h.p_filesz).contains(&vaddr))?;
+                Some(vaddr - h.p_vaddr + h.p_offset)
+            })
+            .collect();
+        offsets.reverse();
+        offsets.push(first.p_offset);
+        offsets.push(last.p_offset + last.p_filesz + 0x100000);
+
+        // the same symbols as one at a time, in the order asked
+        let found = elf.find_symbols(&offsets);
+        assert_eq!(found.len(), offsets.len());
+        for (offset, sym) in offsets.iter().zip(found) {
+            let one = elf
+                .translate(*offset)
+                .ok_or(SymbolizerError::TranslateVirtOffsetFailed(
+                    elf.path.clone(),
+                    *offset,
+                ))
+                .and_then(|relative_offset| elf.find_relative(relative_offset));
+            match (one, sym) {
+                (Ok(one), Ok(batch)) => assert_eq!(one, batch),
+                (Err(_), Err(_)) => {}
+                (one, batch) => panic!("{:#x}: {:?} != {:?}", offset, one, batch),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_file() {
+        let dir = std::env::temp_dir().join(format!("doctor-elf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (stripped, debug) = (dir.join("app"), dir.join("app.debug"));
+        std::fs::copy(std::env::current_exe().unwrap(), &stripped).unwrap();
+        let objcopy = |args: &[&std::ffi::OsStr]| {
+            std::process::Command::new("objcopy")
+                .args(args)
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false)
+        };
+        if !objcopy(&[
+            "--only-keep-debug".as_ref(),
+            stripped.as_ref(),
+            debug.as_ref(),
+        ]) || !objcopy(&["--strip-all".as_ref(), stripped.as_ref()])
+        {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        let elf = ElfMetadata::with_debug_file(stripped, Some(debug.clone())).unwrap();
+        let sym = elf
+            .debug_info
+            .iter()
+            .find(|s| s.name.as_deref() == Some("doctor::profiler::symbolizer::elf::demangle"))
+            .unwrap();
+        let found = elf
+            .find_vaddr(sym.addr + elf.pt_loads[0].p_vaddr + 1)
+            .unwrap();
+        assert!(found.file.unwrap().ends_with("elf.rs"));
+        assert_eq!(elf.table().dwarf, Some(debug));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_debug_file() {
+        let dir = std::env::temp_dir().join(format!("doctor-elf-invalid-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let symbols = ElfMetadata::new(exe.clone()).unwrap().debug_info.len();
+
+        // empty, truncated or not ELF debug files are left out
+        let truncated = std::fs::read(&exe).unwrap()[..64].to_vec();
+        for (name, content) in [
+            ("empty.debug", &b""[..]),
+            ("truncated.debug", &truncated),
+            ("html.debug", b"<html>502 Bad Gateway</html>"),
+        ] {
+            let debug = dir.join(name);
+            std::fs::write(&debug, content).unwrap();
+            assert!(ElfMetadata::new(debug.clone()).is_err());
+            let elf = ElfMetadata::with_debug_file(exe.clone(), Some(debug)).unwrap();
+            assert_eq!(elf.debug_info.len(), symbols);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_translate_segments() {
+        // lld puts the text segment further from its file offset than the
+        // first one
+        let segment = |p_offset, p_vaddr, p_filesz| ProgramHeader {
+            p_type: PT_LOAD,
+            p_offset,
+            p_vaddr,
+            p_filesz,
+            p_memsz: p_filesz,
+            ..Default::default()
+        };
+        let table = SymbolTable {
+            pt_loads: vec![segment(0, 0, 0x800), segment(0x1000, 0x2000, 0x1000)],
+            symbols: BTreeSet::from([
+                Symbol::new(0x100, Some("rodata_fn".into())),
+                Symbol::new(0x2100, Some("text_fn".into())),
+            ]),
+            dwarf: None,
+            golang: None,
+        };
+        let elf = ElfMetadata::from_table("/nonexistent".into(), None, table);
+
+        assert_eq!(elf.translate(0x400), Some(0x400));
+        assert_eq!(elf.translate(0x1150), Some(0x2150));
+        let found = elf.find_relative(elf.translate(0x1150).unwrap()).unwrap();
+        assert_eq!(found.name.as_deref(), Some("text_fn"));
+        // past the segments, as far as the first one is
+        assert_eq!(elf.translate(0x3000), Some(0x3000));
+    }
 }
 
 // #[cfg(test)]
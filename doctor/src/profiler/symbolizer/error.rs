@@ -15,8 +15,39 @@ pub enum SymbolizerError {
     TranslateVirtOffsetFailed(PathBuf, u64),
     #[error("load {0}, elf failed: {1}")]
     MMapIOFailed(PathBuf, std::io::Error),
+    #[error("parse elf {0}: {1}")]
+    ParseElfFailed(PathBuf, goblin::error::Error),
     #[error("get inode: {0}")]
     GetInodeFailed(std::io::Error),
+    #[error("read jit symbols {0}: {1}")]
+    JitIoFailed(PathBuf, std::io::Error),
+    #[error("invalid jitdump {0}")]
+    JitDumpInvalid(PathBuf),
+    #[error("invalid .gopclntab in {0}")]
+    GoPclntabInvalid(PathBuf),
+    #[error("read kernel symbols {0}: {1}")]
+    KernelSymbolsIoFailed(PathBuf, std::io::Error),
+    #[error(
+        "/proc/kallsyms hides kernel addresses (kernel.kptr_restrict = {0}), run with \
+         CAP_SYSLOG, lower kernel.kptr_restrict or pass --vmlinux or --system-map"
+    )]
+    KallsymsRestricted(String),
+    #[error("_text not found in {0}")]
+    KernelTextNotFound(PathBuf),
+    #[error("debuginfod {0}: {1}")]
+    DebuginfodFailed(String, reqwest::Error),
+    #[error("debuginfod {0}: timed out")]
+    DebuginfodTimeout(String),
+    #[error("debuginfod {0}: not an ELF of the build")]
+    DebuginfodInvalid(String),
+    #[error("debuginfod cache {0}: {1}")]
+    DebuginfodIoFailed(PathBuf, std::io::Error),
+    #[error("no ELF or debug file of build {0}")]
+    BuildNotFound(String),
+    #[error("load symbol map of {0}: {1}")]
+    LoadSymbolMapFailed(PathBuf, wholesym::Error),
+    #[error("decompress sections of {0}: {1}")]
+    DecompressIoFailed(PathBuf, std::io::Error),
     #[error("gmili load: {0}")]
     GmiliFailed(#[from] gimli::Error),
 }
@@ -0,0 +1,497 @@
+use std::{fs::File, ops::Range, path::Path};
+
+use goblin::elf::Elf;
+use memmap2::{Mmap, MmapOptions};
+
+use super::{error::SymbolizerError, symbol::Symbol};
+
+// magic numbers of the pclntab header, by the Go release that introduced the
+// layout
+const GO12_MAGIC: u32 = 0xfffffffb;
+const GO116_MAGIC: u32 = 0xfffffffa;
+const GO118_MAGIC: u32 = 0xfffffff0;
+const GO120_MAGIC: u32 = 0xfffffff1;
+
+/// Functions every goroutine and system stack starts from, frames past them
+/// are not part of the stack.
+const STACK_ROOTS: [&str; 3] = ["runtime.goexit", "runtime.mstart", "runtime.rt0_go"];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum GoVersion {
+    Go12,
+    Go116,
+    Go118,
+    Go120,
+}
+
+/// Offsets of the pclntab tables, from the start of the pclntab.
+#[derive(Debug)]
+struct Layout {
+    version: GoVersion,
+    quantum: u32,
+    ptr_size: usize,
+    text_start: u64,
+    nfunctab: usize,
+    funcnametab: usize,
+    cutab: usize,
+    filetab: usize,
+    pctab: usize,
+    funcdata: usize,
+    functab: usize,
+}
+
+/// The function table Go links into every binary for its own tracebacks,
+/// `.gopclntab`. It survives stripping, so it names the functions of stripped
+/// Go binaries along with their files and lines.
+pub struct GoPclntab {
+    data: Mmap,
+    location: PclntabLocation,
+    layout: Layout,
+}
+
+/// Where the pclntab of a Go binary is, kept in the symbol cache so that the
+/// binary is not parsed again to find it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PclntabLocation {
+    /// file offsets of the pclntab
+    pub range: Range<usize>,
+    /// address of `.text`
+    pub text: Option<u64>,
+}
+
+impl GoPclntab {
+    /// Where the pclntab of `elf` is, None when it is not a Go binary.
+    pub fn locate(elf: &Elf) -> Option<PclntabLocation> {
+        let range = pclntab_range(elf)?;
+        let text = elf
+            .section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".text"))
+            .map(|sh| sh.sh_addr);
+        Some(PclntabLocation { range, text })
+    }
+
+    /// The pclntab at `location` in `path`.
+    pub fn load(path: &Path, location: PclntabLocation) -> Result<Self, SymbolizerError> {
+        let file =
+            File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+        let data = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?;
+        let layout = data
+            .get(location.range.clone())
+            .and_then(|pclntab| parse_header(pclntab, location.text))
+            .ok_or_else(|| SymbolizerError::GoPclntabInvalid(path.into()))?;
+        Ok(Self {
+            data,
+            location,
+            layout,
+        })
+    }
+
+    pub fn location(&self) -> &PclntabLocation {
+        &self.location
+    }
+
+    /// Function containing the virtual address `pc`, with the file and line
+    /// of `pc`.
+    pub fn find(&self, pc: u64) -> Option<Symbol> {
+        let table = Table {
+            data: &self.data[self.location.range.clone()],
+            layout: &self.layout,
+        };
+        table.find(pc)
+    }
+}
+
+/// Whether `name` is the function a Go stack starts from.
+pub fn is_stack_root(name: &str) -> bool {
+    let name = name.strip_suffix(".abi0").unwrap_or(name);
+    STACK_ROOTS.contains(&name)
+}
+
+/// `.gopclntab`, or the `runtime.pclntab` symbol range of binaries linked
+/// externally, which put the table in `.data.rel.ro`.
+fn pclntab_range(elf: &Elf) -> Option<Range<usize>> {
+    let section = |name: &str| {
+        elf.section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(name))
+    };
+    if let Some(sh) = section(".gopclntab") {
+        let start = sh.sh_offset as usize;
+        return Some(start..start + sh.sh_size as usize);
+    }
+    // the symbols are only looked for in Go binaries
+    section(".go.buildinfo").or_else(|| section(".note.go.buildid"))?;
+
+    let symbol = |name: &str| {
+        elf.syms
+            .iter()
+            .find(|sym| elf.strtab.get_at(sym.st_name) == Some(name))
+            .map(|sym| sym.st_value)
+    };
+    let (start, end) = (symbol("runtime.pclntab")?, symbol("runtime.epclntab")?);
+    // virtual addresses to file offsets
+    let sh = elf
+        .section_headers
+        .iter()
+        .find(|sh| sh.sh_addr <= start && end <= sh.sh_addr + sh.sh_size)?;
+    let offset = (start - sh.sh_addr + sh.sh_offset) as usize;
+    Some(offset..offset + (end - start) as usize)
+}
+
+/// `text` is the address of `.text`, the base of the entry offsets since Go
+/// 1.18. The address in the header is not relocated in PIE binaries.
+fn parse_header(data: &[u8], text: Option<u64>) -> Option<Layout> {
+    let version = match u32_at(data, 0)? {
+        GO12_MAGIC => GoVersion::Go12,
+        GO116_MAGIC => GoVersion::Go116,
+        GO118_MAGIC => GoVersion::Go118,
+        GO120_MAGIC => GoVersion::Go120,
+        _ => return None,
+    };
+    let (pad, quantum, ptr_size) = (u16_at(data, 4)?, *data.get(6)?, *data.get(7)?);
+    if pad != 0 || !matches!(quantum, 1 | 2 | 4) || !matches!(ptr_size, 4 | 8) {
+        return None;
+    }
+    let ptr_size = ptr_size as usize;
+    let word = |i: usize| uint_at(data, 8 + i * ptr_size, ptr_size).map(|w| w as usize);
+
+    let mut layout = Layout {
+        version,
+        quantum: quantum as u32,
+        ptr_size,
+        text_start: 0,
+        nfunctab: word(0)?,
+        funcnametab: 0,
+        cutab: 0,
+        filetab: 0,
+        pctab: 0,
+        funcdata: 0,
+        functab: 0,
+    };
+    match version {
+        GoVersion::Go118 | GoVersion::Go120 => {
+            layout.text_start = match text {
+                Some(text) => text,
+                None => word(2)? as u64,
+            };
+            layout.funcnametab = word(3)?;
+            layout.cutab = word(4)?;
+            layout.filetab = word(5)?;
+            layout.pctab = word(6)?;
+            layout.funcdata = word(7)?;
+            layout.functab = word(7)?;
+        }
+        GoVersion::Go116 => {
+            layout.funcnametab = word(2)?;
+            layout.cutab = word(3)?;
+            layout.filetab = word(4)?;
+            layout.pctab = word(5)?;
+            layout.funcdata = word(6)?;
+            layout.functab = word(6)?;
+        }
+        GoVersion::Go12 => {
+            // offsets are from the start of the table, the file table
+            // offset follows the function table
+            layout.functab = 8 + ptr_size;
+            let functab_size = (layout.nfunctab * 2 + 1) * ptr_size;
+            layout.filetab = u32_at(data, layout.functab + functab_size)? as usize;
+        }
+    }
+    Some(layout)
+}
+
+struct Table<'a> {
+    data: &'a [u8],
+    layout: &'a Layout,
+}
+
+impl Table<'_> {
+    fn find(&self, pc: u64) -> Option<Symbol> {
+        let n = self.layout.nfunctab;
+        // the entry after the last function is the end of the text
+        if n == 0 || pc < self.entry(0)? || pc >= self.entry(n)? {
+            return None;
+        }
+        let (mut lo, mut hi) = (0, n);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid)? <= pc {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let func = self.layout.funcdata + self.functab_field(lo * 2 + 1)? as usize;
+        let entry = self.func_entry(func)?;
+        let name = c_str(
+            self.data,
+            self.layout.funcnametab + self.func_field(func, 1)? as usize,
+        )?;
+        let file = self
+            .pcvalue(self.func_field(func, 5)?, entry, pc)
+            .and_then(|fileno| self.file_name(func, fileno));
+        let line = self
+            .pcvalue(self.func_field(func, 6)?, entry, pc)
+            .and_then(|line| u32::try_from(line).ok());
+        Some(Symbol {
+            file,
+            line,
+            ..Symbol::new(entry, Some(name.to_owned()))
+        })
+    }
+
+    /// Size of the function table fields, offsets from the text since 1.18.
+    fn functab_field_size(&self) -> usize {
+        if self.layout.version >= GoVersion::Go118 {
+            4
+        } else {
+            self.layout.ptr_size
+        }
+    }
+
+    fn functab_field(&self, i: usize) -> Option<u64> {
+        let size = self.functab_field_size();
+        uint_at(self.data, self.layout.functab + i * size, size)
+    }
+
+    /// Entry address of the function `i` of the function table.
+    fn entry(&self, i: usize) -> Option<u64> {
+        let value = self.functab_field(i * 2)?;
+        Some(self.text_relative(value))
+    }
+
+    fn text_relative(&self, value: u64) -> u64 {
+        if self.layout.version >= GoVersion::Go118 {
+            self.layout.text_start + value
+        } else {
+            value
+        }
+    }
+
+    fn func_entry(&self, func: usize) -> Option<u64> {
+        let value = uint_at(self.data, func, self.functab_field_size())?;
+        Some(self.text_relative(value))
+    }
+
+    /// Field `n` of the `_func` at `func`: 1 nameoff, 5 pcfile, 6 pcln,
+    /// 8 cuOffset. Fields after the entry are 4 bytes.
+    fn func_field(&self, func: usize, n: usize) -> Option<u32> {
+        u32_at(self.data, func + self.functab_field_size() + (n - 1) * 4)
+    }
+
+    fn file_name(&self, func: usize, fileno: i32) -> Option<String> {
+        let fileno = usize::try_from(fileno).ok()?;
+        let offset = if self.layout.version >= GoVersion::Go116 {
+            let cu = self.func_field(func, 8)? as usize;
+            let offset = u32_at(self.data, self.layout.cutab + (cu + fileno) * 4)?;
+            if offset == u32::MAX {
+                return None;
+            }
+            self.layout.filetab + offset as usize
+        } else {
+            // the first entry is the number of files
+            u32_at(self.data, self.layout.filetab + fileno * 4)? as usize
+        };
+        c_str(self.data, offset).map(str::to_owned)
+    }
+
+    /// Value of the pc-value table at `offset` for `pc`, in the function
+    /// starting at `entry`.
+    fn pcvalue(&self, offset: u32, entry: u64, pc: u64) -> Option<i32> {
+        if offset == 0 {
+            return None;
+        }
+        let mut data = self.data.get(self.layout.pctab + offset as usize..)?;
+        let (mut value, mut value_pc) = (-1i32, entry);
+        let mut first = true;
+        loop {
+            let uvdelta = varint(&mut data)?;
+            if uvdelta == 0 && !first {
+                return None;
+            }
+            first = false;
+            // zig-zag encoded
+            let vdelta = if uvdelta & 1 != 0 {
+                !(uvdelta >> 1)
+            } else {
+                uvdelta >> 1
+            } as i32;
+            let pcdelta = varint(&mut data)? * self.layout.quantum;
+            value = value.wrapping_add(vdelta);
+            value_pc += pcdelta as u64;
+            if pc < value_pc {
+                return Some(value);
+            }
+        }
+    }
+}
+
+fn varint(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn uint_at(data: &[u8], pos: usize, size: usize) -> Option<u64> {
+    match size {
+        4 => u32_at(data, pos).map(u64::from),
+        8 => Some(u64::from_le_bytes(data.get(pos..pos + 8)?.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn c_str(data: &[u8], pos: usize) -> Option<&str> {
+    let bytes = data.get(pos..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    std::str::from_utf8(&bytes[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Table of `version` with main.main from 0x401100 to 0x401200 in
+    /// main.go, line 10 in its first half and line 12 in the second.
+    fn pclntab(version: GoVersion) -> Vec<u8> {
+        let magic = match version {
+            GoVersion::Go12 => GO12_MAGIC,
+            GoVersion::Go116 => GO116_MAGIC,
+            GoVersion::Go118 => GO118_MAGIC,
+            GoVersion::Go120 => GO120_MAGIC,
+        };
+        let go12 = version == GoVersion::Go12;
+        let mut data = magic.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0, 0, 1, 8]);
+        // header words, and the function table of Go 1.2 with the offset of
+        // its file table, filled in once the tables are laid out
+        let words = match version {
+            GoVersion::Go12 => 1,
+            GoVersion::Go116 => 7,
+            _ => 8,
+        };
+        data.resize(8 + words * 8, 0);
+        let go12_functab = data.len();
+        if go12 {
+            data.resize(go12_functab + 3 * 8 + 4, 0);
+        }
+
+        let funcnametab = data.len();
+        data.extend_from_slice(b"main.main\0");
+        let cutab = data.len();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let filetab = data.len();
+        if go12 {
+            // the number of files, then their offsets, from 1
+            for word in [2, filetab + 8] {
+                data.extend_from_slice(&(word as u32).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(b"main.go\0");
+        let pctab = data.len();
+        // pcfile at 1: main.go for 0x100 bytes, pcln at 5: line 10 then 12
+        let file = if go12 { 4 } else { 2 };
+        data.extend_from_slice(&[0, file, 0x80, 0x02, 0, 22, 0x80, 0x01, 4, 0x80, 0x01, 0]);
+
+        // entries are from the text since Go 1.18, fields from the start of
+        // the table in Go 1.2
+        let (text, size) = match version >= GoVersion::Go118 {
+            true => (0x401000, 4),
+            false => (0, 8),
+        };
+        let uint =
+            |buf: &mut Vec<u8>, value: u64| buf.extend_from_slice(&value.to_le_bytes()[..size]);
+        let functab = if go12 { go12_functab } else { data.len() };
+        let func = if go12 { data.len() } else { functab + 3 * size };
+        let (funcoff, nameoff, pcoff) = match go12 {
+            true => (func, funcnametab, pctab),
+            false => (func - functab, 0, 0),
+        };
+        // entry, _func offset, end of text
+        let mut table = Vec::new();
+        for value in [0x401100 - text, funcoff as u64, 0x401200 - text] {
+            uint(&mut table, value);
+        }
+        if go12 {
+            table.extend_from_slice(&(filetab as u32).to_le_bytes());
+            data[functab..functab + table.len()].copy_from_slice(&table);
+        } else {
+            data.extend_from_slice(&table);
+        }
+        // the _func: entry, nameoff, args, deferreturn, pcsp, pcfile, pcln,
+        // npcdata, cuOffset, funcID
+        uint(&mut data, 0x401100 - text);
+        for field in [nameoff, 0, 0, 0, pcoff + 1, pcoff + 5, 0, 0, 0, 0] {
+            data.extend_from_slice(&(field as u32).to_le_bytes());
+        }
+
+        let words = match version {
+            GoVersion::Go12 => vec![1],
+            GoVersion::Go116 => vec![1, 1, funcnametab, cutab, filetab, pctab, functab],
+            _ => vec![1, 1, 0x401000, funcnametab, cutab, filetab, pctab, functab],
+        };
+        for (i, word) in words.into_iter().enumerate() {
+            data[8 + i * 8..16 + i * 8].copy_from_slice(&(word as u64).to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_pclntab() {
+        for version in [
+            GoVersion::Go12,
+            GoVersion::Go116,
+            GoVersion::Go118,
+            GoVersion::Go120,
+        ] {
+            let data = pclntab(version);
+            // the text start of the header is used without a `runtime.text`
+            let text = (version != GoVersion::Go118).then_some(0x401000);
+            let layout = parse_header(&data, text).unwrap();
+            assert_eq!(layout.version, version);
+            let table = Table {
+                data: &data,
+                layout: &layout,
+            };
+
+            let sym = table.find(0x401150).unwrap();
+            assert_eq!(sym.addr, 0x401100, "{:?}", version);
+            assert_eq!(sym.name.as_deref(), Some("main.main"), "{:?}", version);
+            assert_eq!(sym.file.as_deref(), Some("main.go"), "{:?}", version);
+            assert_eq!(sym.line, Some(10), "{:?}", version);
+            assert_eq!(
+                table.find(0x401190).unwrap().line,
+                Some(12),
+                "{:?}",
+                version
+            );
+            assert!(table.find(0x401000).is_none());
+            assert!(table.find(0x401200).is_none());
+        }
+        assert!(is_stack_root("runtime.goexit.abi0"));
+    }
+
+    #[test]
+    fn test_not_go() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        assert!(GoPclntab::locate(&elf).is_none());
+    }
+}
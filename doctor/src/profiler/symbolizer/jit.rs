@@ -0,0 +1,437 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    os::linux::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use procfs::process::MMapPath;
+
+use super::{error::SymbolizerError, symbol::Symbol};
+use crate::profiler::process::ProcessMetadata;
+
+/// Files are read again on a miss, at most this often.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+// https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jitdump-specification.txt
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JITDUMP_HEADER_SIZE: usize = 40;
+const JIT_RECORD_HEADER_SIZE: usize = 16;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+/// A piece of JIT generated code.
+#[derive(Clone, Debug)]
+struct JitSymbol {
+    name: String,
+    end: u64,
+    /// (address, source file, line), sorted by address
+    lines: Vec<(u64, String, u32)>,
+}
+
+/// Symbols the JIT compiler of a process (JVM, V8, .NET, ...) publishes for
+/// its generated code, in a perf map (`/tmp/perf-<pid>.map`) and in a
+/// jitdump (`jit-<pid>.dump`), both named after the pid the process sees in
+/// its own namespace and read inside its rootfs.
+pub struct JitSymbols {
+    ns_pid: u32,
+    perf_map: PerfMap,
+    jit_dump: Option<JitDump>,
+    refreshed: Option<Instant>,
+}
+
+impl JitSymbols {
+    pub fn new(proc: &ProcessMetadata) -> Self {
+        let ns_pid = ns_pid(proc.pid).unwrap_or(proc.pid);
+        let path = proc.rootfs.join(format!("tmp/perf-{}.map", ns_pid));
+        Self {
+            ns_pid,
+            perf_map: PerfMap {
+                tail: Tail::new(path),
+                symbols: BTreeMap::new(),
+            },
+            jit_dump: None,
+            refreshed: None,
+        }
+    }
+
+    /// Symbol of the code at `ip` and the file it comes from. The files are
+    /// read again when `ip` is not known yet, as JITs keep appending to them.
+    pub fn find(&mut self, proc: &ProcessMetadata, ip: u64) -> Option<(PathBuf, Symbol)> {
+        if let Some(found) = self.lookup(ip) {
+            return Some(found);
+        }
+        if self
+            .refreshed
+            .is_some_and(|t| t.elapsed() < RELOAD_INTERVAL)
+        {
+            return None;
+        }
+        self.refresh(proc);
+        self.lookup(ip)
+    }
+
+    fn refresh(&mut self, proc: &ProcessMetadata) {
+        self.refreshed = Some(Instant::now());
+        if self.jit_dump.is_none() {
+            self.jit_dump = self.find_jit_dump(proc).map(|path| JitDump {
+                tail: Tail::new(path),
+                symbols: BTreeMap::new(),
+                lines: HashMap::new(),
+            });
+        }
+
+        let mut results = vec![self.perf_map.refresh()];
+        if let Some(jit_dump) = &mut self.jit_dump {
+            results.push(jit_dump.refresh());
+        }
+        for result in results {
+            match result {
+                Err(SymbolizerError::JitIoFailed(_, e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => debug!("jit symbols of {}: {}", proc.pid, e),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    /// The jitdump is mmapped by the JIT so profilers can find it, it is
+    /// looked for in /tmp otherwise.
+    fn find_jit_dump(&self, proc: &ProcessMetadata) -> Option<PathBuf> {
+        let name = format!("jit-{}.dump", self.ns_pid);
+        let mapped = proc.maps().iter().find_map(|m| match &m.pathname {
+            MMapPath::Path(path) if path.file_name().is_some_and(|n| *n == *name) => {
+                Some(proc.rootfs.join(path.strip_prefix("/").unwrap_or(path)))
+            }
+            _ => None,
+        });
+        mapped.or_else(|| {
+            let path = proc.rootfs.join("tmp").join(&name);
+            path.exists().then_some(path)
+        })
+    }
+
+    fn lookup(&self, ip: u64) -> Option<(PathBuf, Symbol)> {
+        // the jitdump has line numbers, the perf map only names
+        let (path, (start, symbol)) = self
+            .jit_dump
+            .as_ref()
+            .and_then(|d| covering(&d.symbols, ip).map(|s| (&d.tail.path, s)))
+            .or_else(|| {
+                covering(&self.perf_map.symbols, ip).map(|s| (&self.perf_map.tail.path, s))
+            })?;
+
+        let line = match symbol.lines.partition_point(|l| l.0 <= ip) {
+            0 => None,
+            i => symbol.lines.get(i - 1),
+        };
+        Some((
+            path.clone(),
+            Symbol {
+                file: line.map(|l| l.1.clone()),
+                line: line.map(|l| l.2),
+                ..Symbol::new(start, Some(symbol.name.clone()))
+            },
+        ))
+    }
+}
+
+fn covering(symbols: &BTreeMap<u64, JitSymbol>, ip: u64) -> Option<(u64, &JitSymbol)> {
+    symbols
+        .range(..=ip)
+        .next_back()
+        .filter(|(_, s)| ip < s.end)
+        .map(|(start, s)| (*start, s))
+}
+
+/// pid of the process in its innermost pid namespace.
+fn ns_pid(pid: u32) -> Option<u32> {
+    let status = procfs::process::Process::new(pid as i32)
+        .ok()?
+        .status()
+        .ok()?;
+    status.nstgid?.last().map(|pid| *pid as u32)
+}
+
+/// Reads an append-only file incrementally, from the start again when the
+/// file was replaced or truncated.
+struct Tail {
+    path: PathBuf,
+    inode: u64,
+    offset: u64,
+}
+
+impl Tail {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            inode: 0,
+            offset: 0,
+        }
+    }
+
+    /// Bytes after `offset`, and whether the file was read from the start.
+    fn read(&mut self) -> Result<(Vec<u8>, bool), SymbolizerError> {
+        let path = self.path.clone();
+        let io_failed = |e| SymbolizerError::JitIoFailed(path.clone(), e);
+        let mut file = File::open(&self.path).map_err(io_failed)?;
+        let meta = file.metadata().map_err(io_failed)?;
+        let reset = meta.st_ino() != self.inode || meta.len() < self.offset;
+        if reset {
+            self.inode = meta.st_ino();
+            self.offset = 0;
+        }
+        file.seek(SeekFrom::Start(self.offset)).map_err(io_failed)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(io_failed)?;
+        Ok((data, reset))
+    }
+}
+
+/// `START SIZE name` lines, in hex, later lines win.
+struct PerfMap {
+    tail: Tail,
+    symbols: BTreeMap<u64, JitSymbol>,
+}
+
+impl PerfMap {
+    fn refresh(&mut self) -> Result<(), SymbolizerError> {
+        let (data, reset) = self.tail.read()?;
+        if reset {
+            self.symbols.clear();
+        }
+        // the last line may still be written
+        let Some(end) = data.iter().rposition(|b| *b == b'\n') else {
+            return Ok(());
+        };
+        for line in data[..end].split(|b| *b == b'\n') {
+            if let Some((start, symbol)) = parse_perf_map_line(&String::from_utf8_lossy(line)) {
+                self.symbols.insert(start, symbol);
+            }
+        }
+        self.tail.offset += end as u64 + 1;
+        Ok(())
+    }
+}
+
+fn parse_perf_map_line(line: &str) -> Option<(u64, JitSymbol)> {
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    let start = hex(parts.next()?)?;
+    let size = hex(parts.next()?)?;
+    let name = parts.next()?.to_owned();
+    Some((
+        start,
+        JitSymbol {
+            name,
+            end: start.saturating_add(size),
+            lines: Vec::new(),
+        },
+    ))
+}
+
+/// perf's jitdump format, code load, move and debug info records are used.
+struct JitDump {
+    tail: Tail,
+    symbols: BTreeMap<u64, JitSymbol>,
+    /// code address -> lines, debug info comes before the code load
+    lines: HashMap<u64, Vec<(u64, String, u32)>>,
+}
+
+impl JitDump {
+    fn refresh(&mut self) -> Result<(), SymbolizerError> {
+        let (data, reset) = self.tail.read()?;
+        if reset {
+            self.symbols.clear();
+            self.lines.clear();
+        }
+        let mut pos = 0;
+        if self.tail.offset == 0 {
+            if data.len() < JITDUMP_HEADER_SIZE {
+                return Ok(());
+            }
+            // files of the other byte order are not supported
+            if u32_at(&data, 0) != Some(JITDUMP_MAGIC) {
+                return Err(SymbolizerError::JitDumpInvalid(self.tail.path.clone()));
+            }
+            pos = u32_at(&data, 8).unwrap_or_default() as usize;
+        }
+        while let Some(size) = data.get(pos..).and_then(|d| self.record(d)) {
+            pos += size;
+        }
+        self.tail.offset += pos as u64;
+        Ok(())
+    }
+
+    /// Applies the record at the start of `data` and returns its size, None
+    /// when it is not completely written yet.
+    fn record(&mut self, data: &[u8]) -> Option<usize> {
+        let id = u32_at(data, 0)?;
+        let size = u32_at(data, 4)? as usize;
+        if size < JIT_RECORD_HEADER_SIZE || data.len() < size {
+            return None;
+        }
+        let body = &data[JIT_RECORD_HEADER_SIZE..size];
+        // a malformed record is skipped
+        let _ = match id {
+            JIT_CODE_LOAD => self.code_load(body),
+            JIT_CODE_MOVE => self.code_move(body),
+            JIT_CODE_DEBUG_INFO => self.debug_info(body),
+            _ => None,
+        };
+        Some(size)
+    }
+
+    fn code_load(&mut self, body: &[u8]) -> Option<()> {
+        // pid, tid, vma, code_addr, code_size, code_index, name, code
+        let code_addr = u64_at(body, 16)?;
+        let code_size = u64_at(body, 24)?;
+        let name = c_str(body.get(40..)?)?;
+        let lines = self.lines.remove(&code_addr).unwrap_or_default();
+        self.symbols.insert(
+            code_addr,
+            JitSymbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                end: code_addr.saturating_add(code_size),
+                lines,
+            },
+        );
+        Some(())
+    }
+
+    fn code_move(&mut self, body: &[u8]) -> Option<()> {
+        // pid, tid, vma, old_code_addr, new_code_addr, code_size, code_index
+        let old = u64_at(body, 16)?;
+        let new = u64_at(body, 24)?;
+        let code_size = u64_at(body, 32)?;
+        let mut symbol = self.symbols.remove(&old)?;
+        symbol.end = new.saturating_add(code_size);
+        for line in &mut symbol.lines {
+            line.0 = line.0.wrapping_sub(old).wrapping_add(new);
+        }
+        self.symbols.insert(new, symbol);
+        Some(())
+    }
+
+    fn debug_info(&mut self, body: &[u8]) -> Option<()> {
+        // code_addr, nr_entry, then (addr, line, discriminator, file) entries
+        let code_addr = u64_at(body, 0)?;
+        let entries = u64_at(body, 8)?;
+        let mut lines = Vec::new();
+        let mut file = String::new();
+        let mut pos = 16;
+        for _ in 0..entries {
+            let addr = u64_at(body, pos)?;
+            let line = u32_at(body, pos + 8)?;
+            let name = c_str(body.get(pos + 16..)?)?;
+            // "\xff" repeats the previous file
+            if name != [0xff] {
+                file = String::from_utf8_lossy(name).into_owned();
+            }
+            lines.push((addr, file.clone(), line));
+            pos += 16 + name.len() + 1;
+        }
+        lines.sort_by_key(|l| l.0);
+        self.lines.insert(code_addr, lines);
+        Some(())
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn c_str(data: &[u8]) -> Option<&[u8]> {
+    let len = data.iter().position(|b| *b == 0)?;
+    Some(&data[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::*;
+
+    fn record(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = id.to_ne_bytes().to_vec();
+        data.extend_from_slice(&((JIT_RECORD_HEADER_SIZE + body.len()) as u32).to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn jit_dump() -> Vec<u8> {
+        let mut data = JITDUMP_MAGIC.to_ne_bytes().to_vec();
+        data.extend_from_slice(&1u32.to_ne_bytes());
+        data.extend_from_slice(&(JITDUMP_HEADER_SIZE as u32).to_ne_bytes());
+        data.resize(JITDUMP_HEADER_SIZE, 0);
+
+        let mut debug_info = Vec::new();
+        for v in [0x2000u64, 2, 0x2000] {
+            debug_info.extend_from_slice(&v.to_ne_bytes());
+        }
+        debug_info.extend_from_slice(&[10, 0, 0, 0, 0, 0, 0, 0]);
+        debug_info.extend_from_slice(b"app.js\0");
+        debug_info.extend_from_slice(&0x2010u64.to_ne_bytes());
+        debug_info.extend_from_slice(&[12, 0, 0, 0, 0, 0, 0, 0]);
+        debug_info.extend_from_slice(b"\xff\0");
+        data.extend(record(JIT_CODE_DEBUG_INFO, &debug_info));
+
+        let mut load = vec![0; 8];
+        for v in [0x2000u64, 0x2000, 0x40, 1] {
+            load.extend_from_slice(&v.to_ne_bytes());
+        }
+        load.extend_from_slice(b"LazyCompile:*handler\0");
+        load.extend_from_slice(&[0x90; 0x40]);
+        data.extend(record(JIT_CODE_LOAD, &load));
+        data
+    }
+
+    #[test]
+    fn test_jit_symbols() {
+        let pid = std::process::id();
+        let perf_map = PathBuf::from(format!("/tmp/perf-{}.map", pid));
+        let dump = PathBuf::from(format!("/tmp/jit-{}.dump", pid));
+        fs::write(&perf_map, "1000 20 Interpreter\n0x1100 10 stub\n1200 1").unwrap();
+        let mut data = jit_dump();
+        // a record still being written
+        data.extend_from_slice(&JIT_CODE_LOAD.to_ne_bytes());
+        fs::write(&dump, data).unwrap();
+
+        let proc = ProcessMetadata::new(pid).unwrap();
+        let mut jit = JitSymbols::new(&proc);
+        let (path, sym) = jit.find(&proc, 0x1010).unwrap();
+        assert_eq!(path, proc.rootfs.join(perf_map.strip_prefix("/").unwrap()));
+        assert_eq!(sym.addr, 0x1000);
+        assert_eq!(sym.name.as_deref(), Some("Interpreter"));
+        assert!(jit.find(&proc, 0x1105).is_some());
+        assert!(jit.find(&proc, 0x1200).is_none());
+
+        let (_, sym) = jit.find(&proc, 0x2014).unwrap();
+        assert_eq!(sym.name.as_deref(), Some("LazyCompile:*handler"));
+        assert_eq!((sym.file.as_deref(), sym.line), (Some("app.js"), Some(12)));
+
+        // the rest of the last line shows up on the next miss
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&perf_map)
+            .unwrap()
+            .write_all(b"0 handler\n")
+            .unwrap();
+        jit.refreshed = None;
+        assert_eq!(
+            jit.find(&proc, 0x1200).unwrap().1.name.as_deref(),
+            Some("handler")
+        );
+
+        fs::remove_file(perf_map).unwrap();
+        fs::remove_file(dump).unwrap();
+    }
+}
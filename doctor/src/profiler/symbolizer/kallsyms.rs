@@ -0,0 +1,358 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use goblin::{
+    container::Ctx,
+    elf::{program_header::PT_LOAD, Elf, ProgramHeader},
+};
+use log::warn;
+use memmap2::MmapOptions;
+
+use super::{elf::ElfMetadata, error::SymbolizerError, symbol::Symbol};
+
+/// DSO of the frames in the kernel image, perf's name for it.
+pub const KERNEL_DSO: &str = "[kernel.kallsyms]";
+
+/// Pseudo module kallsyms lists JITed BPF programs, trampolines and
+/// dispatchers under.
+const BPF_MODULE: &str = "bpf";
+const BPF_PROG_PREFIX: &str = "bpf_prog_";
+
+// relative to the root of the host
+const KALLSYMS: &str = "proc/kallsyms";
+const MODULES: &str = "proc/modules";
+const KCORE: &str = "proc/kcore";
+const KPTR_RESTRICT: &str = "proc/sys/kernel/kptr_restrict";
+
+/// Where the kernel image is mapped on x86_64, `__START_KERNEL_map` and the
+/// 1 GB after it.
+const KERNEL_TEXT: Range<u64> = 0xffffffff80000000..0xffffffffc0000000;
+// the ELF and program headers of /proc/kcore, one per memory range
+const KCORE_HEADERS_SIZE: u64 = 64 * 1024;
+
+/// Kernel images to symbolize with instead of, or on top of, kallsyms.
+#[derive(Clone, Debug, Default)]
+pub struct KernelImages {
+    /// vmlinux with symbols, and DWARF for inlined functions and lines
+    pub vmlinux: Option<PathBuf>,
+    /// System.map of the running kernel, for when kallsyms hides addresses
+    pub system_map: Option<PathBuf>,
+}
+
+struct KernelSymbol {
+    name: String,
+    module: Option<String>,
+}
+
+/// Kernel symbols from `/proc/kallsyms`, with the module each belongs to and
+/// the address ranges of the loaded modules from `/proc/modules`. The kernel
+/// image itself can be symbolized from a vmlinux or a System.map instead,
+/// moved by the KASLR offset.
+pub struct KernelSymbols {
+    syms: BTreeMap<u64, KernelSymbol>,
+    modules: BTreeMap<u64, (u64, String)>, // start -> (end, name)
+    vmlinux: Option<ElfMetadata>,
+    kaslr_offset: u64, // runtime address - link address of the images
+}
+
+impl KernelSymbols {
+    /// Fails when kallsyms shows zero addresses, because of kptr_restrict or
+    /// a missing CAP_SYSLOG, and no image replaces it.
+    pub fn load(rootfs: &Path, images: &KernelImages) -> Result<Self, SymbolizerError> {
+        let path = rootfs.join(KALLSYMS);
+        let kallsyms = File::open(&path)
+            .and_then(|file| parse_kallsyms(BufReader::new(file)))
+            .map_err(|e| SymbolizerError::KernelSymbolsIoFailed(path, e))?;
+        // every address is 0, they collapse into one entry
+        let restricted = kallsyms.keys().all(|addr| *addr == 0);
+        if restricted && images.vmlinux.is_none() && images.system_map.is_none() {
+            let kptr_restrict = fs::read_to_string(rootfs.join(KPTR_RESTRICT));
+            let kptr_restrict = kptr_restrict.as_deref().unwrap_or("unknown").trim();
+            return Err(SymbolizerError::KallsymsRestricted(kptr_restrict.into()));
+        }
+
+        let mut ksyms = Self {
+            syms: BTreeMap::new(),
+            modules: BTreeMap::new(),
+            vmlinux: None,
+            kaslr_offset: 0,
+        };
+        if !restricted {
+            // modules are hidden along with kallsyms
+            let path = rootfs.join(MODULES);
+            ksyms.modules = match File::open(&path) {
+                Ok(file) => parse_modules(BufReader::new(file))
+                    .map_err(|e| SymbolizerError::KernelSymbolsIoFailed(path, e))?,
+                // kernels without module support
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(SymbolizerError::KernelSymbolsIoFailed(path, e)),
+            };
+        }
+        let system_map = match &images.system_map {
+            Some(path) => Some(
+                File::open(path)
+                    .and_then(|file| parse_kallsyms(BufReader::new(file)))
+                    .map_err(|e| SymbolizerError::KernelSymbolsIoFailed(path.clone(), e))?,
+            ),
+            None => None,
+        };
+
+        if images.vmlinux.is_some() || system_map.is_some() {
+            let link_text = match (&system_map, &images.vmlinux) {
+                (Some(syms), _) => find_name(syms, "_text"),
+                (None, Some(vmlinux)) => elf_symbol(vmlinux, "_text")?,
+                (None, None) => None,
+            };
+            let image = images.system_map.as_ref().or(images.vmlinux.as_ref());
+            let link_text = link_text
+                .ok_or_else(|| SymbolizerError::KernelTextNotFound(image.unwrap().clone()))?;
+            let runtime_text = match restricted {
+                false => find_name(&kallsyms, "_text"),
+                true => kcore_text(&rootfs.join(KCORE)),
+            };
+            ksyms.kaslr_offset = match runtime_text {
+                Some(text) => text.wrapping_sub(link_text),
+                None => {
+                    warn!("kernel load address unknown, kernel frames assume no KASLR");
+                    0
+                }
+            };
+        }
+        ksyms.syms = match (restricted, system_map) {
+            (false, _) => kallsyms,
+            (true, Some(system_map)) => system_map
+                .into_iter()
+                .map(|(addr, sym)| (addr.wrapping_add(ksyms.kaslr_offset), sym))
+                .collect(),
+            (true, None) => BTreeMap::new(),
+        };
+        if let Some(vmlinux) = &images.vmlinux {
+            ksyms.vmlinux = Some(ElfMetadata::new(vmlinux.clone())?);
+        }
+        Ok(ksyms)
+    }
+
+    /// Function and DSO of the kernel address `ip`: `[kernel.kallsyms]`, the
+    /// module in brackets, or the `bpf_prog_<tag>_<name>` program itself.
+    pub fn find(&self, ip: u64) -> Option<(Symbol, String)> {
+        if let Some(vmlinux) = &self.vmlinux {
+            if let Ok(sym) = vmlinux.find_vaddr(ip.wrapping_sub(self.kaslr_offset)) {
+                return Some((sym, KERNEL_DSO.into()));
+            }
+        }
+
+        let (addr, sym) = self.syms.range(..=ip).next_back()?;
+        let module = match self.modules.range(..=ip).next_back() {
+            Some((_, (end, name))) if ip < *end => Some(name.as_str()),
+            _ => sym.module.as_deref(),
+        };
+        // a module without symbols, the symbol before belongs to another one
+        if module.is_some() && module != sym.module.as_deref() {
+            let unknown = Symbol::new(ip, Some("unknown".into()));
+            return Some((unknown, format!("[{}]", module?)));
+        }
+        let dso = match module {
+            None => KERNEL_DSO.to_string(),
+            Some(BPF_MODULE) if sym.name.starts_with(BPF_PROG_PREFIX) => sym.name.clone(),
+            Some(module) => format!("[{}]", module),
+        };
+        Some((Symbol::new(*addr, Some(sym.name.clone())), dso))
+    }
+}
+
+fn find_name(syms: &BTreeMap<u64, KernelSymbol>, name: &str) -> Option<u64> {
+    syms.iter()
+        .find(|(_, sym)| sym.name == name)
+        .map(|(addr, _)| *addr)
+}
+
+fn elf_symbol(path: &Path, name: &str) -> Result<Option<u64>, SymbolizerError> {
+    let file =
+        File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+    let data = unsafe { MmapOptions::new().map(&file) }
+        .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?;
+    let Ok(elf) = Elf::parse(&data) else {
+        return Ok(None);
+    };
+    Ok(elf
+        .syms
+        .iter()
+        .find(|sym| elf.strtab.get_at(sym.st_name) == Some(name))
+        .map(|sym| sym.st_value))
+}
+
+/// Runtime address of `_text`: /proc/kcore maps the kernel image at it, and
+/// shows it to root whatever kptr_restrict says.
+fn kcore_text(path: &Path) -> Option<u64> {
+    let mut data = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(KCORE_HEADERS_SIZE)
+        .read_to_end(&mut data)
+        .ok()?;
+    let header = Elf::parse_header(&data).ok()?;
+    let ctx = Ctx::new(header.container().ok()?, header.endianness().ok()?);
+    let headers =
+        ProgramHeader::parse(&data, header.e_phoff as usize, header.e_phnum as usize, ctx).ok()?;
+    headers
+        .iter()
+        .find(|h| h.p_type == PT_LOAD && KERNEL_TEXT.contains(&h.p_vaddr))
+        .map(|h| h.p_vaddr)
+}
+
+/// `address type name`, followed by a tab and `[module]` for module symbols.
+fn parse_kallsyms(reader: impl BufRead) -> io::Result<BTreeMap<u64, KernelSymbol>> {
+    let mut syms = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let (sym, module) = match line.split_once('\t') {
+            Some((sym, module)) => {
+                let module = module.trim_start_matches('[').trim_end_matches(']');
+                (sym, Some(module.to_owned()))
+            }
+            None => (line.as_str(), None),
+        };
+        let mut parts = sym.splitn(3, ' ');
+        let (Some(addr), Some(_), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, line.clone()));
+        };
+        let addr = u64::from_str_radix(addr, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, line.clone()))?;
+        let name = name.to_owned();
+        syms.insert(addr, KernelSymbol { name, module });
+    }
+    Ok(syms)
+}
+
+/// `name size refcount deps state address`, the address is 0 when hidden by
+/// kptr_restrict.
+fn parse_modules(reader: impl BufRead) -> io::Result<BTreeMap<u64, (u64, String)>> {
+    let mut modules = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let (Some(name), Some(size), Some(addr)) = (parts.first(), parts.get(1), parts.get(5))
+        else {
+            continue;
+        };
+        let (Ok(size), Ok(addr)) = (
+            size.parse::<u64>(),
+            u64::from_str_radix(addr.trim_start_matches("0x"), 16),
+        ) else {
+            continue;
+        };
+        if addr != 0 {
+            modules.insert(addr, (addr + size, name.to_string()));
+        }
+    }
+    Ok(modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KALLSYMS_TEXT: &str = "\
+ffffffff81000000 T _stext
+ffffffff81001000 T do_syscall_64
+ffffffffc0500000 t ext4_file_write_iter\t[ext4]
+ffffffffc0500400 t ext4_buffered_write_iter\t[ext4]
+ffffffffc0700000 t bpf_prog_6deef7357e7b4530_sd_fw_ingress\t[bpf]
+ffffffffc0701000 t bpf_trampoline_6442491873\t[bpf]
+";
+    const MODULES_TEXT: &str = "\
+ext4 4096 2 mbcache,jbd2, Live 0xffffffffc0500000
+nosyms 4096 0 - Live 0xffffffffc0600000
+hidden 4096 0 - Live 0x0000000000000000
+";
+
+    #[test]
+    fn test_kernel_dsos() {
+        let syms = KernelSymbols {
+            syms: parse_kallsyms(KALLSYMS_TEXT.as_bytes()).unwrap(),
+            modules: parse_modules(MODULES_TEXT.as_bytes()).unwrap(),
+            vmlinux: None,
+            kaslr_offset: 0,
+        };
+        let find = |ip| syms.find(ip).map(|(sym, dso)| (sym.name.unwrap(), dso));
+        let frame = |name: &str, dso: &str| Some((name.to_string(), dso.to_string()));
+
+        assert_eq!(find(0xffffffff81001010), frame("do_syscall_64", KERNEL_DSO));
+        assert_eq!(
+            find(0xffffffffc0500410),
+            frame("ext4_buffered_write_iter", "[ext4]")
+        );
+        assert_eq!(find(0xffffffffc0600010), frame("unknown", "[nosyms]"));
+        assert_eq!(
+            find(0xffffffffc0700010),
+            frame(
+                "bpf_prog_6deef7357e7b4530_sd_fw_ingress",
+                "bpf_prog_6deef7357e7b4530_sd_fw_ingress"
+            )
+        );
+        assert_eq!(
+            find(0xffffffffc0701010),
+            frame("bpf_trampoline_6442491873", "[bpf]")
+        );
+        assert_eq!(find(0xffffffff80000000), None);
+    }
+
+    /// An ELF header and one PT_LOAD at `vaddr`, like /proc/kcore.
+    fn kcore(vaddr: u64) -> Vec<u8> {
+        let mut data = b"\x7fELF\x02\x01\x01".to_vec();
+        data.resize(16, 0);
+        data.extend(4u16.to_le_bytes()); // ET_CORE
+        data.extend(62u16.to_le_bytes()); // EM_X86_64
+        data.extend(1u32.to_le_bytes());
+        data.extend(0u64.to_le_bytes()); // entry
+        data.extend(64u64.to_le_bytes()); // phoff
+        data.extend(0u64.to_le_bytes()); // shoff
+        data.extend(0u32.to_le_bytes());
+        for half in [64u16, 56, 1, 64, 0, 0] {
+            data.extend(half.to_le_bytes()); // ehsize, phentsize, phnum, ...
+        }
+        data.extend(PT_LOAD.to_le_bytes());
+        data.extend(7u32.to_le_bytes());
+        for word in [0x1000, vaddr, 0, 0x1000000, 0x1000000, 0x1000] {
+            data.extend(word.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_restricted_kallsyms() {
+        let rootfs = std::env::temp_dir().join(format!("doctor-kallsyms-{}", std::process::id()));
+        fs::create_dir_all(rootfs.join("proc/sys/kernel")).unwrap();
+        let hidden = "0000000000000000 T _text\n0000000000000000 T do_syscall_64\n";
+        fs::write(rootfs.join(KALLSYMS), hidden).unwrap();
+        fs::write(rootfs.join(KPTR_RESTRICT), "2\n").unwrap();
+
+        let err = KernelSymbols::load(&rootfs, &KernelImages::default()).err();
+        assert!(matches!(err, Some(SymbolizerError::KallsymsRestricted(v)) if v == "2"));
+
+        // the kernel runs 0x1e00000 above its link address
+        let system_map = rootfs.join("System.map");
+        fs::write(
+            &system_map,
+            "ffffffff81000000 T _text\nffffffff81001000 T do_syscall_64\n",
+        )
+        .unwrap();
+        fs::write(rootfs.join(KCORE), kcore(0xffffffff82e00000)).unwrap();
+        let images = KernelImages {
+            vmlinux: None,
+            system_map: Some(system_map),
+        };
+        let syms = KernelSymbols::load(&rootfs, &images).unwrap();
+        fs::remove_dir_all(&rootfs).unwrap();
+
+        assert_eq!(syms.kaslr_offset, 0x1e00000);
+        let (sym, dso) = syms.find(0xffffffff82e01010).unwrap();
+        assert_eq!(sym.name.as_deref(), Some("do_syscall_64"));
+        assert_eq!(dso, KERNEL_DSO);
+    }
+}
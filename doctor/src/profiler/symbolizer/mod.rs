@@ -1,5 +1,13 @@
+pub mod compression;
+pub mod debuginfo;
+pub mod debuginfod;
 pub mod elf;
 pub mod error;
+pub mod golang;
+pub mod jit;
+pub mod kallsyms;
+pub mod python;
 pub mod symbol;
+pub mod symbol_cache;
 pub mod symbol_store;
 pub mod symbolizer;
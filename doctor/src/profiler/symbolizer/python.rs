@@ -0,0 +1,488 @@
+use std::{collections::HashMap, fs::File, os::unix::fs::FileExt, path::Path, sync::Arc};
+
+use doctor_common::{PyFrame, PyOffsets, PY_ENTRY_FRAME, PY_NONE};
+use goblin::elf::{
+    program_header::{PF_X, PT_LOAD},
+    Elf,
+};
+use log::debug;
+use memmap2::MmapOptions;
+use procfs::process::{MMapPath, MemoryMap};
+
+use super::{error::SymbolizerError, symbol::Symbol};
+use crate::profiler::process::ProcessMetadata;
+
+const PAGE_MASK: u64 = 0xfff;
+
+// PyUnicodeObject and PyBytesObject fields, the same in all supported versions
+const UNICODE_LENGTH: u64 = 16;
+const UNICODE_STATE: u64 = 32;
+const BYTES_SIZE: u64 = 16;
+const BYTES_DATA: u64 = 32;
+
+// names and line tables are small, anything larger is not a code object
+const MAX_STRING_LEN: u64 = 4096;
+const MAX_LINETABLE_LEN: u64 = 1 << 20;
+
+/// Layout of a CPython minor release, x86_64 builds without Py_DEBUG.
+/// Patch releases keep the layout.
+#[derive(Debug)]
+pub struct PyVersion {
+    pub minor: u32,
+    pub offsets: PyOffsets,
+    code_filename: u64,
+    code_qualname: u64,
+    code_linetable: u64,
+    code_firstlineno: u64,
+    code_adaptive: u64, // co_code_adaptive, the bytecode
+    ascii_data: u64,    // sizeof(PyASCIIObject)
+    compact_data: u64,  // sizeof(PyCompactUnicodeObject)
+}
+
+static VERSIONS: [PyVersion; 3] = [
+    PyVersion {
+        minor: 11,
+        offsets: PyOffsets {
+            runtime_interpreters_head: 40,
+            interp_threads_head: 16,
+            tstate_next: 8,
+            tstate_native_thread_id: 160,
+            tstate_frame: 56,
+            cframe_current_frame: 8,
+            frame_code: 32,
+            frame_previous: 48,
+            frame_instr: 56,
+            frame_owner: 69,
+            frame_is_entry: 68,
+        },
+        code_filename: 112,
+        code_qualname: 128,
+        code_linetable: 136,
+        code_firstlineno: 72,
+        code_adaptive: 184,
+        ascii_data: 48,
+        compact_data: 72,
+    },
+    PyVersion {
+        minor: 12,
+        offsets: PyOffsets {
+            runtime_interpreters_head: 40,
+            interp_threads_head: 72,
+            tstate_next: 8,
+            tstate_native_thread_id: 144,
+            tstate_frame: 56,
+            cframe_current_frame: 0,
+            frame_code: 0,
+            frame_previous: 8,
+            frame_instr: 56,
+            frame_owner: 70,
+            frame_is_entry: PY_NONE,
+        },
+        code_filename: 112,
+        code_qualname: 128,
+        code_linetable: 136,
+        code_firstlineno: 68,
+        code_adaptive: 192,
+        ascii_data: 40,
+        compact_data: 56,
+    },
+    PyVersion {
+        minor: 13,
+        offsets: PyOffsets {
+            runtime_interpreters_head: 632,
+            interp_threads_head: 7344,
+            tstate_next: 8,
+            tstate_native_thread_id: 160,
+            tstate_frame: 72,
+            cframe_current_frame: PY_NONE,
+            frame_code: 0,
+            frame_previous: 8,
+            frame_instr: 56,
+            frame_owner: 70,
+            frame_is_entry: PY_NONE,
+        },
+        code_filename: 112,
+        code_qualname: 128,
+        code_linetable: 136,
+        code_firstlineno: 68,
+        code_adaptive: 200,
+        ascii_data: 40,
+        compact_data: 56,
+    },
+];
+
+impl PyVersion {
+    /// Layout of the release `hex`, the `PY_VERSION_HEX` of the interpreter.
+    fn find(hex: u32) -> Option<&'static Self> {
+        let (major, minor) = (hex >> 24, (hex >> 16) & 0xff);
+        VERSIONS.iter().find(|v| major == 3 && v.minor == minor)
+    }
+}
+
+/// The interpreter of a CPython process: its layout and the runtime address
+/// of `_PyRuntime`, where the eBPF program starts walking.
+pub struct PyRuntime {
+    pub version: &'static PyVersion,
+    pub addr: u64,
+}
+
+impl PyRuntime {
+    /// Looks for libpython, or a python executable linked statically, in the
+    /// mappings of `proc`. None when it does not run a supported CPython.
+    pub fn find(proc: &ProcessMetadata) -> Result<Option<Self>, SymbolizerError> {
+        for map in proc.maps() {
+            let MMapPath::Path(path) = &map.pathname else {
+                continue;
+            };
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !name.contains("python") {
+                continue;
+            }
+            let path = proc.rootfs.join(path.strip_prefix("/").unwrap_or(path));
+            if let Some(runtime) = Self::load(&path, map)? {
+                return Ok(Some(runtime));
+            }
+        }
+        Ok(None)
+    }
+
+    fn load(path: &Path, map: &MemoryMap) -> Result<Option<Self>, SymbolizerError> {
+        let file =
+            File::open(path).map_err(|e| SymbolizerError::SymbolStoreIOFailed(path.into(), e))?;
+        let data = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| SymbolizerError::MMapIOFailed(path.into(), e))?;
+        let Ok(elf) = Elf::parse(&data) else {
+            return Ok(None);
+        };
+        let symbol = |name: &str| {
+            let dynsyms = elf
+                .dynsyms
+                .iter()
+                .map(|sym| (elf.dynstrtab.get_at(sym.st_name), sym.st_value));
+            let syms = elf
+                .syms
+                .iter()
+                .map(|sym| (elf.strtab.get_at(sym.st_name), sym.st_value));
+            dynsyms
+                .chain(syms)
+                .find(|(sym, _)| *sym == Some(name))
+                .map(|(_, addr)| addr)
+        };
+        // Py_Version is exported since 3.11, older releases are not supported
+        let (Some(runtime), Some(version)) = (symbol("_PyRuntime"), symbol("Py_Version")) else {
+            return Ok(None);
+        };
+        let loads = elf
+            .program_headers
+            .iter()
+            .filter(|h| h.p_type == PT_LOAD)
+            .collect::<Vec<_>>();
+
+        // a constant, read from the file
+        let hex = loads
+            .iter()
+            .find(|h| (h.p_vaddr..h.p_vaddr + h.p_filesz).contains(&version))
+            .map(|h| (version - h.p_vaddr + h.p_offset) as usize)
+            .and_then(|offset| data.get(offset..offset + 4))
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+        let Some(version) = hex.and_then(PyVersion::find) else {
+            debug!("{:?}: unsupported python version {:x?}", path, hex);
+            return Ok(None);
+        };
+
+        // mappings start at the page holding the segment start
+        let Some(text) = loads.iter().find(|h| {
+            h.p_flags & PF_X != 0
+                && (h.p_offset & !PAGE_MASK..h.p_offset + h.p_filesz).contains(&map.offset)
+        }) else {
+            return Ok(None);
+        };
+        let bias = map
+            .address
+            .0
+            .wrapping_sub(map.offset)
+            .wrapping_add(text.p_offset)
+            .wrapping_sub(text.p_vaddr);
+        Ok(Some(Self {
+            version,
+            addr: runtime.wrapping_add(bias),
+        }))
+    }
+}
+
+/// What a frame shows of its code object.
+struct PyCode {
+    qualname: String,
+    filename: String,
+    firstlineno: u32,
+    linetable: Vec<u8>,
+}
+
+/// Names the Python frames of a process from the code objects in its memory.
+/// Code objects usually live as long as the process, they are read once.
+pub struct PythonSymbols {
+    version: Option<&'static PyVersion>,
+    mem: Option<File>,
+    codes: HashMap<u64, Option<Arc<PyCode>>>, // code object address -> code
+}
+
+impl PythonSymbols {
+    pub fn new(proc: &ProcessMetadata) -> Self {
+        let version = match PyRuntime::find(proc) {
+            Ok(runtime) => runtime.map(|r| r.version),
+            Err(e) => {
+                debug!("python runtime of {}: {}", proc.pid, e);
+                None
+            }
+        };
+        let mem = match File::open(format!("/proc/{}/mem", proc.pid)) {
+            Ok(mem) => Some(mem),
+            Err(e) => {
+                debug!("memory of {}: {}", proc.pid, e);
+                None
+            }
+        };
+        Self {
+            version,
+            mem,
+            codes: HashMap::new(),
+        }
+    }
+
+    /// Function of `frame`, with its file and the line it is at.
+    pub fn find(&mut self, frame: &PyFrame) -> Option<Symbol> {
+        let (version, mem) = (self.version?, self.mem.as_ref()?);
+        let addr = frame.code & !PY_ENTRY_FRAME;
+        let code = self
+            .codes
+            .entry(addr)
+            .or_insert_with(|| read_code(mem, version, addr).map(Arc::new))
+            .clone()?;
+
+        // the instruction pointer is at a code unit of co_code_adaptive,
+        // before the first one until the frame starts running
+        let start = addr + version.code_adaptive;
+        let line = match frame.instr.checked_sub(start) {
+            Some(offset) => addr2line(&code.linetable, code.firstlineno, offset / 2),
+            None => Some(code.firstlineno),
+        };
+        Some(Symbol {
+            file: Some(code.filename.clone()),
+            line,
+            ..Symbol::new(addr, Some(code.qualname.clone()))
+        })
+    }
+}
+
+fn read_code(mem: &File, version: &PyVersion, addr: u64) -> Option<PyCode> {
+    let linetable = read_u64(mem, addr + version.code_linetable)?;
+    let size = read_u64(mem, linetable + BYTES_SIZE)?;
+    if size > MAX_LINETABLE_LEN {
+        return None;
+    }
+    Some(PyCode {
+        qualname: read_str(mem, version, read_u64(mem, addr + version.code_qualname)?)?,
+        filename: read_str(mem, version, read_u64(mem, addr + version.code_filename)?)?,
+        firstlineno: read_bytes(mem, addr + version.code_firstlineno, 4)
+            .map(|b| i32::from_le_bytes(b.try_into().unwrap()).max(0) as u32)?,
+        linetable: read_bytes(mem, linetable + BYTES_DATA, size)?,
+    })
+}
+
+fn read_bytes(mem: &File, addr: u64, len: u64) -> Option<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    mem.read_exact_at(&mut buf, addr).ok()?;
+    Some(buf)
+}
+
+fn read_u64(mem: &File, addr: u64) -> Option<u64> {
+    let bytes = read_bytes(mem, addr, 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A compact `str` object, the form of every identifier and file name.
+fn read_str(mem: &File, version: &PyVersion, addr: u64) -> Option<String> {
+    let len = read_u64(mem, addr + UNICODE_LENGTH)?;
+    let state = read_bytes(mem, addr + UNICODE_STATE, 4)?;
+    let state = u32::from_le_bytes(state.try_into().unwrap());
+    // interned:2, kind:3, compact:1, ascii:1
+    let (kind, compact, ascii) = ((state >> 2) & 7, (state >> 5) & 1, (state >> 6) & 1);
+    if compact == 0 || !matches!(kind, 1 | 2 | 4) || len > MAX_STRING_LEN {
+        return None;
+    }
+    let data = addr
+        + match ascii {
+            1 => version.ascii_data,
+            _ => version.compact_data,
+        };
+    let bytes = read_bytes(mem, data, len * kind as u64)?;
+    // one code point per unit of `kind` bytes
+    bytes
+        .chunks_exact(kind as usize)
+        .map(|unit| {
+            let mut cp = [0; 4];
+            cp[..unit.len()].copy_from_slice(unit);
+            char::from_u32(u32::from_le_bytes(cp))
+        })
+        .collect()
+}
+
+/// Line of the code unit `index` in a 3.11+ location table, see
+/// Objects/locations.md in the CPython sources.
+fn addr2line(table: &[u8], firstlineno: u32, index: u64) -> Option<u32> {
+    let mut bytes = table.iter().copied();
+    let mut line = firstlineno as i64;
+    let mut start = 0;
+    while let Some(first) = bytes.next() {
+        let (code, len) = ((first >> 3) & 15, (first & 7) as u64 + 1);
+        match code {
+            // no location
+            15 => {}
+            // long form: line delta, end line delta, column, end column
+            14 => {
+                line += read_svarint(&mut bytes)?;
+                for _ in 0..3 {
+                    read_varint(&mut bytes)?;
+                }
+            }
+            // no column
+            13 => line += read_svarint(&mut bytes)?,
+            // one line forms: the delta is in the code, then two columns
+            10..=12 => {
+                line += (code - 10) as i64;
+                bytes.nth(1)?;
+            }
+            // short forms: same line, one byte of columns
+            _ => {
+                bytes.next()?;
+            }
+        }
+        if (start..start + len).contains(&index) {
+            return match code {
+                15 => None,
+                _ => u32::try_from(line).ok(),
+            };
+        }
+        start += len;
+    }
+    None
+}
+
+/// Little endian groups of 6 bits, bit 6 set on all but the last byte.
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut byte = bytes.next()?;
+    let mut value = (byte & 63) as u64;
+    let mut shift = 0;
+    while byte & 64 != 0 {
+        byte = bytes.next()?;
+        shift += 6;
+        value |= ((byte & 63) as u64).checked_shl(shift)?;
+    }
+    Some(value)
+}
+
+/// A varint with the sign in the lowest bit.
+fn read_svarint(bytes: &mut impl Iterator<Item = u8>) -> Option<i64> {
+    let value = read_varint(bytes)?;
+    match value & 1 {
+        1 => Some(-((value >> 1) as i64)),
+        _ => Some((value >> 1) as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Command, Stdio},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    const SCRIPT: &str = "import time
+def inner():
+    print('ready', flush=True)
+    while True:
+        time.sleep(1)
+def outer():
+    inner()
+outer()
+";
+
+    /// Frames of the thread `tid`, walked the way the eBPF program does.
+    fn walk(mem: &File, runtime: &PyRuntime, tid: u32) -> Vec<PyFrame> {
+        let off = &runtime.version.offsets;
+        let interp = read_u64(mem, runtime.addr + off.runtime_interpreters_head).unwrap();
+        let mut tstate = read_u64(mem, interp + off.interp_threads_head).unwrap();
+        while read_u64(mem, tstate + off.tstate_native_thread_id).unwrap() != tid as u64 {
+            tstate = read_u64(mem, tstate + off.tstate_next).unwrap();
+        }
+        let mut frame = read_u64(mem, tstate + off.tstate_frame).unwrap();
+        if off.cframe_current_frame != PY_NONE {
+            frame = read_u64(mem, frame + off.cframe_current_frame).unwrap();
+        }
+
+        let mut frames = Vec::<PyFrame>::new();
+        while frame != 0 {
+            let owner = read_bytes(mem, frame + off.frame_owner, 1).unwrap()[0];
+            if owner == doctor_common::PY_FRAME_OWNED_BY_CSTACK {
+                frames.last_mut().unwrap().code |= PY_ENTRY_FRAME;
+            } else {
+                let entry = off.frame_is_entry != PY_NONE
+                    && read_bytes(mem, frame + off.frame_is_entry, 1).unwrap()[0] != 0;
+                frames.push(PyFrame {
+                    code: read_u64(mem, frame + off.frame_code).unwrap() | entry as u64,
+                    instr: read_u64(mem, frame + off.frame_instr).unwrap(),
+                });
+            }
+            frame = read_u64(mem, frame + off.frame_previous).unwrap();
+        }
+        frames
+    }
+
+    #[test]
+    fn test_python_frames() {
+        let mut child = Command::new("python3")
+            .args(["-c", SCRIPT])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("python3 on the PATH");
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        // stopped in the sleep, the frames do not move while they are read
+        thread::sleep(Duration::from_millis(100));
+        unsafe { libc::kill(child.id() as i32, libc::SIGSTOP) };
+        thread::sleep(Duration::from_millis(100));
+
+        let proc = ProcessMetadata::new(child.id()).unwrap();
+        let runtime = PyRuntime::find(&proc).unwrap();
+        let frames = runtime.map(|runtime| {
+            let mem = File::open(format!("/proc/{}/mem", child.id())).unwrap();
+            let frames = walk(&mem, &runtime, child.id());
+            let mut symbols = PythonSymbols::new(&proc);
+            frames
+                .iter()
+                .map(|f| {
+                    let sym = symbols.find(f).unwrap();
+                    (sym.name.unwrap(), sym.line, f.code & PY_ENTRY_FRAME != 0)
+                })
+                .collect::<Vec<_>>()
+        });
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let frames = frames.expect("python3 is CPython 3.11 to 3.13");
+        assert_eq!(
+            frames,
+            [
+                ("inner".to_string(), Some(5), false),
+                ("outer".to_string(), Some(7), false),
+                ("<module>".to_string(), Some(8), true),
+            ]
+        );
+    }
+}
@@ -5,14 +5,50 @@ use std::{fmt::Display, hash::Hasher};
 pub struct Symbol {
     pub(crate) addr: u64,
     pub(crate) name: Option<String>,
+    pub(crate) file: Option<String>,
+    pub(crate) line: Option<u32>,
+    /// functions inlined at the address, innermost first
+    pub(crate) inlines: Vec<InlinedFrame>,
+}
+
+/// A function the compiler inlined into its caller, with the source position
+/// inside it.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct InlinedFrame {
+    pub(crate) name: String,
+    pub(crate) file: Option<String>,
+    pub(crate) line: Option<u32>,
+}
+
+impl Symbol {
+    pub fn new(addr: u64, name: Option<String>) -> Self {
+        Self {
+            addr,
+            name,
+            file: None,
+            line: None,
+            inlines: Vec::new(),
+        }
+    }
 }
 
 impl Display for Symbol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for inline in &self.inlines {
+            write!(f, "{} (inlined)", inline.name)?;
+            if let (Some(file), Some(line)) = (&inline.file, inline.line) {
+                write!(f, " at {}:{}", file, line)?;
+            }
+            writeln!(f)?;
+        }
         match &self.name {
-            Some(name) => write!(f, "{} (f_0x{:016X})", name, self.addr),
-            None => write!(f, "Unnamed (f_0x{:016X})", self.addr),
+            Some(name) => write!(f, "{} (f_0x{:016X})", name, self.addr)?,
+            None => write!(f, "Unnamed (f_0x{:016X})", self.addr)?,
+        }
+        if let (Some(file), Some(line)) = (&self.file, self.line) {
+            write!(f, " at {}:{}", file, line)?;
         }
+        Ok(())
     }
 }
 
@@ -0,0 +1,540 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use goblin::elf::{program_header::PT_LOAD, ProgramHeader};
+use sled::{Db, IVec, Tree};
+
+use super::{
+    error::SymbolizerError,
+    golang::PclntabLocation,
+    symbol::{InlinedFrame, Symbol},
+};
+
+// bumped when the encoding of a table changes, older entries are dropped
+const FORMAT_VERSION: u8 = 1;
+
+/// What is kept of an ELF in the symbol cache: what takes seconds to parse
+/// and demangle in large binaries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolTable {
+    pub pt_loads: Vec<ProgramHeader>,
+    pub symbols: BTreeSet<Symbol>,
+    /// the ELF or its debug file with DWARF, read again for source lines and
+    /// inlined functions of addresses not in the `SymbolFrames` of the build
+    pub dwarf: Option<PathBuf>,
+    pub golang: Option<PclntabLocation>,
+}
+
+/// Source position of an address and the functions inlined at it, innermost
+/// first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceFrames {
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub inlines: Vec<InlinedFrame>,
+}
+
+/// Symbol tables on disk by build-id, least recently used first out once
+/// they take more than `capacity` bytes.
+#[derive(Clone)]
+pub struct SymbolCache {
+    db: Db,
+    tables: Tree, // build-id -> table
+    frames: Tree, // build-id, address -> source frames
+    usage: Tree,  // build-id -> last use, size of the table and its frames
+    capacity: u64,
+}
+
+/// Source frames of the addresses of one build looked up in its DWARF, so
+/// that the DWARF is read again only for addresses not looked up before.
+/// They are kept as long as the table of the build.
+#[derive(Clone)]
+pub struct SymbolFrames {
+    cache: SymbolCache,
+    build_id: String,
+}
+
+impl SymbolCache {
+    pub fn open(path: &Path, capacity: u64) -> Result<SymbolCache, SymbolizerError> {
+        let db = sled::Config::new().path(path).open()?;
+        Ok(Self {
+            tables: db.open_tree("tables")?,
+            frames: db.open_tree("frames")?,
+            usage: db.open_tree("usage")?,
+            db,
+            capacity,
+        })
+    }
+
+    /// `open`, once a cache dropped just before lets go of its lock: sled
+    /// releases it from its flusher thread.
+    #[cfg(test)]
+    pub fn reopen(path: &Path, capacity: u64) -> SymbolCache {
+        for _ in 0..200 {
+            if let Ok(cache) = Self::open(path, capacity) {
+                return cache;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        Self::open(path, capacity).unwrap()
+    }
+
+    pub fn get(&self, build_id: &str) -> Result<Option<SymbolTable>, SymbolizerError> {
+        let Some(raw) = self.tables.get(build_id)? else {
+            return Ok(None);
+        };
+        match SymbolTable::decode(&raw) {
+            Some(table) => {
+                // the size counts the frames kept since
+                self.usage.fetch_and_update(build_id, |value| {
+                    let size = value.and_then(|value| read_u64(value, 8));
+                    Some(usage(size.unwrap_or(raw.len() as u64)))
+                })?;
+                Ok(Some(table))
+            }
+            None => {
+                self.remove(build_id.as_bytes())?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn insert(&self, build_id: &str, table: &SymbolTable) -> Result<(), SymbolizerError> {
+        let raw = table.encode();
+        let size = raw.len() as u64;
+        if size > self.capacity {
+            return Ok(());
+        }
+        self.evict(size)?;
+        self.tables.insert(build_id, raw)?;
+        self.usage.insert(build_id, usage(size))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Source frames of the build `build_id`.
+    pub fn frames(&self, build_id: &str) -> SymbolFrames {
+        SymbolFrames {
+            cache: self.clone(),
+            build_id: build_id.into(),
+        }
+    }
+
+    /// Drops the table of `build_id`, loaded again with the debug info found
+    /// since.
+    pub fn invalidate(&self, build_id: &str) -> Result<(), SymbolizerError> {
+        self.remove(build_id.as_bytes())
+    }
+
+    /// Drops the least recently used tables until `size` more bytes fit.
+    fn evict(&self, size: u64) -> Result<(), SymbolizerError> {
+        let mut entries = Vec::new();
+        for entry in self.usage.iter() {
+            let (key, value) = entry?;
+            if let (Some(used), Some(size)) = (read_u64(&value, 0), read_u64(&value, 8)) {
+                entries.push((used, size, key));
+            }
+        }
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort();
+        for (_, entry_size, key) in entries {
+            if total + size <= self.capacity {
+                break;
+            }
+            log::debug!("evict symbols of {}", String::from_utf8_lossy(&key));
+            self.remove(&key)?;
+            total -= entry_size;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), SymbolizerError> {
+        self.tables.remove(key)?;
+        self.usage.remove(key)?;
+        for entry in self.frames.scan_prefix(frames_prefix(key)) {
+            self.frames.remove(entry?.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl SymbolFrames {
+    pub fn get(&self, addr: u64) -> Result<Option<SourceFrames>, SymbolizerError> {
+        Ok(self
+            .cache
+            .frames
+            .get(self.key(addr))?
+            .and_then(|raw| SourceFrames::decode(&raw)))
+    }
+
+    /// Keeps the frames of `addr`, counted in the size of the table of the
+    /// build and making room like tables do. Nothing is kept when the table
+    /// is not.
+    pub fn insert(&self, addr: u64, frames: &SourceFrames) -> Result<(), SymbolizerError> {
+        let cache = &self.cache;
+        let raw = frames.encode();
+        let size = raw.len() as u64;
+        if !cache.usage.contains_key(&self.build_id)? {
+            return Ok(());
+        }
+        cache.evict(size)?;
+        // unless the table itself made room
+        if !cache.usage.contains_key(&self.build_id)? {
+            return Ok(());
+        }
+        cache.frames.insert(self.key(addr), raw)?;
+        cache.usage.fetch_and_update(&self.build_id, |value| {
+            let value = value?;
+            let (used, total) = (read_u64(value, 0)?, read_u64(value, 8)?);
+            Some([used.to_le_bytes(), (total + size).to_le_bytes()].concat())
+        })?;
+        Ok(())
+    }
+
+    fn key(&self, addr: u64) -> Vec<u8> {
+        let mut key = frames_prefix(self.build_id.as_bytes());
+        key.extend_from_slice(&addr.to_be_bytes());
+        key
+    }
+}
+
+fn frames_prefix(build_id: &[u8]) -> Vec<u8> {
+    [build_id, b"/"].concat()
+}
+
+fn usage(size: u64) -> IVec {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut value = now.to_le_bytes().to_vec();
+    value.extend_from_slice(&size.to_le_bytes());
+    value.into()
+}
+
+fn read_u64(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+impl SymbolTable {
+    // version, DWARF path, then the pclntab location, PT_LOADs and symbols,
+    // each prefixed by their count
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![FORMAT_VERSION];
+        let dwarf = self.dwarf.as_deref().unwrap_or(Path::new(""));
+        put_str(&mut buf, &dwarf.to_string_lossy());
+        buf.extend_from_slice(&(self.golang.iter().len() as u64).to_le_bytes());
+        if let Some(golang) = &self.golang {
+            buf.extend_from_slice(&(golang.range.start as u64).to_le_bytes());
+            buf.extend_from_slice(&(golang.range.end as u64).to_le_bytes());
+            put_opt_u64(&mut buf, golang.text);
+        }
+        buf.extend_from_slice(&(self.pt_loads.len() as u64).to_le_bytes());
+        for h in &self.pt_loads {
+            for field in [
+                h.p_flags as u64,
+                h.p_offset,
+                h.p_vaddr,
+                h.p_filesz,
+                h.p_memsz,
+            ] {
+                buf.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        buf.extend_from_slice(&(self.symbols.len() as u64).to_le_bytes());
+        for sym in &self.symbols {
+            buf.extend_from_slice(&sym.addr.to_le_bytes());
+            put_str(&mut buf, sym.name.as_deref().unwrap_or_default());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<SymbolTable> {
+        if *buf.first()? != FORMAT_VERSION {
+            return None;
+        }
+        let mut reader = Reader { buf, at: 1 };
+        let dwarf = Some(reader.str()?)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        let golang = match reader.u64()? {
+            0 => None,
+            _ => Some(PclntabLocation {
+                range: reader.u64()? as usize..reader.u64()? as usize,
+                text: reader.opt_u64()?,
+            }),
+        };
+
+        let mut pt_loads = Vec::new();
+        for _ in 0..reader.u64()? {
+            pt_loads.push(ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: reader.u64()? as u32,
+                p_offset: reader.u64()?,
+                p_vaddr: reader.u64()?,
+                p_filesz: reader.u64()?,
+                p_memsz: reader.u64()?,
+                ..Default::default()
+            });
+        }
+        let mut symbols = BTreeSet::new();
+        for _ in 0..reader.u64()? {
+            let addr = reader.u64()?;
+            let name = reader.str()?;
+            symbols.insert(Symbol::new(addr, Some(name.to_owned())));
+        }
+        Some(SymbolTable {
+            pt_loads,
+            symbols,
+            dwarf,
+            golang,
+        })
+    }
+}
+
+impl SourceFrames {
+    // version, file and line, then the inlined functions prefixed by their
+    // count
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![FORMAT_VERSION];
+        put_opt_str(&mut buf, self.file.as_deref());
+        put_opt_u64(&mut buf, self.line.map(u64::from));
+        buf.extend_from_slice(&(self.inlines.len() as u64).to_le_bytes());
+        for inline in &self.inlines {
+            put_str(&mut buf, &inline.name);
+            put_opt_str(&mut buf, inline.file.as_deref());
+            put_opt_u64(&mut buf, inline.line.map(u64::from));
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<SourceFrames> {
+        if *buf.first()? != FORMAT_VERSION {
+            return None;
+        }
+        let mut reader = Reader { buf, at: 1 };
+        let file = reader.opt_str()?;
+        let line = reader.opt_u32()?;
+        let mut inlines = Vec::new();
+        for _ in 0..reader.u64()? {
+            inlines.push(InlinedFrame {
+                name: reader.str()?.to_owned(),
+                file: reader.opt_str()?,
+                line: reader.opt_u32()?,
+            });
+        }
+        Some(SourceFrames {
+            file,
+            line,
+            inlines,
+        })
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// options are prefixed by their count, as lists
+fn put_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    buf.extend_from_slice(&(s.iter().len() as u64).to_le_bytes());
+    if let Some(s) = s {
+        put_str(buf, s);
+    }
+}
+
+fn put_opt_u64(buf: &mut Vec<u8>, value: Option<u64>) {
+    buf.extend_from_slice(&(value.iter().len() as u64).to_le_bytes());
+    if let Some(value) = value {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u64(&mut self) -> Option<u64> {
+        let value = read_u64(self.buf, self.at)?;
+        self.at += 8;
+        Some(value)
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u64()? as usize;
+        let s = self.buf.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        std::str::from_utf8(s).ok()
+    }
+
+    fn opt_u64(&mut self) -> Option<Option<u64>> {
+        match self.u64()? {
+            0 => Some(None),
+            _ => self.u64().map(Some),
+        }
+    }
+
+    fn opt_u32(&mut self) -> Option<Option<u32>> {
+        match self.opt_u64()? {
+            Some(value) => u32::try_from(value).ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    fn opt_str(&mut self) -> Option<Option<String>> {
+        match self.u64()? {
+            0 => Some(None),
+            _ => self.str().map(|s| Some(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(names: &[&str]) -> SymbolTable {
+        SymbolTable {
+            pt_loads: vec![ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: 5,
+                p_offset: 0x1000,
+                p_vaddr: 0x401000,
+                p_filesz: 0x2000,
+                p_memsz: 0x3000,
+                ..Default::default()
+            }],
+            symbols: names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Symbol::new(i as u64 * 0x10, Some(name.to_string())))
+                .collect(),
+            dwarf: Some("/usr/lib/debug/.build-id/ab/cdef.debug".into()),
+            golang: Some(PclntabLocation {
+                range: 0x2000..0x3000,
+                text: Some(0x401000),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_symbol_cache() {
+        let dir = std::env::temp_dir().join(format!("doctor-symbol-cache-{}", std::process::id()));
+        let small = table(&["main", "std::rt::lang_start"]);
+        let size = small.encode().len() as u64;
+        {
+            let cache = SymbolCache::open(&dir, size * 2).unwrap();
+            cache.insert("aa", &small).unwrap();
+            assert_eq!(cache.get("aa").unwrap(), Some(small.clone()));
+            assert_eq!(cache.get("bb").unwrap(), None);
+        }
+
+        // entries survive a restart
+        let cache = SymbolCache::reopen(&dir, size * 2);
+        assert_eq!(cache.get("aa").unwrap(), Some(small.clone()));
+        // the oldest entry makes room, larger ones than the cache are not kept
+        let oldest = [0u64.to_le_bytes(), size.to_le_bytes()].concat();
+        cache.usage.insert("aa", oldest).unwrap();
+        cache.insert("bb", &small).unwrap();
+        cache.insert("cc", &small).unwrap();
+        assert_eq!(cache.get("aa").unwrap(), None);
+        assert!(cache.get("bb").unwrap().is_some());
+        cache.insert("dd", &table(&["a"; 64])).unwrap();
+        assert_eq!(cache.get("dd").unwrap(), None);
+        assert!(cache.get("cc").unwrap().is_some());
+
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_symbol_frames() {
+        let dir = std::env::temp_dir().join(format!("doctor-symbol-frames-{}", std::process::id()));
+        let cache = SymbolCache::open(&dir, 1 << 20).unwrap();
+        let frames = SourceFrames {
+            file: Some("src/main.rs".into()),
+            line: Some(42),
+            inlines: vec![InlinedFrame {
+                name: "core::ptr::read".into(),
+                file: None,
+                line: Some(0),
+            }],
+        };
+
+        // only kept along with the table of the build
+        cache.frames("aa").insert(0x10, &frames).unwrap();
+        assert_eq!(cache.frames("aa").get(0x10).unwrap(), None);
+
+        let small = table(&["main"]);
+        cache.insert("aa", &small).unwrap();
+        cache.frames("aa").insert(0x10, &frames).unwrap();
+        cache
+            .frames("aa")
+            .insert(0x20, &SourceFrames::default())
+            .unwrap();
+        assert_eq!(cache.frames("aa").get(0x10).unwrap(), Some(frames));
+        assert_eq!(
+            cache.frames("aa").get(0x20).unwrap(),
+            Some(SourceFrames::default())
+        );
+        assert_eq!(cache.frames("aa").get(0x30).unwrap(), None);
+        assert_eq!(cache.frames("ab").get(0x10).unwrap(), None);
+        let size = read_u64(&cache.usage.get("aa").unwrap().unwrap(), 8).unwrap();
+        assert!(size > small.encode().len() as u64);
+        // still counted once the table is used again
+        cache.get("aa").unwrap().unwrap();
+        assert_eq!(
+            read_u64(&cache.usage.get("aa").unwrap().unwrap(), 8),
+            Some(size)
+        );
+
+        // and dropped with it
+        cache.remove(b"aa").unwrap();
+        assert_eq!(cache.frames.len(), 0);
+
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_frames_capacity() {
+        let dir =
+            std::env::temp_dir().join(format!("doctor-frames-capacity-{}", std::process::id()));
+        let small = table(&["main"]);
+        let frames = SourceFrames {
+            file: Some("src/main.rs".into()),
+            line: Some(42),
+            inlines: Vec::new(),
+        };
+        let (table_size, frames_size) = (small.encode().len() as u64, frames.encode().len() as u64);
+        let capacity = table_size * 2 + frames_size * 4;
+        let cache = SymbolCache::open(&dir, capacity).unwrap();
+        cache.insert("aa", &small).unwrap();
+        cache.insert("bb", &small).unwrap();
+        let oldest = [0u64.to_le_bytes(), table_size.to_le_bytes()].concat();
+        cache.usage.insert("aa", oldest).unwrap();
+
+        // the frames of bb make room past the capacity
+        for addr in 0..5 {
+            cache.frames("bb").insert(addr, &frames).unwrap();
+        }
+        assert_eq!(cache.get("aa").unwrap(), None);
+        assert_eq!(cache.frames("bb").get(4).unwrap(), Some(frames));
+        let used: u64 = cache
+            .usage
+            .iter()
+            .map(|entry| read_u64(&entry.unwrap().1, 8).unwrap())
+            .sum();
+        assert_eq!(used, table_size + frames_size * 5);
+        assert!(used <= capacity);
+
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
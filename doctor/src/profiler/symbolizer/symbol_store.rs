@@ -1,43 +1,294 @@
 use crate::profiler::symbolizer::elf::ElfMetadata;
-use std::path::PathBuf;
+use std::{
+    fs::metadata,
+    os::linux::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use super::debuginfo::DebugDirs;
+use super::debuginfod::{Debuginfod, Fetched};
 use super::error::SymbolizerError;
 use super::symbol::Symbol;
+use super::symbol_cache::SymbolCache;
+use super::symbolizer::SymbolizerOptions;
 
 use moka::sync::Cache;
 
+/// Identity of a file on disk, inode numbers alone collide across
+/// filesystems, overlayfs layers and bind mounts.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FileId {
+    dev: u64,
+    inode: u64,
+    mtime: (i64, i64),
+    size: u64,
+}
+
+impl FileId {
+    pub fn new(path: &Path) -> Result<FileId, SymbolizerError> {
+        let meta = metadata(path).map_err(SymbolizerError::GetInodeFailed)?;
+        Ok(Self {
+            dev: meta.st_dev(),
+            inode: meta.st_ino(),
+            mtime: (meta.st_mtime(), meta.st_mtime_nsec()),
+            size: meta.st_size(),
+        })
+    }
+}
+
+/// Cache key of an ELF, the same binary in several places shares its build-id.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum ElfKey {
+    BuildId(String),
+    File(FileId),
+}
+
+impl ElfKey {
+    fn build_id(self) -> Option<String> {
+        match self {
+            ElfKey::BuildId(id) => Some(id),
+            ElfKey::File(_) => None,
+        }
+    }
+}
+
+/// Symbols of offsets in an ELF, with its GNU build-id.
+pub type Symbols = (Option<String>, Vec<Result<Symbol, SymbolizerError>>);
+
 pub struct SymbolStore {
-    cache: Cache<u64, ElfMetadata>, // inode -> elf
+    keys: Cache<FileId, ElfKey>,
+    cache: Cache<ElfKey, Arc<ElfMetadata>>,
+    disk: Option<SymbolCache>, // build-id -> symbol table, kept across runs
+    debug_dirs: DebugDirs,
+    debuginfod: Option<Debuginfod>,
 }
 
 impl SymbolStore {
-    pub fn new(path: PathBuf) -> Result<SymbolStore, SymbolizerError> {
+    /// A store keeping symbol tables of up to `cache_size` bytes under
+    /// `cache_dir` across runs, or only in memory without `cache_dir`.
+    /// debuginfod downloads are kept there too.
+    pub fn new(opts: SymbolizerOptions) -> Result<SymbolStore, SymbolizerError> {
+        let disk = match &opts.cache_dir {
+            Some(dir) if opts.cache_size > 0 => {
+                match SymbolCache::open(&dir.join("tables"), opts.cache_size) {
+                    Ok(disk) => Some(disk),
+                    // e.g. held by another doctor
+                    Err(e) => {
+                        log::warn!("symbol cache {:?} disabled: {}", dir, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let debuginfod = match &opts.cache_dir {
+            Some(dir) if !opts.debuginfod_urls.is_empty() => Some(Debuginfod::new(
+                opts.debuginfod_urls,
+                dir.join("debuginfod"),
+                opts.debuginfod_wait,
+            )?),
+            _ => None,
+        };
         Ok(Self {
+            keys: Cache::new(1000),
             cache: Cache::new(100),
+            disk,
+            debug_dirs: opts.debug_dirs,
+            debuginfod,
         })
     }
 
-    pub fn get_symbol(&self, dso: &PathBuf, offset: u64) -> Result<Symbol, SymbolizerError> {
-        let elf = self.fetch_elf(dso)?;
-        elf.find_symbol(offset)
+    /// Symbols at `offsets` of `dso`, a path inside `rootfs`, in their order.
+    pub fn get_symbols(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+        offsets: &[u64],
+    ) -> Result<Symbols, SymbolizerError> {
+        self.reload_downloaded();
+        let (key, elf) = self.fetch_elf(rootfs, dso)?;
+        Ok((key.build_id(), elf.find_symbols(offsets)))
+    }
+
+    /// Like `get_symbols`, for the build `build_id` of `dso` recorded on
+    /// another machine.
+    pub fn get_symbols_by_build_id(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+        build_id: &str,
+        offsets: &[u64],
+    ) -> Result<Symbols, SymbolizerError> {
+        self.reload_downloaded();
+        let elf = self.fetch_elf_by_build_id(rootfs, dso, build_id)?;
+        Ok((Some(build_id.into()), elf.find_symbols(offsets)))
+    }
+
+    /// GNU build-id of `dso`, hex encoded.
+    pub fn build_id(&self, dso: &Path) -> Result<Option<String>, SymbolizerError> {
+        Ok(self.key(dso)?.build_id())
+    }
+
+    fn fetch_elf(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+    ) -> Result<(ElfKey, Arc<ElfMetadata>), SymbolizerError> {
+        let path = rootfs.join(dso.strip_prefix("/").unwrap_or(dso));
+        let key = self.load_elf(rootfs, dso, &path)?;
+        match self.cache.get(&key) {
+            Some(val) => Ok((key, val)),
+            None => Err(SymbolizerError::FetchElfFailed(path)),
+        }
     }
 
-    fn fetch_elf(&self, dso: &PathBuf) -> Result<ElfMetadata, SymbolizerError> {
-        let inode = self.load_elf(dso)?;
-        match self.cache.get(&inode) {
+    /// The build `build_id` of `dso`: `dso` itself when it is the same build
+    /// here, else its debug file or the build from debuginfod.
+    fn fetch_elf_by_build_id(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+        build_id: &str,
+    ) -> Result<Arc<ElfMetadata>, SymbolizerError> {
+        let key = ElfKey::BuildId(build_id.into());
+        if !self.cache.contains_key(&key) {
+            let path = rootfs.join(dso.strip_prefix("/").unwrap_or(dso));
+            let elf = if self.build_id(&path).ok().flatten().as_deref() == Some(build_id) {
+                self.load_cached(&path, build_id, || {
+                    self.load(rootfs, dso, &path, Some(build_id))
+                })?
+            } else {
+                let found = self
+                    .debug_dirs
+                    .find(rootfs, dso, Some(build_id))
+                    .or_else(|| self.fetch_build(build_id))
+                    .ok_or_else(|| SymbolizerError::BuildNotFound(build_id.into()))?;
+                self.load_cached(&found, build_id, || ElfMetadata::new(found.clone()))?
+            };
+            self.cache.insert(key.clone(), Arc::new(elf));
+        }
+        match self.cache.get(&key) {
             Some(val) => Ok(val),
-            None => Err(SymbolizerError::FetchElfFailed(dso.clone())),
+            None => Err(SymbolizerError::FetchElfFailed(dso.into())),
         }
     }
 
-    fn load_elf(&self, dso: &PathBuf) -> Result<u64, SymbolizerError> {
-        let inode = ElfMetadata::get_inode(dso)?;
-        if !self.cache.contains_key(&inode) {
-            log::info!("load elf {dso:?}, {inode}");
-            self.cache.insert(inode, ElfMetadata::new(dso.clone())?);
+    fn load_elf(&self, rootfs: &Path, dso: &Path, path: &Path) -> Result<ElfKey, SymbolizerError> {
+        let key = self.key(path)?;
+        if !self.cache.contains_key(&key) {
+            log::info!("load elf {path:?}, {key:?}");
+            let elf = match &key {
+                ElfKey::BuildId(id) => {
+                    self.load_cached(path, id, || self.load(rootfs, dso, path, Some(id)))?
+                }
+                ElfKey::File(_) => self.load(rootfs, dso, path, None)?,
+            };
+            self.cache.insert(key.clone(), Arc::new(elf));
         }
 
-        Ok(inode)
+        Ok(key)
+    }
+
+    /// The ELF from its table in the symbol cache, or by `load` and then
+    /// kept there.
+    fn load_cached(
+        &self,
+        path: &Path,
+        build_id: &str,
+        load: impl FnOnce() -> Result<ElfMetadata, SymbolizerError>,
+    ) -> Result<ElfMetadata, SymbolizerError> {
+        let Some(disk) = &self.disk else {
+            return load();
+        };
+        match disk.get(build_id) {
+            // the debug file may have been removed since
+            Ok(Some(table)) if table.dwarf.as_ref().is_none_or(|p| p.exists()) => {
+                let mut elf =
+                    ElfMetadata::from_table(path.to_path_buf(), Some(build_id.into()), table);
+                elf.keep_frames(disk.frames(build_id));
+                return Ok(elf);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("symbol cache: {}", e),
+        }
+        let mut elf = load()?;
+        // kept once its debug info is downloaded
+        if self.downloading(build_id) {
+            return Ok(elf);
+        }
+        if let Err(e) = disk.insert(build_id, &elf.table()) {
+            log::warn!("symbol cache: {}", e);
+        }
+        elf.keep_frames(disk.frames(build_id));
+        Ok(elf)
+    }
+
+    fn load(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+        path: &Path,
+        build_id: Option<&str>,
+    ) -> Result<ElfMetadata, SymbolizerError> {
+        let debug = self
+            .debug_dirs
+            .find(rootfs, dso, build_id)
+            .or_else(|| self.fetch_debuginfo(path, build_id?));
+        ElfMetadata::with_debug_file(path.to_path_buf(), debug)
+    }
+
+    /// Debug info of a stripped ELF from debuginfod, or its unstripped build.
+    fn fetch_debuginfo(&self, path: &Path, build_id: &str) -> Option<PathBuf> {
+        self.debuginfod.as_ref()?;
+        if ElfMetadata::read_has_dwarf(path).unwrap_or(true) {
+            return None;
+        }
+        self.fetch_build(build_id)
+    }
+
+    /// Debug info or executable of the build from debuginfod, None while it
+    /// is downloaded in the background.
+    fn fetch_build(&self, build_id: &str) -> Option<PathBuf> {
+        match self.debuginfod.as_ref()?.fetch(build_id) {
+            Fetched::Found(path) => Some(path),
+            Fetched::Pending | Fetched::Missing => None,
+        }
+    }
+
+    fn downloading(&self, build_id: &str) -> bool {
+        self.debuginfod
+            .as_ref()
+            .is_some_and(|debuginfod| debuginfod.downloading(build_id))
+    }
+
+    /// Loads again the ELFs symbolized without the debug info downloaded
+    /// since in the background.
+    fn reload_downloaded(&self) {
+        let Some(debuginfod) = &self.debuginfod else {
+            return;
+        };
+        for build_id in debuginfod.finished() {
+            self.cache.invalidate(&ElfKey::BuildId(build_id.clone()));
+            if let Some(disk) = &self.disk {
+                if let Err(e) = disk.invalidate(&build_id) {
+                    log::warn!("symbol cache: {}", e);
+                }
+            }
+        }
+    }
+
+    fn key(&self, dso: &Path) -> Result<ElfKey, SymbolizerError> {
+        let file = FileId::new(dso)?;
+        if let Some(key) = self.keys.get(&file) {
+            return Ok(key);
+        }
+        let key = match ElfMetadata::read_build_id(dso)? {
+            Some(id) => ElfKey::BuildId(id),
+            None => ElfKey::File(file.clone()),
+        };
+        self.keys.insert(file, key.clone());
+        Ok(key)
     }
 }
 
@@ -49,9 +300,37 @@ mod tests {
     fn test_fetch_elf() {
         // 获取当前可执行文件路径
         println!("start");
-        let ss = SymbolStore::new("/home/noneback/workspace/doctor/doctor/tests".into()).unwrap();
-        let sym = ss.get_symbol(&"/root/workspace/profiler/bianque/main".into(), 0x3321b);
+        let ss = SymbolStore::new(SymbolizerOptions::default()).unwrap();
+        let syms = ss
+            .get_symbols(
+                Path::new("/"),
+                Path::new("/root/workspace/profiler/bianque/main"),
+                &[0x3321b],
+            )
+            .unwrap();
 
-        println!("sym:\n {}", sym.unwrap());
+        println!("sym:\n {}", syms.1[0].as_ref().unwrap());
+    }
+
+    #[test]
+    fn test_build_id_key() {
+        let dir = std::env::temp_dir().join(format!("doctor-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::copy(&exe, &a).unwrap();
+        std::fs::copy(&exe, &b).unwrap();
+
+        let ss = SymbolStore::new(SymbolizerOptions::default()).unwrap();
+        let id = ss.build_id(&a).unwrap();
+        assert_eq!(id, ElfMetadata::read_build_id(&exe).unwrap());
+        // copies are other inodes of the same binary
+        assert_eq!(ss.key(&a).unwrap(), ss.key(&b).unwrap());
+        assert_ne!(FileId::new(&a).unwrap(), FileId::new(&b).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(id) = id {
+            assert!(id.len() >= 16 && id.chars().all(|c| c.is_ascii_hexdigit()));
+        }
     }
 }
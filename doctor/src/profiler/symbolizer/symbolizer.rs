@@ -1,26 +1,59 @@
-use super::{error::SymbolizerError, symbol::Symbol, symbol_store::SymbolStore};
+use super::{
+    debuginfo::DebugDirs,
+    error::SymbolizerError,
+    symbol_store::{SymbolStore, Symbols},
+};
+
+use std::path::{Path, PathBuf};
+
+/// Where symbols are kept and looked for besides the ELFs themselves.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolizerOptions {
+    /// symbol tables and debuginfod downloads, kept across runs
+    pub cache_dir: Option<PathBuf>,
+    /// bytes of symbol tables kept in `cache_dir`, 0 keeps none
+    pub cache_size: u64,
+    pub debug_dirs: DebugDirs,
+    /// servers asked for the debug info of stripped ELFs
+    pub debuginfod_urls: Vec<String>,
+    /// wait for debuginfod downloads, else symbolize without them until they
+    /// are done in the background
+    pub debuginfod_wait: bool,
+}
 
-use std::path::PathBuf;
 pub struct Symbolizer {
     dss: SymbolStore,
 }
 
 impl Symbolizer {
-    pub fn new(path: PathBuf) -> Result<Symbolizer, SymbolizerError> {
+    pub fn new(opts: SymbolizerOptions) -> Result<Symbolizer, SymbolizerError> {
         Ok(Self {
-            dss: SymbolStore::new(path)?,
+            dss: SymbolStore::new(opts)?,
         })
     }
 
-    pub fn symbolize(
+    /// Symbols at `offsets` of `dso`, in their order, with its build-id: the
+    /// ELF is looked up once and its symbol table searched in one pass for
+    /// all of them.
+    pub fn batch_symbolize(
         &self,
-        rootfs: &PathBuf,
-        dso: &PathBuf,
-        offset: u64,
-    ) -> Result<Symbol, SymbolizerError> {
-        let dso_path = rootfs.join(dso.strip_prefix("/").unwrap());
+        rootfs: &Path,
+        dso: &Path,
+        offsets: &[u64],
+    ) -> Result<Symbols, SymbolizerError> {
+        self.dss.get_symbols(rootfs, dso, offsets)
+    }
 
-        self.dss.get_symbol(&dso_path, offset)
+    /// Like `batch_symbolize`, for the build `build_id` of `dso` recorded on
+    /// another machine.
+    pub fn batch_symbolize_build_id(
+        &self,
+        rootfs: &Path,
+        dso: &Path,
+        build_id: &str,
+        offsets: &[u64],
+    ) -> Result<Symbols, SymbolizerError> {
+        self.dss
+            .get_symbols_by_build_id(rootfs, dso, build_id, offsets)
     }
-    pub fn batch_symbolize(_dso: PathBuf, _offsets: &[u64]) {}
 }
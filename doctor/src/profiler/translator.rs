@@ -1,44 +1,76 @@
 use std::{
-    collections::BTreeMap,
-    fs::File,
-    io::{self, BufRead, BufReader},
-    path::PathBuf,
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::{anyhow, Error};
 use aya::maps::stack_trace::StackTrace;
+use doctor_common::{PyStack, PY_ENTRY_FRAME};
 
 use super::{
-    error::TranslateError, perf_record::PerfStackFrame, process::ProcessMetadata,
-    symbolizer::symbolizer::Symbolizer,
+    error::TranslateError,
+    formater::raw::Mapping,
+    perf_record::{FrameKind, PerfStackFrame},
+    process::ProcessMetadata,
+    symbolizer::{
+        error::SymbolizerError,
+        golang,
+        jit::JitSymbols,
+        kallsyms::{KernelImages, KernelSymbols, KERNEL_DSO},
+        python::PythonSymbols,
+        symbol::Symbol,
+        symbolizer::Symbolizer,
+    },
 };
 
+/// Native function of the CPython evaluation loop, one call runs Python
+/// frames up to an entry frame.
+const PY_EVAL_FRAME: &str = "_PyEval_EvalFrameDefault";
+
+/// Symbol of a DSO offset, with the build-id of the DSO.
+type DsoSymbol = (Option<String>, Result<Symbol, SymbolizerError>);
+
 pub struct Translator {
     rootfs: PathBuf,
-    ksyms: Option<BTreeMap<u64, String>>,
+    ksyms: Option<Option<KernelSymbols>>, // None until loaded, Some(None) when they cannot be
+    kernel: KernelImages,
     symbolizer: Arc<Symbolizer>,
+    symbolize: bool,                // false leaves user frames to `doctor symbolize`
+    jits: HashMap<u32, JitSymbols>, // pid -> jit symbols
+    pythons: HashMap<u32, PythonSymbols>, // pid -> python code objects
 }
 
 impl Translator {
-    pub fn new(rootfs: PathBuf) -> Self {
+    pub fn new(rootfs: PathBuf, kernel: KernelImages, symbolizer: Symbolizer) -> Self {
         Self {
             rootfs,
             ksyms: None,
-            symbolizer: Arc::new(
-                Symbolizer::new("/root/workspace/profiler/bianque/doctor/tests".into()).unwrap(),
-            ), // TODO:
+            kernel,
+            symbolizer: Arc::new(symbolizer),
+            symbolize: true,
+            jits: HashMap::new(),
+            pythons: HashMap::new(),
         }
     }
 
+    /// Leaves user frames in files unsymbolized, for `record --no-symbolize`.
+    pub fn without_symbols(mut self) -> Self {
+        self.symbolize = false;
+        self
+    }
+
+    /// Drops the JIT and Python symbols of `pid` once it exited.
+    pub fn forget(&mut self, pid: u32) {
+        self.jits.remove(&pid);
+        self.pythons.remove(&pid);
+    }
+
     pub fn translate_ktrace(&mut self, ktrace: &StackTrace) -> Result<Vec<PerfStackFrame>, Error> {
         // for frame in record.stack_frames {}
         let mut frames = Vec::new();
         for f in ktrace.frames() {
-            frames.push(
-                self.translate_ksyms(f.ip)
-                    .map_err(|e| anyhow!("translate_single -> {}", e))?,
-            );
+            frames.extend(self.translate_ksyms(f.ip));
         }
         Ok(frames)
     }
@@ -54,30 +86,41 @@ impl Translator {
             .map_err(|e| anyhow!("translate_utrace pid {} -> {}", pid, e))
     }
 
-    pub fn translate_ksyms(&mut self, ip: u64) -> Result<PerfStackFrame, Error> {
-        let elf_path = self.rootfs.join("/proc/kallsyms");
-
-        if self.ksyms.is_none() {
-            let reader = BufReader::new(File::open(&elf_path)?);
-            self.ksyms = Some(BTreeMap::new());
-            for line in reader.lines() {
-                let text = line?;
-                let parts = text.splitn(4, ' ').collect::<Vec<_>>();
-                let addr = u64::from_str_radix(parts[0], 16)
-                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, text.clone()))?;
-                let name = parts[2].to_owned();
-                self.ksyms.as_mut().unwrap().insert(addr, name);
+    /// Frames of the kernel address `ip`, the functions inlined at it first.
+    /// `unknown` when the kernel symbols cannot be loaded, reported once.
+    pub fn translate_ksyms(&mut self, ip: u64) -> Vec<PerfStackFrame> {
+        let ksyms = self.ksyms.get_or_insert_with(|| {
+            match KernelSymbols::load(&self.rootfs, &self.kernel) {
+                Ok(ksyms) => Some(ksyms),
+                Err(e) => {
+                    log::warn!("kernel frames are not symbolized: {}", e);
+                    None
+                }
             }
-        }
+        });
+        let kernel_frame = |name: &str, dso: &str| PerfStackFrame {
+            kind: FrameKind::Kernel,
+            ..PerfStackFrame::new(ip, format!("{}_[k]", name), dso.into(), ip)
+        };
+        let Some((sym, dso)) = ksyms.as_ref().and_then(|ksyms| ksyms.find(ip)) else {
+            return vec![kernel_frame("unknown", KERNEL_DSO)];
+        };
 
-        if let Some(ksyms) = &self.ksyms {
-            return Ok(ksyms
-                .range(..=ip)
-                .next_back()
-                .map(|(_, s)| PerfStackFrame::new(ip, format!("{}_[k]", s), elf_path, ip))
-                .unwrap());
+        let mut frames = Vec::new();
+        for inline in sym.inlines {
+            frames.push(PerfStackFrame {
+                file: inline.file,
+                line: inline.line,
+                inlined: true,
+                ..kernel_frame(&inline.name, &dso)
+            });
         }
-        Err(anyhow::anyhow!("translate_ksyms 0x{} NotFound", ip))
+        frames.push(PerfStackFrame {
+            file: sym.file,
+            line: sym.line,
+            ..kernel_frame(sym.name.as_deref().unwrap_or("unknown"), &dso)
+        });
+        frames
     }
 
     pub fn translate_usyms(
@@ -86,27 +129,270 @@ impl Translator {
         ips: Vec<u64>,
     ) -> Result<Vec<PerfStackFrame>, TranslateError> {
         let proc = ProcessMetadata::new(pid)?;
-        let mut frames = Vec::new();
+        let mut located = Vec::with_capacity(ips.len());
 
         for ip in ips {
             if let Ok((dso_path, offset)) = proc.abs_addr(ip) {
-                let psf = match self.symbolizer.symbolize(&proc.rootfs, &dso_path, offset) {
-                    Ok(sym) => PerfStackFrame::new(
+                located.push(UserAddr::Dso(ip, dso_path, offset));
+            } else if let Some((path, sym)) = self
+                .jits
+                .entry(pid)
+                .or_insert_with(|| JitSymbols::new(&proc))
+                .find(&proc, ip)
+            {
+                // code outside of file mappings is generated by a JIT
+                located.push(UserAddr::Jit(PerfStackFrame {
+                    file: sym.file,
+                    line: sym.line,
+                    ..PerfStackFrame::new(
                         ip,
                         sym.name.unwrap_or("unknown".into()),
-                        dso_path,
-                        offset,
-                    ),
-                    Err(e) => {
-                        log::debug!("Symbolize {}, file {:#?}: {}", pid, &dso_path, e);
-                        PerfStackFrame::new(ip, "unknown".into(), dso_path.clone(), offset)
-                    }
-                };
+                        path,
+                        ip - sym.addr,
+                    )
+                }));
+            }
+        }
 
-                frames.push(psf);
+        if !self.symbolize {
+            let frames = located.into_iter().map(|addr| match addr {
+                UserAddr::Dso(ip, dso_path, offset) => PerfStackFrame {
+                    kind: FrameKind::Unsymbolized,
+                    ..PerfStackFrame::new(ip, String::new(), dso_path, offset)
+                },
+                UserAddr::Jit(frame) => frame,
+            });
+            return Ok(frames.collect());
+        }
+        let addrs: Vec<(&Path, Option<&str>, u64)> = located
+            .iter()
+            .filter_map(|addr| match addr {
+                UserAddr::Dso(_, dso_path, offset) => Some((dso_path.as_path(), None, *offset)),
+                UserAddr::Jit(_) => None,
+            })
+            .collect();
+        let mut syms = self.batch_symbolize(&proc.rootfs, &addrs).into_iter();
+        let mut frames = Vec::new();
+        for addr in located {
+            match addr {
+                UserAddr::Dso(ip, dso_path, offset) => {
+                    let (build_id, sym) = syms.next().unwrap();
+                    if push_dso_frames(&mut frames, ip, dso_path, offset, build_id, sym) {
+                        break;
+                    }
+                }
+                UserAddr::Jit(frame) => frames.push(frame),
             }
         }
 
         Ok(frames)
     }
+
+    /// Symbolizes the frames `record --no-symbolize` left, from the mappings
+    /// their process had and the build-ids of its DSOs.
+    pub fn translate_raw(
+        &mut self,
+        frames: Vec<PerfStackFrame>,
+        mappings: &[Mapping],
+    ) -> Vec<PerfStackFrame> {
+        let mapped: Vec<Option<(&Mapping, u64)>> = frames
+            .iter()
+            .filter(|frame| frame.kind == FrameKind::Unsymbolized)
+            .map(|frame| {
+                let m = mappings
+                    .iter()
+                    .find(|m| (m.start..m.end).contains(&frame.ip))?;
+                Some((m, m.offset + (frame.ip - m.start)))
+            })
+            .collect();
+        let addrs: Vec<(&Path, Option<&str>, u64)> = mapped
+            .iter()
+            .flatten()
+            .map(|(m, offset)| (m.path.as_path(), m.build_id.as_deref(), *offset))
+            .collect();
+        let mut syms = self.batch_symbolize(&self.rootfs, &addrs).into_iter();
+        let mut mapped = mapped.into_iter();
+
+        let mut translated = Vec::with_capacity(frames.len());
+        let mut root = false;
+        for frame in frames {
+            if frame.kind != FrameKind::Unsymbolized {
+                translated.push(frame);
+                continue;
+            }
+            let ip = frame.ip;
+            let Some((mapping, offset)) = mapped.next().flatten() else {
+                if !root {
+                    translated.push(PerfStackFrame::new(ip, "unknown".into(), PathBuf::new(), 0));
+                }
+                continue;
+            };
+            let (_, sym) = syms.next().unwrap();
+            if root {
+                continue;
+            }
+            root = push_dso_frames(
+                &mut translated,
+                ip,
+                mapping.path.clone(),
+                offset,
+                mapping.build_id.clone(),
+                sym,
+            );
+        }
+        translated
+    }
+
+    /// Symbols of DSO offsets, in their order, with the build-id of their
+    /// DSO, looked up a DSO at a time. Offsets with a build-id are looked up
+    /// by it, for frames recorded on another machine.
+    fn batch_symbolize(
+        &self,
+        rootfs: &Path,
+        addrs: &[(&Path, Option<&str>, u64)],
+    ) -> Vec<DsoSymbol> {
+        let mut groups: HashMap<(&Path, Option<&str>), Vec<usize>> = HashMap::new();
+        for (i, (dso, build_id, _)) in addrs.iter().enumerate() {
+            groups.entry((dso, *build_id)).or_default().push(i);
+        }
+
+        let mut syms: Vec<Option<DsoSymbol>> = addrs.iter().map(|_| None).collect();
+        for ((dso, build_id), indices) in groups {
+            let offsets: Vec<u64> = indices.iter().map(|&i| addrs[i].2).collect();
+            let found = match build_id {
+                Some(build_id) => self
+                    .symbolizer
+                    .batch_symbolize_build_id(rootfs, dso, build_id, &offsets),
+                None => self.symbolizer.batch_symbolize(rootfs, dso, &offsets),
+            };
+            match found {
+                Ok((build_id, found)) => {
+                    for (i, sym) in indices.into_iter().zip(found) {
+                        syms[i] = Some((build_id.clone(), sym));
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Symbolize file {:#?}: {}", dso, e);
+                    for i in indices {
+                        let e = SymbolizerError::FetchElfFailed(dso.into());
+                        syms[i] = Some((None, Err(e)));
+                    }
+                }
+            }
+        }
+        syms.into_iter().flatten().collect()
+    }
+
+    /// Replaces every native frame of the evaluation loop in `native` with
+    /// the Python frames it runs. Python frames left when the native stack
+    /// ends early go to the root.
+    pub fn translate_python(
+        &mut self,
+        pid: u32,
+        stack: &PyStack,
+        native: Vec<PerfStackFrame>,
+    ) -> Vec<PerfStackFrame> {
+        let symbols = match self.pythons.entry(pid) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match ProcessMetadata::new(pid) {
+                Ok(proc) => e.insert(PythonSymbols::new(&proc)),
+                Err(e) => {
+                    log::debug!("python frames of {}: {}", pid, e);
+                    return native;
+                }
+            },
+        };
+        let mut python = stack.frames().iter().map(|frame| {
+            let entry = frame.code & PY_ENTRY_FRAME != 0;
+            let psf = match symbols.find(frame) {
+                Some(sym) => PerfStackFrame {
+                    file: sym.file.clone(),
+                    line: sym.line,
+                    ..PerfStackFrame::new(
+                        sym.addr,
+                        sym.name.unwrap_or("unknown".into()),
+                        sym.file.unwrap_or_default().into(),
+                        frame.instr.wrapping_sub(sym.addr),
+                    )
+                },
+                None => PerfStackFrame::new(
+                    frame.code & !PY_ENTRY_FRAME,
+                    "unknown".into(),
+                    PathBuf::new(),
+                    0,
+                ),
+            };
+            (psf, entry)
+        });
+
+        let mut frames = Vec::with_capacity(native.len() + stack.frames().len());
+        for frame in native {
+            let name = frame.sym.strip_suffix(".cold").unwrap_or(&frame.sym);
+            if name != PY_EVAL_FRAME || frame.inlined {
+                frames.push(frame);
+                continue;
+            }
+            let len = frames.len();
+            for (psf, entry) in python.by_ref() {
+                frames.push(psf);
+                if entry {
+                    break;
+                }
+            }
+            // an evaluation loop without Python frames left
+            if frames.len() == len {
+                frames.push(frame);
+            }
+        }
+        frames.extend(python.map(|(psf, _)| psf));
+        frames
+    }
+}
+
+/// A user address of a stack, in a DSO or in JIT generated code.
+enum UserAddr {
+    Dso(u64, PathBuf, u64), // ip, DSO, file offset
+    Jit(PerfStackFrame),
+}
+
+/// Pushes the frames of `ip`, at `offset` of `dso_path`: the functions
+/// inlined at it first, as callees of its symbol. True when it is the root of
+/// a goroutine stack, the frames past it are leftovers of the unwind.
+fn push_dso_frames(
+    frames: &mut Vec<PerfStackFrame>,
+    ip: u64,
+    dso_path: PathBuf,
+    offset: u64,
+    build_id: Option<String>,
+    sym: Result<Symbol, SymbolizerError>,
+) -> bool {
+    let psf = match sym {
+        Ok(sym) => {
+            for inline in sym.inlines {
+                frames.push(PerfStackFrame {
+                    file: inline.file,
+                    line: inline.line,
+                    inlined: true,
+                    build_id: build_id.clone(),
+                    ..PerfStackFrame::new(ip, inline.name, dso_path.clone(), offset)
+                });
+            }
+            PerfStackFrame {
+                file: sym.file,
+                line: sym.line,
+                build_id,
+                ..PerfStackFrame::new(ip, sym.name.unwrap_or("unknown".into()), dso_path, offset)
+            }
+        }
+        Err(e) => {
+            log::debug!("Symbolize file {:#?}: {}", &dso_path, e);
+            PerfStackFrame {
+                build_id,
+                ..PerfStackFrame::new(ip, "unknown".into(), dso_path, offset)
+            }
+        }
+    };
+    let root = golang::is_stack_root(&psf.sym);
+    frames.push(psf);
+    root
 }
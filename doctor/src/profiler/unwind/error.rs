@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::profiler::symbolizer::error::SymbolizerError;
+
+#[derive(Error, Debug)]
+pub enum UnwindError {
+    #[error("read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("parse elf {0}: {1}")]
+    Elf(PathBuf, goblin::error::Error),
+    #[error("parse cfi of {0}: {1}")]
+    Cfi(PathBuf, gimli::Error),
+    #[error("unwind tables full, {0} rows needed")]
+    TableFull(usize),
+    #[error("process {0}: {1}")]
+    Process(u32, anyhow::Error),
+    #[error("bpf map: {0}")]
+    Map(#[from] aya::maps::MapError),
+    #[error("python runtime: {0}")]
+    Python(#[from] SymbolizerError),
+    #[error("map {0} not found")]
+    MapNotFound(&'static str),
+    #[error("start unwind loader: {0}")]
+    Thread(std::io::Error),
+    #[error("unwind loader exited")]
+    LoaderGone,
+}
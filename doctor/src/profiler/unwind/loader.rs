@@ -0,0 +1,329 @@
+use std::{
+    collections::{HashMap as StdHashMap, HashSet},
+    path::Path,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use aya::{
+    maps::{Array, HashMap, MapData},
+    Ebpf,
+};
+use doctor_common::{ProcUnwind, UnwindMapping, UnwindRow, MAX_UNWIND_MAPPINGS, UNWIND_ROWS};
+use log::{debug, warn};
+use procfs::process::MMapPath;
+
+use super::{error::UnwindError, table::ElfCfi};
+use crate::profiler::process::ProcessMetadata;
+
+/// (dev, inode) of a mapped ELF.
+type ElfKey = ((i32, i32), u64);
+
+/// Where the rows of an ELF live in `unwind_rows`.
+struct ElfTable {
+    start: u32,
+    len: u32,
+    cfi: ElfCfi,
+}
+
+/// Compiles the unwind tables of the ELFs mapped by profiled processes and
+/// loads them for the eBPF unwinder. An ELF shared by several processes is
+/// loaded once. ELFs are parsed on a thread of their own, off the event
+/// loop, and the maps of known processes are rescanned for the libraries
+/// they load later.
+pub struct DwarfUnwinder {
+    requests: Sender<Request>,
+    prepared: HashSet<u32>,
+}
+
+enum Request {
+    Prepare(u32),
+    Forget(u32),
+    Rescan,
+}
+
+impl DwarfUnwinder {
+    pub fn new(bpf: &mut Ebpf) -> Result<Self, UnwindError> {
+        let loader = TableLoader::new(bpf)?;
+        let (requests, rx) = channel();
+        thread::Builder::new()
+            .name("dwarf-unwind".into())
+            .spawn(move || loader.serve(rx))
+            .map_err(UnwindError::Thread)?;
+        Ok(Self {
+            requests,
+            prepared: HashSet::new(),
+        })
+    }
+
+    /// Loads the tables of every executable mapping of `tgid`, once per
+    /// process. Samples taken before fall back to frame pointers.
+    pub fn prepare(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        if !self.prepared.insert(tgid) {
+            return Ok(());
+        }
+        self.send(Request::Prepare(tgid))
+    }
+
+    /// Loads the tables of the mappings prepared processes made since, like
+    /// dlopen'd libraries, and drops the ones they unmapped.
+    pub fn rescan(&self) -> Result<(), UnwindError> {
+        self.send(Request::Rescan)
+    }
+
+    /// Drops the mappings of an exited process, and the tables no other
+    /// process uses.
+    pub fn forget(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        if !self.prepared.remove(&tgid) {
+            return Ok(());
+        }
+        self.send(Request::Forget(tgid))
+    }
+
+    fn send(&self, request: Request) -> Result<(), UnwindError> {
+        self.requests
+            .send(request)
+            .map_err(|_| UnwindError::LoaderGone)
+    }
+}
+
+/// The unwind maps and what is loaded in them, owned by the loader thread.
+struct TableLoader {
+    rows: Array<MapData, UnwindRow>,
+    procs: HashMap<MapData, u32, ProcUnwind>,
+    free: RowRanges,
+    /// table, None when the ELF has no usable CFI, and the processes mapping
+    /// it
+    tables: StdHashMap<ElfKey, (Option<ElfTable>, HashSet<u32>)>,
+    /// file mappings of each process as (start, end, ELF) when last loaded
+    proc_maps: StdHashMap<u32, Vec<(u64, u64, ElfKey)>>,
+}
+
+impl TableLoader {
+    fn new(bpf: &mut Ebpf) -> Result<Self, UnwindError> {
+        let rows = bpf
+            .take_map("unwind_rows")
+            .ok_or(UnwindError::MapNotFound("unwind_rows"))?;
+        let procs = bpf
+            .take_map("unwind_procs")
+            .ok_or(UnwindError::MapNotFound("unwind_procs"))?;
+        Ok(Self {
+            rows: Array::try_from(rows)?,
+            procs: HashMap::try_from(procs)?,
+            free: RowRanges::new(UNWIND_ROWS),
+            tables: StdHashMap::new(),
+            proc_maps: StdHashMap::new(),
+        })
+    }
+
+    fn serve(mut self, requests: Receiver<Request>) {
+        for request in requests {
+            match request {
+                Request::Prepare(tgid) => {
+                    if let Err(e) = self.prepare(tgid) {
+                        debug!("unwind tables of {}: {}", tgid, e);
+                    }
+                }
+                Request::Forget(tgid) => self.forget(tgid),
+                Request::Rescan => {
+                    let tgids = self.proc_maps.keys().copied().collect::<Vec<_>>();
+                    for tgid in tgids {
+                        if let Err(e) = self.prepare(tgid) {
+                            debug!("unwind tables of {}: {}", tgid, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads the mappings of `tgid`, with the tables of the ELFs not loaded
+    /// yet. Nothing is done when its mappings did not change.
+    fn prepare(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        let proc = ProcessMetadata::new(tgid).map_err(|e| UnwindError::Process(tgid, e))?;
+        let mut maps = proc
+            .maps()
+            .iter()
+            .filter(|m| matches!(m.pathname, MMapPath::Path(_)))
+            .collect::<Vec<_>>();
+        maps.sort_by_key(|m| m.address.0);
+        let mapped = maps
+            .iter()
+            .map(|m| (m.address.0, m.address.1, (m.dev, m.inode)))
+            .collect::<Vec<_>>();
+        if self.proc_maps.get(&tgid) == Some(&mapped) {
+            return Ok(());
+        }
+
+        let mut elfs = HashSet::new();
+        // safe to zero, plain integers
+        let mut unwind: ProcUnwind = unsafe { std::mem::zeroed() };
+        for map in maps {
+            let MMapPath::Path(path) = &map.pathname else {
+                continue;
+            };
+            if unwind.len as usize == MAX_UNWIND_MAPPINGS {
+                warn!("pid {}: more than {} mappings", tgid, MAX_UNWIND_MAPPINGS);
+                break;
+            }
+            let key = (map.dev, map.inode);
+            if !self.tables.contains_key(&key) {
+                let path = proc.rootfs.join(path.strip_prefix("/").unwrap_or(path));
+                let table = match load(&mut self.rows, &mut self.free, &path) {
+                    Ok(table) => table,
+                    Err(e @ UnwindError::TableFull(_)) => {
+                        warn!("no unwind table for {:?}: {}", path, e);
+                        None
+                    }
+                    Err(e) => {
+                        debug!("no unwind table for {:?}: {}", path, e);
+                        None
+                    }
+                };
+                self.tables.insert(key, (table, HashSet::new()));
+            }
+            let Some((table, users)) = self.tables.get_mut(&key) else {
+                continue;
+            };
+            users.insert(tgid);
+            elfs.insert(key);
+            let Some(table) = table else {
+                continue;
+            };
+            let Some(bias) = table.cfi.bias(map) else {
+                continue;
+            };
+            unwind.mappings[unwind.len as usize] = UnwindMapping {
+                start: map.address.0,
+                end: map.address.1,
+                bias,
+                table_start: table.start,
+                table_len: table.len,
+            };
+            unwind.len += 1;
+        }
+
+        debug!("pid {}: {} mappings with unwind tables", tgid, unwind.len);
+        self.procs.insert(tgid, unwind, 0)?;
+        let unmapped = self.proc_maps.insert(tgid, mapped).unwrap_or_default();
+        for (_, _, key) in unmapped {
+            if !elfs.contains(&key) {
+                self.release(tgid, key);
+            }
+        }
+        Ok(())
+    }
+
+    fn forget(&mut self, tgid: u32) {
+        if let Err(e) = self.procs.remove(&tgid) {
+            debug!("unwind mappings of {}: {}", tgid, e);
+        }
+        for (_, _, key) in self.proc_maps.remove(&tgid).unwrap_or_default() {
+            self.release(tgid, key);
+        }
+    }
+
+    /// Drops `tgid` from the users of `key`, and its table once unused.
+    fn release(&mut self, tgid: u32, key: ElfKey) {
+        let Some((table, users)) = self.tables.get_mut(&key) else {
+            return;
+        };
+        users.remove(&tgid);
+        if !users.is_empty() {
+            return;
+        }
+        if let Some(table) = table {
+            self.free.release(table.start, table.len);
+        }
+        self.tables.remove(&key);
+    }
+}
+
+fn load(
+    rows_map: &mut Array<MapData, UnwindRow>,
+    free: &mut RowRanges,
+    path: &Path,
+) -> Result<Option<ElfTable>, UnwindError> {
+    let cfi = ElfCfi::load(path)?;
+    let rows = &cfi.rows;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    let start = u32::try_from(rows.len())
+        .ok()
+        .and_then(|len| free.alloc(len))
+        .ok_or(UnwindError::TableFull(rows.len()))?;
+
+    for (i, row) in rows.iter().enumerate() {
+        if let Err(e) = rows_map.set(start + i as u32, row, 0) {
+            free.release(start, rows.len() as u32);
+            return Err(e.into());
+        }
+    }
+    debug!("{:?}: {} unwind rows", path, rows.len());
+
+    Ok(Some(ElfTable {
+        start,
+        len: rows.len() as u32,
+        cfi,
+    }))
+}
+
+/// Free ranges of `unwind_rows` as (start, len), sorted and not adjacent.
+struct RowRanges(Vec<(u32, u32)>);
+
+impl RowRanges {
+    fn new(len: u32) -> Self {
+        Self(vec![(0, len)])
+    }
+
+    /// Start of `len` free rows, first fit.
+    fn alloc(&mut self, len: u32) -> Option<u32> {
+        let i = self.0.iter().position(|&(_, free)| free >= len)?;
+        let (start, free) = self.0[i];
+        if free == len {
+            self.0.remove(i);
+        } else {
+            self.0[i] = (start + len, free - len);
+        }
+        Some(start)
+    }
+
+    fn release(&mut self, start: u32, len: u32) {
+        let i = self.0.partition_point(|&(free, _)| free < start);
+        self.0.insert(i, (start, len));
+        if let Some(&(next, next_len)) = self.0.get(i + 1) {
+            if start + len == next {
+                self.0[i].1 += next_len;
+                self.0.remove(i + 1);
+            }
+        }
+        if i > 0 && self.0[i - 1].0 + self.0[i - 1].1 == start {
+            self.0[i - 1].1 += self.0[i].1;
+            self.0.remove(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_ranges() {
+        let mut free = RowRanges::new(100);
+        assert_eq!(free.alloc(30), Some(0));
+        assert_eq!(free.alloc(30), Some(30));
+        assert_eq!(free.alloc(30), Some(60));
+        assert_eq!(free.alloc(30), None);
+
+        // released rows are reused, and merge with their free neighbours
+        free.release(0, 30);
+        free.release(60, 30);
+        assert_eq!(free.0, vec![(0, 30), (60, 40)]);
+        assert_eq!(free.alloc(50), None);
+        free.release(30, 30);
+        assert_eq!(free.0, vec![(0, 100)]);
+        assert_eq!(free.alloc(100), Some(0));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod error;
+pub mod loader;
+pub mod python;
+pub mod snapshot;
+pub mod table;
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use aya::{
+    maps::{HashMap, MapData},
+    Ebpf,
+};
+use doctor_common::PyProc;
+use log::debug;
+
+use super::error::UnwindError;
+use crate::profiler::{process::ProcessMetadata, symbolizer::python::PyRuntime};
+
+/// Tells the eBPF program where the interpreter of CPython processes is, so
+/// it can walk the Python frames of their threads. Interpreters are looked
+/// for on a thread of their own, off the event loop.
+pub struct PythonUnwinder {
+    requests: Sender<Request>,
+    prepared: HashSet<u32>,
+}
+
+enum Request {
+    Prepare(u32),
+    Forget(u32),
+}
+
+impl PythonUnwinder {
+    pub fn new(bpf: &mut Ebpf) -> Result<Self, UnwindError> {
+        let procs = bpf
+            .take_map("python_procs")
+            .ok_or(UnwindError::MapNotFound("python_procs"))?;
+        let loader = PythonLoader {
+            procs: HashMap::try_from(procs)?,
+            pythons: HashSet::new(),
+        };
+        let (requests, rx) = channel();
+        thread::Builder::new()
+            .name("python-unwind".into())
+            .spawn(move || loader.serve(rx))
+            .map_err(UnwindError::Thread)?;
+        Ok(Self {
+            requests,
+            prepared: HashSet::new(),
+        })
+    }
+
+    /// Looks for a supported CPython in `tgid`, once per process. Samples
+    /// taken before it is found only have native frames.
+    pub fn prepare(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        if !self.prepared.insert(tgid) {
+            return Ok(());
+        }
+        self.send(Request::Prepare(tgid))
+    }
+
+    /// Drops the interpreter of an exited process, its pid may be reused by
+    /// another one.
+    pub fn forget(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        if !self.prepared.remove(&tgid) {
+            return Ok(());
+        }
+        self.send(Request::Forget(tgid))
+    }
+
+    fn send(&self, request: Request) -> Result<(), UnwindError> {
+        self.requests
+            .send(request)
+            .map_err(|_| UnwindError::LoaderGone)
+    }
+}
+
+/// `python_procs` and the processes in it, owned by the loader thread.
+struct PythonLoader {
+    procs: HashMap<MapData, u32, PyProc>,
+    pythons: HashSet<u32>,
+}
+
+impl PythonLoader {
+    fn serve(mut self, requests: Receiver<Request>) {
+        for request in requests {
+            match request {
+                Request::Prepare(tgid) => {
+                    if let Err(e) = self.prepare(tgid) {
+                        debug!("python interpreter of {}: {}", tgid, e);
+                    }
+                }
+                Request::Forget(tgid) => {
+                    if let Err(e) = self.forget(tgid) {
+                        debug!("python interpreter of {}: {}", tgid, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn prepare(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        let proc = ProcessMetadata::new(tgid).map_err(|e| UnwindError::Process(tgid, e))?;
+        let Some(runtime) = PyRuntime::find(&proc)? else {
+            return Ok(());
+        };
+        debug!(
+            "pid {}: python 3.{}, _PyRuntime at {:#x}",
+            tgid, runtime.version.minor, runtime.addr
+        );
+        let (pidns_dev, pidns_ino) = pid_namespace(tgid)?;
+        let proc = PyProc {
+            runtime: runtime.addr,
+            offsets: runtime.version.offsets,
+            pidns_dev,
+            pidns_ino,
+        };
+        self.procs.insert(tgid, proc, 0)?;
+        self.pythons.insert(tgid);
+        Ok(())
+    }
+
+    fn forget(&mut self, tgid: u32) -> Result<(), UnwindError> {
+        if self.pythons.remove(&tgid) {
+            self.procs.remove(&tgid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Device and inode of the pid namespace of `tgid`, (0, 0) when it is ours.
+fn pid_namespace(tgid: u32) -> Result<(u64, u64), UnwindError> {
+    let ns = |path: String| {
+        std::fs::metadata(&path)
+            .map(|m| (m.dev(), m.ino()))
+            .map_err(|e| UnwindError::Io(path.into(), e))
+    };
+    let theirs = ns(format!("/proc/{}/ns/pid", tgid))?;
+    match ns("/proc/self/ns/pid".into())? {
+        ours if ours == theirs => Ok((0, 0)),
+        _ => Ok(theirs),
+    }
+}
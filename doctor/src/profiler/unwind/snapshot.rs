@@ -0,0 +1,197 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use doctor_common::{CFA_PLT, CFA_RBP, CFA_RSP, RBP_SAVED};
+use log::debug;
+use procfs::process::{MMapPath, MemoryMap};
+
+use super::table::ElfCfi;
+use crate::profiler::process::ProcessMetadata;
+
+const MAX_FRAMES: usize = 128;
+
+/// (dev, inode) -> CFI, None when the ELF has none
+type ElfCache = HashMap<((i32, i32), u64), Option<Arc<ElfCfi>>>;
+
+/// User registers and the top of the user stack, copied by the eBPF program.
+pub struct UserSnapshot {
+    pub ip: u64,
+    pub sp: u64,
+    pub bp: u64,
+    pub stack: Vec<u8>,
+}
+
+impl UserSnapshot {
+    /// Reads a word of the copied stack, None past the copy.
+    fn read(&self, addr: u64) -> Option<u64> {
+        let offset = usize::try_from(addr.checked_sub(self.sp)?).ok()?;
+        let bytes = self.stack.get(offset..offset.checked_add(8)?)?;
+        Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+    }
+}
+
+/// Unwinds stack snapshots against the CFI of the mapped ELFs, the way
+/// `perf record --call-graph dwarf` does.
+#[derive(Default)]
+pub struct SnapshotUnwinder {
+    procs: HashMap<u32, ProcessMetadata>,
+    elfs: ElfCache,
+}
+
+impl SnapshotUnwinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return addresses of the snapshot, leaf first, and whether the unwind
+    /// stopped because it reached the end of the copied stack.
+    pub fn unwind(&mut self, tgid: u32, snapshot: &UserSnapshot) -> (Vec<u64>, bool) {
+        let mut ips = Vec::new();
+        let (mut ip, mut sp, mut bp) = (snapshot.ip, snapshot.sp, snapshot.bp);
+        for i in 0..MAX_FRAMES {
+            ips.push(ip);
+            let Some((cfi, bias)) = self.cfi(tgid, ip, i == 0) else {
+                break;
+            };
+            // return addresses point after the call, look up the call itself
+            let pc = ip.wrapping_sub(bias).wrapping_sub((i > 0) as u64);
+            let Some(row) = cfi.find(pc) else {
+                break;
+            };
+            let cfa = match row.cfa_rule {
+                CFA_RSP => sp.wrapping_add(row.cfa_offset as i64 as u64),
+                CFA_RBP => bp.wrapping_add(row.cfa_offset as i64 as u64),
+                CFA_PLT => sp + if ip & 15 >= 11 { 16 } else { 8 },
+                _ => break,
+            };
+            if row.rbp_rule == RBP_SAVED {
+                match snapshot.read(cfa.wrapping_add(row.rbp_offset as i64 as u64)) {
+                    Some(saved) => bp = saved,
+                    None => return (ips, true),
+                }
+            }
+            ip = match snapshot.read(cfa.wrapping_sub(8)) {
+                Some(ip) => ip,
+                None => return (ips, true),
+            };
+            sp = cfa;
+            if ip == 0 {
+                break;
+            }
+        }
+        (ips, false)
+    }
+
+    /// Drops the maps of an exited process, and the CFI of the ELFs no other
+    /// process maps.
+    pub fn forget(&mut self, tgid: u32) {
+        if self.procs.remove(&tgid).is_none() {
+            return;
+        }
+        let mapped = self
+            .procs
+            .values()
+            .flat_map(|proc| proc.maps())
+            .map(|map| (map.dev, map.inode))
+            .collect::<HashSet<_>>();
+        self.elfs.retain(|key, _| mapped.contains(key));
+    }
+
+    /// CFI and load bias of the ELF mapped at `ip`. The maps of the process
+    /// are read again when the leaf is outside of them, after a dlopen or an
+    /// exec.
+    fn cfi(&mut self, tgid: u32, ip: u64, leaf: bool) -> Option<(Arc<ElfCfi>, u64)> {
+        let found = self
+            .procs
+            .get(&tgid)
+            .is_some_and(|proc| proc.find_mapper(ip).is_ok());
+        if !found && (leaf || !self.procs.contains_key(&tgid)) {
+            match ProcessMetadata::new(tgid) {
+                Ok(proc) => {
+                    self.procs.insert(tgid, proc);
+                }
+                Err(e) => {
+                    debug!("maps of {}: {}", tgid, e);
+                    return None;
+                }
+            }
+        }
+        let proc = self.procs.get(&tgid)?;
+        let map = proc.find_mapper(ip).ok()?;
+        let cfi = elf_cfi(&mut self.elfs, proc, map)?;
+        let bias = cfi.bias(map)?;
+        Some((cfi, bias))
+    }
+}
+
+fn elf_cfi(elfs: &mut ElfCache, proc: &ProcessMetadata, map: &MemoryMap) -> Option<Arc<ElfCfi>> {
+    elfs.entry((map.dev, map.inode))
+        .or_insert_with(|| {
+            let MMapPath::Path(path) = &map.pathname else {
+                return None;
+            };
+            let path = proc.rootfs.join(path.strip_prefix("/").unwrap_or(path));
+            match ElfCfi::load(&path) {
+                Ok(cfi) => Some(Arc::new(cfi)),
+                Err(e) => {
+                    debug!("no unwind table for {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .clone()
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::arch::asm;
+
+    use super::*;
+
+    /// Snapshot of the calling thread, the copy stops at the end of its stack.
+    #[inline(never)]
+    fn capture(size: u64) -> UserSnapshot {
+        let (ip, sp, bp): (u64, u64, u64);
+        unsafe {
+            asm!(
+                "lea {ip}, [rip]",
+                "mov {sp}, rsp",
+                "mov {bp}, rbp",
+                ip = out(reg) ip,
+                sp = out(reg) sp,
+                bp = out(reg) bp,
+            );
+        }
+        let maps = procfs::process::Process::myself().unwrap().maps().unwrap();
+        let end = maps
+            .iter()
+            .find(|m| m.address.0 <= sp && sp < m.address.1)
+            .unwrap()
+            .address
+            .1;
+        let len = size.min(end - sp) as usize;
+        let stack = unsafe { std::slice::from_raw_parts(sp as *const u8, len) }.to_vec();
+        UserSnapshot { ip, sp, bp, stack }
+    }
+
+    #[test]
+    fn test_unwind_own_stack() {
+        let pid = std::process::id();
+        let mut unwinder = SnapshotUnwinder::new();
+
+        let (ips, truncated) = unwinder.unwind(pid, &capture(64 * 1024));
+        // capture, the test, and the test harness below
+        assert!(ips.len() > 3, "{:x?}", ips);
+        assert!(!truncated);
+
+        let (short, truncated) = unwinder.unwind(pid, &capture(64));
+        assert!(truncated);
+        assert!(short.len() < ips.len());
+
+        unwinder.forget(pid);
+        assert!(unwinder.procs.is_empty());
+        assert!(unwinder.elfs.is_empty());
+    }
+}
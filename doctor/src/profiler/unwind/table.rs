@@ -0,0 +1,233 @@
+use std::{fs::File, path::Path};
+
+use doctor_common::{UnwindRow, CFA_END, CFA_PLT, CFA_RBP, CFA_RSP, RBP_SAME, RBP_SAVED};
+use gimli::{
+    BaseAddresses, CfaRule, CieOrFde, DebugFrame, EhFrame, EndianSlice, LittleEndian, RegisterRule,
+    UnwindContext, UnwindSection, X86_64,
+};
+use goblin::elf::{
+    program_header::{PF_X, PT_LOAD},
+    section_header::{SHF_COMPRESSED, SHT_NOBITS},
+    Elf,
+};
+use memmap2::MmapOptions;
+use procfs::process::MemoryMap;
+
+use super::error::UnwindError;
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+const PAGE_MASK: u64 = 0xfff;
+
+// CFA expression of the lazy binding PLT stubs emitted by binutils:
+// rsp + 8 + ((rip & 15) >= 11) << 3
+const PLT_EXPR_PREFIX: [u8; 6] = [0x77, 0x08, 0x80, 0x00, 0x3f, 0x1a];
+
+/// Unwind rows of an ELF, with its executable PT_LOAD segments as
+/// (p_offset, p_vaddr, p_filesz) to map file offsets back to virtual
+/// addresses.
+pub struct ElfCfi {
+    pub rows: Vec<UnwindRow>,
+    loads: Vec<(u64, u64, u64)>,
+}
+
+impl ElfCfi {
+    pub fn load(path: &Path) -> Result<Self, UnwindError> {
+        let file = File::open(path).map_err(|e| UnwindError::Io(path.to_path_buf(), e))?;
+        let data = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| UnwindError::Io(path.to_path_buf(), e))?;
+        let elf = Elf::parse(&data).map_err(|e| UnwindError::Elf(path.to_path_buf(), e))?;
+        let rows = compile(&elf, &data).map_err(|e| UnwindError::Cfi(path.to_path_buf(), e))?;
+        let loads = elf
+            .program_headers
+            .iter()
+            .filter(|h| h.p_type == PT_LOAD && h.p_flags & PF_X != 0)
+            .map(|h| (h.p_offset, h.p_vaddr, h.p_filesz))
+            .collect();
+        Ok(Self { rows, loads })
+    }
+
+    /// Runtime address minus ELF virtual address in `map`.
+    pub fn bias(&self, map: &MemoryMap) -> Option<u64> {
+        // mappings start at the page holding the segment start
+        let (p_offset, p_vaddr, _) = self
+            .loads
+            .iter()
+            .find(|(offset, _, size)| (offset & !PAGE_MASK..offset + size).contains(&map.offset))?;
+        Some(
+            map.address
+                .0
+                .wrapping_sub(map.offset)
+                .wrapping_add(*p_offset)
+                .wrapping_sub(*p_vaddr),
+        )
+    }
+
+    /// Row covering the ELF virtual address `pc`.
+    pub fn find(&self, pc: u64) -> Option<&UnwindRow> {
+        let i = self.rows.partition_point(|r| r.pc <= pc);
+        self.rows.get(i.checked_sub(1)?)
+    }
+}
+
+/// Compiles the `.eh_frame` and `.debug_frame` CFI of an x86_64 ELF into
+/// rows sorted by pc, the format the eBPF unwinder searches. `.debug_frame`
+/// only fills in functions `.eh_frame` does not cover.
+pub fn compile(elf: &Elf, data: &[u8]) -> Result<Vec<UnwindRow>, gimli::Error> {
+    let mut bases = BaseAddresses::default();
+    if let Some((addr, _)) = section(elf, data, ".text") {
+        bases = bases.set_text(addr);
+    }
+    if let Some((addr, _)) = section(elf, data, ".got") {
+        bases = bases.set_got(addr);
+    }
+
+    let mut rows = Vec::new();
+    let mut covered = Vec::new();
+    if let Some((addr, bytes)) = section(elf, data, ".eh_frame") {
+        let eh_frame = EhFrame::new(bytes, LittleEndian);
+        let bases = bases.clone().set_eh_frame(addr);
+        collect(&eh_frame, &bases, &[], &mut covered, &mut rows)?;
+    }
+    if let Some((_, bytes)) = section(elf, data, ".debug_frame") {
+        let mut debug_frame = DebugFrame::new(bytes, LittleEndian);
+        debug_frame.set_address_size(8);
+        covered.sort_unstable();
+        let eh_covered = covered.clone();
+        collect(&debug_frame, &bases, &eh_covered, &mut covered, &mut rows)?;
+    }
+
+    // an FDE starting where the previous one ends replaces its end marker
+    rows.sort_by_key(|r| (r.pc, r.cfa_rule != CFA_END));
+    rows.dedup_by(|next, prev| {
+        if next.pc == prev.pc {
+            *prev = *next;
+            true
+        } else {
+            false
+        }
+    });
+    // consecutive rows with the same rules are one row
+    rows.dedup_by(|next, prev| {
+        UnwindRow {
+            pc: prev.pc,
+            ..*next
+        } == *prev
+    });
+    Ok(rows)
+}
+
+fn section<'a>(elf: &Elf, data: &'a [u8], name: &str) -> Option<(u64, &'a [u8])> {
+    let header = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(name))?;
+    // compressed debug sections are not supported
+    if header.sh_type == SHT_NOBITS || header.sh_flags & SHF_COMPRESSED as u64 != 0 {
+        return None;
+    }
+    let start = header.sh_offset as usize;
+    let bytes = data.get(start..start.checked_add(header.sh_size as usize)?)?;
+    Some((header.sh_addr, bytes))
+}
+
+/// Rows of every FDE of `section` that does not overlap the sorted `skip`
+/// ranges, the ranges of the FDEs taken are added to `covered`.
+fn collect<'a, S: UnwindSection<Slice<'a>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    skip: &[(u64, u64)],
+    covered: &mut Vec<(u64, u64)>,
+    rows: &mut Vec<UnwindRow>,
+) -> Result<(), gimli::Error> {
+    let mut ctx = UnwindContext::new();
+    let mut entries = section.entries(bases);
+    while let Some(entry) = entries.next()? {
+        let fde = match entry {
+            CieOrFde::Cie(_) => continue,
+            CieOrFde::Fde(partial) => {
+                match partial.parse(|section, bases, offset| section.cie_from_offset(bases, offset))
+                {
+                    Ok(fde) => fde,
+                    // a broken FDE only loses its own function
+                    Err(_) => continue,
+                }
+            }
+        };
+        let range = (fde.initial_address(), fde.end_address());
+        if fde.len() == 0 || overlaps(skip, range) {
+            continue;
+        }
+        covered.push(range);
+
+        let mut table = fde.rows(section, bases, &mut ctx)?;
+        while let Some(row) = table.next_row()? {
+            let mut unwind_row = UnwindRow {
+                pc: row.start_address(),
+                ..Default::default()
+            };
+            // the outermost frame has no return address
+            if row.register(X86_64::RA) != RegisterRule::Undefined {
+                match row.cfa() {
+                    CfaRule::RegisterAndOffset { register, offset } => {
+                        if let Ok(offset) = i32::try_from(*offset) {
+                            unwind_row.cfa_offset = offset;
+                            unwind_row.cfa_rule = match *register {
+                                X86_64::RSP => CFA_RSP,
+                                X86_64::RBP => CFA_RBP,
+                                _ => CFA_END,
+                            };
+                        }
+                    }
+                    CfaRule::Expression(expr) => {
+                        let expr = expr.get(section)?;
+                        if expr.0.slice().starts_with(&PLT_EXPR_PREFIX) {
+                            unwind_row.cfa_rule = CFA_PLT;
+                        }
+                    }
+                }
+            }
+            if let RegisterRule::Offset(offset) = row.register(X86_64::RBP) {
+                if let Ok(offset) = i32::try_from(offset) {
+                    unwind_row.rbp_rule = RBP_SAVED;
+                    unwind_row.rbp_offset = offset;
+                }
+            } else {
+                unwind_row.rbp_rule = RBP_SAME;
+            }
+            rows.push(unwind_row);
+        }
+        rows.push(UnwindRow {
+            pc: range.1,
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+fn overlaps(sorted: &[(u64, u64)], (start, end): (u64, u64)) -> bool {
+    let i = sorted.partition_point(|r| r.1 <= start);
+    sorted.get(i).is_some_and(|r| r.0 < end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_own_binary() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        let rows = compile(&elf, &data).unwrap();
+
+        assert!(rows.len() > 100);
+        assert!(rows.windows(2).all(|w| w[0].pc < w[1].pc));
+        // every function starts with the return address on top of the stack
+        let entry = rows
+            .iter()
+            .find(|r| r.cfa_rule == CFA_RSP && r.cfa_offset == 8)
+            .unwrap();
+        assert_eq!(entry.rbp_rule, RBP_SAME);
+        assert!(rows.iter().any(|r| r.rbp_rule == RBP_SAVED));
+    }
+}